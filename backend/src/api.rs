@@ -1,19 +1,83 @@
-use actix_web::web::{Data, Json};
-use common::data::Reply;
+use std::{future::Future, ops::Deref, pin::Pin};
+
+use actix_web::{web::{self, Data, Json}, FromRequest, HttpRequest, dev::Payload, error};
+use common::data::{Reply, AccessType};
 use rusqlite::Connection;
 use uuid::Uuid;
 
-use crate::database::{self, AuthHandle};
+use crate::database::{self, AuthHandle, DbPool, SqliteManager};
 
 pub mod task;
 pub mod user;
 pub mod repo;
 pub mod transfer;
+pub mod admin;
+
+// A connection from the shared pool
+// Handlers release it before waiting on a repo, so waiting requests do not use up the pool
+pub struct Db(r2d2::PooledConnection<SqliteManager>, Data<DbPool>);
+
+impl Db {
+    // The pool blocks until a connection is free, so this waits on the blocking threads instead of the worker
+    pub async fn connect(pool: Data<DbPool>) -> Result<Db, actix_web::Error> {
+        let waiting = pool.clone();
+        let conn = web::block(move || waiting.get()).await?.map_err(|e| error::ErrorServiceUnavailable(e))?;
+        Ok(Db(conn, pool))
+    }
+
+    // Runs slow work on the connection, like hashing passwords, on the blocking threads so the worker stays free
+    pub async fn block<F, R>(self, f: F) -> Result<(Db, R), actix_web::Error>
+    where F: FnOnce(&Connection) -> R + Send + 'static, R: Send + 'static {
+        let Db(conn, pool) = self;
+        let (conn, res) = web::block(move || {
+            let res = f(&conn);
+            (conn, res)
+        }).await?;
+        Ok((Db(conn, pool), res))
+    }
+
+    // Returns the connection to the pool, connect gets a new one if the handler needs the DB again
+    pub fn release(self) -> Data<DbPool> {
+        self.1
+    }
+}
+
+impl Deref for Db {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        &self.0
+    }
+}
+
+impl FromRequest for Db {
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Db, actix_web::Error>>>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let pool = req.app_data::<Data<DbPool>>().cloned();
+
+        Box::pin(async move {
+            if let Some(pool) = pool {
+                Db::connect(pool).await
+            } else {
+                Err(error::ErrorInternalServerError("Database pool is missing"))
+            }
+        })
+    }
+}
 
-pub fn handle_auth_request<T>(data: &Data<Connection>, token: Option<Uuid>) -> Result<AuthHandle, Json<Reply<T>>> {
+// Headers set by proxies can be forged by anyone, so only the address of the connection counts
+pub fn get_client_address(req: &HttpRequest) -> String {
+    req.peer_addr().map(|addr| addr.ip().to_string()).unwrap_or_default()
+}
+
+pub fn handle_auth_request<T>(data: &Connection, token: Option<Uuid>) -> Result<AuthHandle, Json<Reply<T>>> {
     if let Some(token) = token {
         if let Some(res) = database::get_auth_handle_from_token(data, token) {
             return Ok(res);
+        } else if let Some(res) = database::get_auth_handle_from_api_key(data, token) {
+            return Ok(res);
         } else {
             return Err(Json(Reply::AuthFailed));
         }
@@ -24,4 +88,39 @@ pub fn handle_auth_request<T>(data: &Data<Connection>, token: Option<Uuid>) -> R
 
 
     //Err(Json(Reply::Failed))
+}
+
+// API keys are only meant for syncing, changing the account needs a log in
+pub fn handle_login_only<T>(handle: &AuthHandle) -> Result<(), Json<Reply<T>>> {
+    if handle.api_key.is_some() {
+        return Err(Json(Reply::Denied { token: None }));
+    }
+
+    Ok(())
+}
+
+// Checks if the user of the handle has a permission on the repo that passes the check, admins always pass
+pub fn handle_repo_access<T>(data: &Connection, handle: &AuthHandle, repo_name: &String, check: fn(&AccessType) -> bool) -> Result<(), Json<Reply<T>>> {
+    let res = database::get_handle_repo_permission(data, handle, repo_name.clone());
+    if handle.admin {
+        // Admins still need the repo to exist
+        if let Some(_) = database::get_repo(data, repo_name.clone()) {
+            return Ok(());
+        } else {
+            return Err(Json(Reply::NotFound { token: handle.token.clone() }));
+        }
+    } else if let Some(acc) = res {
+        if check(&acc) {
+            return Ok(());
+        } else {
+            return Err(Json(Reply::Denied { token: handle.token.clone() }));
+        }
+    } else {
+        // Check if exists to send correct responds
+        if let Some(_) = database::get_repo(data, repo_name.clone()) {
+            return Err(Json(Reply::Denied { token: handle.token.clone() }));
+        } else {
+            return Err(Json(Reply::NotFound { token: handle.token.clone() }));
+        }
+    }
 }
\ No newline at end of file
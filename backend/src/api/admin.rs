@@ -0,0 +1,261 @@
+use std::path::PathBuf;
+
+use actix_web::{web::{Data, Json}, get};
+use actix_web_lab::__reexports::tokio::sync::RwLock;
+use common::data::{Reply, RequestGarbageCollection, GarbageReport, RequestRepository, VerifyReport, RequestQuota, RequestRetention, RetentionReport, RequestLockout, LoginLockout, ServerSettings, RequestServerSettings};
+
+use crate::{database, api::{Db, handle_auth_request}, file_processing::{self, RepoController}};
+
+#[get("/admin/repo/gc")]
+pub async fn collect_garbage(controller: Data<RwLock<RepoController>>, data: Db, request: Json<RequestGarbageCollection>) -> Json<Reply<GarbageReport>> {
+    let res = handle_auth_request(&data, request.token);
+    if let Ok(handle) = res {
+        if !handle.admin {
+            return Json(Reply::Denied { token: handle.token });
+        }
+
+        let repo = controller.read().await.get_repo(&request.repo_name);
+        if let Some(repo) = repo {
+            drop(data);
+            let mut repo = repo.lock().await;
+
+            let dry_run = request.dry_run.unwrap_or(true);
+            return Json(Reply::Ok { value: repo.collect_garbage(file_processing::get_unix_time(), dry_run), token: handle.token });
+        } else {
+            return Json(Reply::NotFound { token: handle.token });
+        }
+    } else if let Err(e) = res {
+        return e;
+    }
+
+    Json(Reply::Failed)
+}
+
+// Runs the retention policy of the repo right away, instead of waiting for the schedule
+#[get("/admin/repo/retention")]
+pub async fn apply_retention(controller: Data<RwLock<RepoController>>, data: Db, request: Json<RequestRetention>) -> Json<Reply<RetentionReport>> {
+    let res = handle_auth_request(&data, request.token);
+    if let Ok(handle) = res {
+        if !handle.admin {
+            return Json(Reply::Denied { token: handle.token });
+        }
+
+        let repo = controller.read().await.get_repo(&request.repo_name);
+        let repo = if let Some(repo) = repo {
+            repo
+        } else {
+            return Json(Reply::NotFound { token: handle.token });
+        };
+
+        drop(data);
+        let dry_run = request.dry_run.unwrap_or(true);
+        let res = repo.lock().await.apply_retention(file_processing::get_unix_time(), dry_run);
+        if let Some(report) = res {
+            return Json(Reply::Ok { value: report, token: handle.token });
+        } else {
+            return Json(Reply::NoPolicy { token: handle.token });
+        }
+    } else if let Err(e) = res {
+        return e;
+    }
+
+    Json(Reply::Failed)
+}
+
+#[get("/admin/repo/verify")]
+pub async fn verify_repo(controller: Data<RwLock<RepoController>>, data: Db, request: Json<RequestRepository>) -> Json<Reply<VerifyReport>> {
+    let res = handle_auth_request(&data, request.token);
+    if let Ok(handle) = res {
+        if !handle.admin {
+            return Json(Reply::Denied { token: handle.token });
+        }
+
+        if let Some(repo_name) = &request.repo_name {
+            if let None = controller.read().await.get_repo(repo_name) {
+                return Json(Reply::NotFound { token: handle.token });
+            }
+
+            drop(data);
+            if let Some(report) = file_processing::verify_repo(&controller, repo_name).await {
+                return Json(Reply::Ok { value: report, token: handle.token });
+            } else {
+                // The HEADER could not be read
+                return Json(Reply::Error { token: handle.token });
+            }
+        } else {
+            return Json(Reply::MissingParameter { token: handle.token });
+        }
+    } else if let Err(e) = res {
+        return e;
+    }
+
+    Json(Reply::Failed)
+}
+
+// Sets the quota of either a repo or a user, a quota of None removes it
+#[get("/admin/quota/set")]
+pub async fn set_quota(data: Db, request: Json<RequestQuota>) -> Json<Reply<()>> {
+    let res = handle_auth_request(&data, request.token);
+    if let Ok(handle) = res {
+        if !handle.admin {
+            return Json(Reply::Denied { token: handle.token });
+        }
+
+        let res = if let (Some(repo_name), None) = (&request.repo_name, request.user_id) {
+            if database::get_repo(&data, repo_name.clone()).is_none() {
+                return Json(Reply::NotFound { token: handle.token });
+            }
+
+            database::set_repo_quota(&data, repo_name, request.quota)
+        } else if let (None, Some(user_id)) = (&request.repo_name, request.user_id) {
+            if database::get_user(&data, user_id).is_none() {
+                return Json(Reply::NotFound { token: handle.token });
+            }
+
+            database::set_user_quota(&data, user_id, request.quota)
+        } else {
+            // Exactly one of them has to be set
+            return Json(Reply::MissingParameter { token: handle.token });
+        };
+
+        if res {
+            return Json(Reply::Ok { value: (), token: handle.token });
+        } else {
+            return Json(Reply::Error { token: handle.token });
+        }
+    } else if let Err(e) = res {
+        return e;
+    }
+
+    Json(Reply::Failed)
+}
+
+// Everyone who failed to log in recently, locked or not
+#[get("/admin/lockout/list")]
+pub async fn list_lockouts(data: Db, request: Json<RequestLockout>) -> Json<Reply<Vec<LoginLockout>>> {
+    let res = handle_auth_request(&data, request.token);
+    if let Ok(handle) = res {
+        if !handle.admin {
+            return Json(Reply::Denied { token: handle.token });
+        }
+
+        return Json(Reply::Ok { value: database::get_login_lockouts(&data), token: handle.token });
+    } else if let Err(e) = res {
+        return e;
+    }
+
+    Json(Reply::Failed)
+}
+
+// Clears the failures of one user name or address, or of everyone if neither source nor subject are set
+#[get("/admin/lockout/clear")]
+pub async fn clear_lockouts(data: Db, request: Json<RequestLockout>) -> Json<Reply<()>> {
+    let res = handle_auth_request(&data, request.token);
+    if let Ok(handle) = res {
+        if !handle.admin {
+            return Json(Reply::Denied { token: handle.token });
+        }
+
+        let res = if let (Some(source), Some(subject)) = (&request.source, &request.subject) {
+            database::clear_login_failures(&data, source, subject)
+        } else if let (None, None) = (&request.source, &request.subject) {
+            database::clear_all_login_failures(&data)
+        } else {
+            return Json(Reply::MissingParameter { token: handle.token });
+        };
+
+        if res {
+            return Json(Reply::Ok { value: (), token: handle.token });
+        } else {
+            return Json(Reply::Error { token: handle.token });
+        }
+    } else if let Err(e) = res {
+        return e;
+    }
+
+    Json(Reply::Failed)
+}
+
+fn get_settings(data: &Db) -> Option<ServerSettings> {
+    let (expire_time, replacement_time) = database::get_token_times(data)?;
+    Some(ServerSettings {
+        expire_time,
+        replacement_time,
+        temp_folder: file_processing::get_temp_folder_root(data)?,
+        retention_interval: file_processing::get_retention_interval(data)
+    })
+}
+
+#[get("/admin/settings")]
+pub async fn get_server_settings(data: Db, request: Json<RequestServerSettings>) -> Json<Reply<ServerSettings>> {
+    let res = handle_auth_request(&data, request.token);
+    if let Ok(handle) = res {
+        if !handle.admin {
+            return Json(Reply::Denied { token: handle.token });
+        }
+
+        if let Some(settings) = get_settings(&data) {
+            return Json(Reply::Ok { value: settings, token: handle.token });
+        } else {
+            return Json(Reply::Error { token: handle.token });
+        }
+    } else if let Err(e) = res {
+        return e;
+    }
+
+    Json(Reply::Failed)
+}
+
+// Only overwrites what was passed in, everything is read again on use, so no restart is needed
+#[get("/admin/settings/set")]
+pub async fn set_server_settings(controller: Data<RwLock<RepoController>>, data: Db, request: Json<RequestServerSettings>) -> Json<Reply<ServerSettings>> {
+    let res = handle_auth_request(&data, request.token);
+    if let Ok(handle) = res {
+        if !handle.admin {
+            return Json(Reply::Denied { token: handle.token });
+        }
+
+        let current = if let Some(settings) = get_settings(&data) {
+            settings
+        } else {
+            return Json(Reply::Error { token: handle.token });
+        };
+
+        // Validating everything first, so an invalid value does not leave half the settings changed
+        let expire_time = request.expire_time.unwrap_or(current.expire_time);
+        let replacement_time = request.replacement_time.unwrap_or(current.replacement_time);
+        if !database::check_token_times(expire_time, replacement_time) || request.retention_interval == Some(0) {
+            return Json(Reply::Error { token: handle.token });
+        }
+
+        if let Some(temp_folder) = &request.temp_folder {
+            if temp_folder != &current.temp_folder && !file_processing::set_temp_folder_root(&data, temp_folder.clone()) {
+                // Either a file, not creatable, or temp folders are in use
+                return Json(Reply::Conflict { token: handle.token });
+            }
+        }
+
+        let res = database::set_token_times(&data, expire_time, replacement_time) &&
+            request.retention_interval.map(|i| file_processing::set_retention_interval(&data, i)).unwrap_or(true);
+        let settings = get_settings(&data);
+        drop(data);
+
+        // Repos build large files in there too
+        if let Some(temp_folder) = &request.temp_folder {
+            let repos = controller.write().await.set_temp_folder(PathBuf::from(temp_folder));
+            for (_, repo) in repos {
+                repo.lock().await.set_temp_folder(PathBuf::from(temp_folder));
+            }
+        }
+
+        if let (true, Some(settings)) = (res, settings) {
+            return Json(Reply::Ok { value: settings, token: handle.token });
+        } else {
+            return Json(Reply::Error { token: handle.token });
+        }
+    } else if let Err(e) = res {
+        return e;
+    }
+
+    Json(Reply::Failed)
+}
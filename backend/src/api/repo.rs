@@ -1,35 +1,64 @@
+use std::collections::HashMap;
+
 use actix_web::{web::{Data, Json}, get};
 use actix_web_lab::__reexports::tokio::sync::RwLock;
-use common::{data::{Reply, RequestRepository, Repository, AccessType, RepositoryAccess, Branch, CreateCommit}, U232, LargeU};
-use rusqlite::Connection;
+use common::{data::{Reply, RequestRepository, Repository, AccessType, RepositoryAccess, Branch, CreateCommit, RequestCommit, Folder, RequestBranch, RequestCommitLog, CommitLog, CommitEntry, TreeEntry, RequestDiff, CommitDiff, RepositorySettings, RequestRepositorySettings, CommitResult, Compression, RetentionPolicy}, U232, LargeU};
+
+use crate::{database, api::{Db, handle_auth_request, handle_repo_access, handle_login_only}, file_processing::{RepoController, self, repository_file::CommitInfo, storage::{StorageRepo, BranchUpdate, SnapshotPolicy, CommitError}}};
+
+const DEFAULT_LOG_LIMIT:usize = 50;
+const MAX_LOG_LIMIT:usize = 500;
+const MAX_LOG_DEPTH:usize = 10000;
+
+// Returns the commit requested, either directly through the commit id, or the last commit on the branch
+fn resolve_commit(repo: &mut StorageRepo, commit: Option<U232>, branch_name: &Option<String>) -> Option<U232> {
+    if let Some(commit) = commit {
+        if commit == U232::new() || repo.get_commit(commit).is_err() {
+            return None;
+        }
+
+        return Some(commit);
+    } else if let Some(branch_name) = branch_name {
+        repo.update_header_and_branches();
+        if let Some(branch) = repo.get_branch(branch_name.clone()) {
+            let commit = branch.get_previous_commit();
+            if commit != U232::new() {
+                return Some(commit);
+            }
+        }
+    }
 
-use crate::{database, api::handle_auth_request, file_processing::{RepoController, self, repository_file::CommitInfo}};
+    None
+}
 
 #[get("/repo/info")]
-pub async fn get_repo(data: Data<Connection>, request: Json<RequestRepository>) -> Json<Reply<Repository>> {
+pub async fn get_repo(controller: Data<RwLock<RepoController>>, data: Db, request: Json<RequestRepository>) -> Json<Reply<Repository>> {
     let res = handle_auth_request(&data, request.token);
     if let Ok(handle) = res {
         if let Some(name) = &request.repo_name {
             // Getting the repo
             let res = database::get_repo(&data, name.clone());
             if let Some(mut rep) = res {
+                rep.quota = database::get_repo_quota(&data, &rep.repo_name);
                 
                 // Checking and setting the availability
-                let res = database::get_user_repo_permission(&data, handle.user_id, rep.repo_name.clone());
+                let res = database::get_handle_repo_permission(&data, &handle, rep.repo_name.clone());
                 if handle.admin {
                     rep.permission = Some(AccessType::All);
-                    return Json(Reply::Ok { value: rep, token: handle.token });
                 } else if let Some(perm) = res {
                     if perm.is_read_allowed() {
                         rep.permission = Some(perm);
-
-                        return Json(Reply::Ok { value: rep, token: handle.token });
                     } else {
                         return Json(Reply::Denied { token: handle.token });
                     }
                 } else {
                     return Json(Reply::Denied { token: handle.token });
                 }
+
+                // The size needs the repo, which might be busy
+                drop(data);
+                rep.size = file_processing::get_repo_size(&controller, &rep.repo_name).await;
+                return Json(Reply::Ok { value: rep, token: handle.token });
             } else {
                 return Json(Reply::NotFound { token: handle.token });
             }
@@ -44,15 +73,23 @@ pub async fn get_repo(data: Data<Connection>, request: Json<RequestRepository>)
 }
 
 #[get("/repo/list")]
-pub async fn list_repo(data: Data<Connection>, request: Json<RequestRepository>) -> Json<Reply<Vec<Repository>>> {
+pub async fn list_repo(data: Db, request: Json<RequestRepository>) -> Json<Reply<Vec<Repository>>> {
     let res = handle_auth_request(&data, request.token);
     if let Ok(handle) = res {
-        let data = if handle.admin {
+        let mut data = if handle.admin {
             database::list_repos(&data, None)
         } else {
             database::list_repos(&data, Some(handle.user_id))
         };
 
+        // Only what the api key is allowed to see
+        if let Some(scope) = &handle.api_key {
+            data.retain(|rep| scope.allows_repo(&rep.repo_name));
+            for rep in data.iter_mut() {
+                rep.permission = rep.permission.as_ref().map(|perm| scope.limit(&rep.repo_name, perm.clone()));
+            }
+        }
+
         return Json(Reply::Ok { value: data, token: handle.token });
     } else if let Err(e) = res {
         return e;
@@ -62,18 +99,34 @@ pub async fn list_repo(data: Data<Connection>, request: Json<RequestRepository>)
 }
 
 #[get("/repo/create")]
-pub async fn create_repo(repocontroller: Data<RwLock<RepoController>>, data: Data<Connection>, request: Json<RequestRepository>) -> Json<Reply<Repository>> {
+pub async fn create_repo(repocontroller: Data<RwLock<RepoController>>, data: Db, request: Json<RequestRepository>) -> Json<Reply<Repository>> {
     let res = handle_auth_request(&data, request.token);
     if let Ok(handle) = res {
+        if let Err(e) = handle_login_only(&handle) {
+            return e;
+        }
         
         // Adding it to the Database
         let res = database::create_repo(&data, request.clone());
         if let Some(mut rep) = res {
 
             // 
-            let mut repocontroller = repocontroller.write().await;
-            if repocontroller.create_repo(rep.repo_name.clone()) {
-                drop(repocontroller); // releasing the lock
+            let created = repocontroller.write().await.create_repo(rep.repo_name.clone());
+            if created {
+                if let Some(compression) = request.compression.clone() {
+                    if !file_processing::save_compression(&data, &rep.repo_name, &compression) {
+                        // The compression was asked for, so the repo is not created without it
+                        database::delete_repo(&data, rep.repo_name.clone());
+                        drop(data);
+                        file_processing::delete_repo(&repocontroller, &rep.repo_name).await;
+                        return Json(Reply::Error { token: handle.token });
+                    }
+
+                    let repo = repocontroller.read().await.get_repo(&rep.repo_name);
+                    if let Some(repo) = repo {
+                        repo.lock().await.set_compression(compression);
+                    }
+                }
                 database::set_user_repo_permission(&data, handle.user_id, rep.repo_name.clone(), AccessType::Owner);
                 rep.permission = Some(AccessType::Owner);
 
@@ -95,11 +148,11 @@ pub async fn create_repo(repocontroller: Data<RwLock<RepoController>>, data: Dat
 }
 
 #[get("/repo/delete")]
-pub async fn delete_repo(controller: Data<RwLock<RepoController>>, data: Data<Connection>, request: Json<RequestRepository>) -> Json<Reply<()>> {
+pub async fn delete_repo(controller: Data<RwLock<RepoController>>, data: Db, request: Json<RequestRepository>) -> Json<Reply<()>> {
     let res = handle_auth_request(&data, request.token);
     if let Ok(handle) = res {
         if let Some(repo_name) = &request.repo_name {
-            let res = database::get_user_repo_permission(&data, handle.user_id, repo_name.clone());
+            let res = database::get_handle_repo_permission(&data, &handle, repo_name.clone());
             if handle.admin {
                 // Will have access, irrelevant of what
             } else if let Some(acc) = res {
@@ -118,13 +171,19 @@ pub async fn delete_repo(controller: Data<RwLock<RepoController>>, data: Data<Co
                 }
             }
 
+            let owner = database::get_repo_owner(&data, repo_name);
             if database::delete_repo(&data, repo_name.clone()) {
-                let mut controller = controller.write().await;
-                if controller.delete_repo(repo_name) {
+                let pool = data.release();
+                if file_processing::delete_repo(&controller, repo_name).await {
                     return Json(Reply::Ok { value: (), token: handle.token });
+                } else if let Ok(data) = Db::connect(pool).await {
+                    // Undo deletion out of DB, the repo itself was already put back into the controller
+                    database::create_repo_fast(&data, repo_name.clone());
+                    if let Some(owner) = owner {
+                        database::set_user_repo_permission(&data, owner, repo_name.clone(), AccessType::Owner);
+                    }
+                    return Json(Reply::Error { token: handle.token });
                 } else {
-                    // Undo deletion out of DB
-                    controller.reload_folder(&data);
                     return Json(Reply::Error { token: handle.token });
                 }
             } else {
@@ -141,9 +200,13 @@ pub async fn delete_repo(controller: Data<RwLock<RepoController>>, data: Data<Co
 }
 
 #[get("/repo/permission/set")]
-pub async fn set_repo_access(data: Data<Connection>, request: Json<RepositoryAccess>) -> Json<Reply<()>> {
+pub async fn set_repo_access(data: Db, request: Json<RepositoryAccess>) -> Json<Reply<()>> {
     let res = handle_auth_request(&data, request.token);
     if let Ok(handle) = res {
+        if let Err(e) = handle_login_only(&handle) {
+            return e;
+        }
+
         // Check if user exists
         let res = database::get_user(&data, request.user_id);
         if let Some(_other_user) = res {
@@ -217,13 +280,92 @@ pub async fn set_repo_access(data: Data<Connection>, request: Json<RepositoryAcc
     Json(Reply::Failed)
 }
 
+fn to_settings(policy: SnapshotPolicy, compression: Compression, retention: Option<RetentionPolicy>) -> RepositorySettings {
+    RepositorySettings { snapshot_depth: policy.max_depth, snapshot_size: policy.max_size, compression, retention }
+}
+
+#[get("/repo/settings/info")]
+pub async fn get_repo_settings(data: Db, request: Json<RequestRepositorySettings>) -> Json<Reply<RepositorySettings>> {
+    let res = handle_auth_request(&data, request.token);
+    if let Ok(handle) = res {
+        if let Err(e) = handle_repo_access(&data, &handle, &request.repo_name, AccessType::is_read_allowed) {
+            return e;
+        }
+
+        // The settings are stored in the DB, so the repo does not have to be locked
+        let policy = file_processing::get_snapshot_policy(&data, &request.repo_name);
+        let compression = file_processing::get_compression(&data, &request.repo_name);
+        let retention = file_processing::get_retention_policy(&data, &request.repo_name);
+        return Json(Reply::Ok { value: to_settings(policy, compression, retention), token: handle.token });
+    } else if let Err(e) = res {
+        return e;
+    }
+
+    Json(Reply::Failed)
+}
+
+#[get("/repo/settings/set")]
+pub async fn set_repo_settings(controller: Data<RwLock<RepoController>>, data: Db, request: Json<RequestRepositorySettings>) -> Json<Reply<RepositorySettings>> {
+    let res = handle_auth_request(&data, request.token);
+    if let Ok(handle) = res {
+        if let Err(e) = handle_repo_access(&data, &handle, &request.repo_name, AccessType::is_manage_allowed) {
+            return e;
+        }
+
+        // Only overwriting what was passed in, 0 disables a limit
+        let mut policy = file_processing::get_snapshot_policy(&data, &request.repo_name);
+        let compression = request.compression.clone().unwrap_or(file_processing::get_compression(&data, &request.repo_name));
+        let retention = if request.disable_retention.unwrap_or(false) {
+            None
+        } else if let Some(retention) = &request.retention {
+            Some(retention.clone())
+        } else {
+            file_processing::get_retention_policy(&data, &request.repo_name)
+        };
+        if let Some(depth) = request.snapshot_depth {
+            policy.max_depth = depth;
+        }
+        if let Some(size) = request.snapshot_size {
+            policy.max_size = size;
+        }
+        if !retention.as_ref().map(file_processing::is_valid_retention_policy).unwrap_or(true) {
+            return Json(Reply::Error { token: handle.token });
+        }
+
+        let repo = controller.read().await.get_repo(&request.repo_name);
+        if let Some(repo) = repo {
+            // Changing the compression only affects new commits, the retention is applied on the next scheduled run
+            if file_processing::save_snapshot_policy(&data, &request.repo_name, &policy) &&
+                file_processing::save_compression(&data, &request.repo_name, &compression) &&
+                file_processing::save_retention_policy(&data, &request.repo_name, &retention) {
+                drop(data);
+                let mut repo = repo.lock().await;
+                repo.set_snapshot_policy(policy.clone());
+                repo.set_compression(compression.clone());
+                repo.set_retention_policy(retention.clone());
+                drop(repo);
+
+                return Json(Reply::Ok { value: to_settings(policy, compression, retention), token: handle.token });
+            } else {
+                return Json(Reply::Error { token: handle.token });
+            }
+        } else {
+            return Json(Reply::NotFound { token: handle.token });
+        }
+    } else if let Err(e) = res {
+        return e;
+    }
+
+    Json(Reply::Failed)
+}
+
 #[get("/repo/branch/list")]
-pub async fn list_branches(controller: Data<RwLock<RepoController>>, data: Data<Connection>, request: Json<RequestRepository>) -> Json<Reply<Vec<Branch>>> {
+pub async fn list_branches(controller: Data<RwLock<RepoController>>, data: Db, request: Json<RequestRepository>) -> Json<Reply<Vec<Branch>>> {
     let res = handle_auth_request(&data, request.token);
     if let Ok(handle) = res {
         if let Some(repo_name) = &request.repo_name {
             //Checking for access
-            let res = database::get_user_repo_permission(&data, handle.user_id, repo_name.clone());
+            let res = database::get_handle_repo_permission(&data, &handle, repo_name.clone());
             if handle.admin {
                 // Will have access, irrelevant of what
             } else if let Some(acc) = res {
@@ -241,10 +383,10 @@ pub async fn list_branches(controller: Data<RwLock<RepoController>>, data: Data<
             }
 
             // Getting the repo
-            let controller = controller.read().await;
-            let res = controller.get_repo(repo_name);
+            let res = controller.read().await.get_repo(repo_name);
             if let Some(repo) = res {
-                let repo = repo.lock().unwrap();
+                drop(data);
+                let repo = repo.lock().await;
                 let list = repo.get_branches();
 
                 let mut output = Vec::<Branch>::new();
@@ -269,15 +411,137 @@ pub async fn list_branches(controller: Data<RwLock<RepoController>>, data: Data<
     Json(Reply::Failed)
 }
 
+#[get("/repo/branch/create")]
+pub async fn create_branch(controller: Data<RwLock<RepoController>>, data: Db, request: Json<RequestBranch>) -> Json<Reply<Branch>> {
+    let res = handle_auth_request(&data, request.token);
+    if let Ok(handle) = res {
+        if let Err(e) = handle_repo_access(&data, &handle, &request.repo_name, AccessType::is_write_allowed) {
+            return e;
+        }
+
+        if !StorageRepo::is_valid_branch_name(&request.branch_name) {
+            return Json(Reply::Error { token: handle.token });
+        }
+
+        let repo = controller.read().await.get_repo(&request.repo_name);
+        if let Some(repo) = repo {
+            drop(data);
+            let mut repo = repo.lock().await;
+
+            // No commit means an empty branch
+            let commit = if let Some(commit) = request.commit { commit } else { U232::new() };
+
+            return match repo.create_branch(request.branch_name.clone(), commit) {
+                BranchUpdate::Ok => Json(Reply::Ok { value: Branch { name: request.branch_name.clone(), last_commit: commit }, token: handle.token }),
+                BranchUpdate::Conflict => Json(Reply::Conflict { token: handle.token }),
+                BranchUpdate::NotFound => Json(Reply::NotFound { token: handle.token }),
+                BranchUpdate::Err => Json(Reply::Error { token: handle.token })
+            };
+        } else {
+            return Json(Reply::NotFound { token: handle.token });
+        }
+    } else if let Err(e) = res {
+        return e;
+    }
+
+    Json(Reply::Failed)
+}
+
+#[get("/repo/branch/delete")]
+pub async fn delete_branch(controller: Data<RwLock<RepoController>>, data: Db, request: Json<RequestBranch>) -> Json<Reply<()>> {
+    let res = handle_auth_request(&data, request.token);
+    if let Ok(handle) = res {
+        if let Err(e) = handle_repo_access(&data, &handle, &request.repo_name, AccessType::is_delete_allowed) {
+            return e;
+        }
+
+        let repo = controller.read().await.get_repo(&request.repo_name);
+        if let Some(repo) = repo {
+            drop(data);
+            let mut repo = repo.lock().await;
+            repo.update_header_and_branches();
+
+            if let None = repo.get_branch(request.branch_name.clone()) {
+                return Json(Reply::NotFound { token: handle.token });
+            }
+
+            if repo.delete_branch(request.branch_name.clone()) {
+                return Json(Reply::Ok { value: (), token: handle.token });
+            } else {
+                return Json(Reply::Error { token: handle.token });
+            }
+        } else {
+            return Json(Reply::NotFound { token: handle.token });
+        }
+    } else if let Err(e) = res {
+        return e;
+    }
+
+    Json(Reply::Failed)
+}
+
+#[get("/repo/branch/push")]
+pub async fn push_branch(controller: Data<RwLock<RepoController>>, data: Db, request: Json<RequestBranch>) -> Json<Reply<Branch>> {
+    let res = handle_auth_request(&data, request.token);
+    if let Ok(handle) = res {
+        let commit = if let Some(commit) = request.commit {
+            commit
+        } else {
+            return Json(Reply::MissingParameter { token: handle.token });
+        };
+
+        // Forcing the branch onto a commit can drop commits from the branch, so it needs delete permissions
+        let force = request.force.unwrap_or(false);
+        let check = if force { AccessType::is_delete_allowed } else { AccessType::is_write_allowed };
+        if let Err(e) = handle_repo_access(&data, &handle, &request.repo_name, check) {
+            return e;
+        }
+
+        // Pushing onto a branch that does not exist creates it
+        if !StorageRepo::is_valid_branch_name(&request.branch_name) {
+            return Json(Reply::Error { token: handle.token });
+        }
+
+        let repo = controller.read().await.get_repo(&request.repo_name);
+        if let Some(repo) = repo {
+            drop(data);
+            let mut repo = repo.lock().await;
+
+            return match repo.push_commit_onto_branch(commit, request.branch_name.clone(), force) {
+                BranchUpdate::Ok => Json(Reply::Ok { value: Branch { name: request.branch_name.clone(), last_commit: commit }, token: handle.token }),
+                BranchUpdate::Conflict => Json(Reply::Conflict { token: handle.token }),
+                BranchUpdate::NotFound => Json(Reply::NotFound { token: handle.token }),
+                BranchUpdate::Err => Json(Reply::Error { token: handle.token })
+            };
+        } else {
+            return Json(Reply::NotFound { token: handle.token });
+        }
+    } else if let Err(e) = res {
+        return e;
+    }
+
+    Json(Reply::Failed)
+}
+
+// Replies with the commit id only, use /repo/commit/create/v2 to also get the files stored as full copies
 #[get("/repo/commit/create")]
-pub async fn create_commit(controller: Data<RwLock<RepoController>>, data: Data<Connection>, request: Json<CreateCommit>) -> Json<Reply<U232>> {
+pub async fn create_commit(controller: Data<RwLock<RepoController>>, data: Db, request: Json<CreateCommit>) -> Json<Reply<U232>> {
+    Json(commit_temp_folder(&controller, data, &request).await.0.map(|res| res.commit))
+}
+
+#[get("/repo/commit/create/v2")]
+pub async fn create_commit_v2(controller: Data<RwLock<RepoController>>, data: Db, request: Json<CreateCommit>) -> Json<Reply<CommitResult>> {
+    commit_temp_folder(&controller, data, &request).await
+}
+
+async fn commit_temp_folder(controller: &RwLock<RepoController>, data: Db, request: &CreateCommit) -> Json<Reply<CommitResult>> {
     let res = handle_auth_request(&data, request.token);
     if let Ok(handle) = res {
         if let Some(repo_db) = database::get_repo(&data, request.repo_name.clone()) {
             // Checking if the user is allowed to push
             let access = if handle.admin {
                 true
-            } else if let Some(perm) = database::get_user_repo_permission(&data, handle.user_id, repo_db.repo_name.clone()) {
+            } else if let Some(perm) = database::get_handle_repo_permission(&data, &handle, repo_db.repo_name.clone()) {
                 perm.is_write_allowed()
             } else {
                 false
@@ -294,6 +558,14 @@ pub async fn create_commit(controller: Data<RwLock<RepoController>>, data: Data<
                     return Json(Reply::Error { token: handle.token });
                 }
 
+                // From here on we wait on the repos, the connection is only needed again for the clean up
+                let limits = file_processing::get_repo_limits(&data, &repo_db.repo_name);
+                let pool = data.release();
+
+                if let Some((used, quota)) = file_processing::check_quota(controller, &limits, 0).await {
+                    return Json(Reply::QuotaExceeded { used, quota, token: handle.token });
+                }
+
                 // Applying the folder_name
                 let build_path = if let Some(name) = folder.folder_name {
                     let mut target = path.clone();
@@ -312,9 +584,9 @@ pub async fn create_commit(controller: Data<RwLock<RepoController>>, data: Data<
                     }
                 };
 
-                let conn = controller.read().await;
-                if let Some(repo) = conn.get_repo(&repo_db.repo_name) {
-                    let mut repo = repo.lock().unwrap();
+                let repo = controller.read().await.get_repo(&repo_db.repo_name);
+                if let Some(repo) = repo {
+                    let mut repo = repo.lock().await;
                     
                     // Checking for the previous commit
                     let previous_commit = if let Some(prev) = request.previous_commit {
@@ -323,7 +595,6 @@ pub async fn create_commit(controller: Data<RwLock<RepoController>>, data: Data<
                         } else if let Err(_) = repo.get_commit(prev) {
                             // Previous commit could not be found
                             drop(repo);
-                            drop(conn);
                             return Json(Reply::NotFound { token: handle.token });
                         } else {
                             Some(prev)
@@ -334,7 +605,8 @@ pub async fn create_commit(controller: Data<RwLock<RepoController>>, data: Data<
 
                     
                     // Creating the commit
-                    if let Some(commit) = repo.create_commit(previous_commit, build_path.as_path(), build_path.eq(&path)) {
+                    let res = repo.create_commit(previous_commit, build_path.as_path(), build_path.eq(&path));
+                    if let Ok(commit) = res {
                         if previous_commit != Some(commit.clone()) {
                             let time =if let Ok(t) = chrono::Utc::now().timestamp().try_into() {
                                 t
@@ -350,21 +622,30 @@ pub async fn create_commit(controller: Data<RwLock<RepoController>>, data: Data<
                             repo.set_commit_info(commit, CommitInfo::new(handle.user_id, handle.device_id, text, time));
                         }
 
+                        // Reporting files that were stored as a full copy, relative to the folder
+                        let full_copies = repo.get_full_copies().iter()
+                            .map(|p| p.strip_prefix(path.as_path()).unwrap_or(p.as_path()).to_str().unwrap_or_default().to_string())
+                            .collect();
+
                         drop(repo);
-                        drop(conn);
 
-                        // Cleaning up the temp folder
-                        database::delete_temp_folder(&data, folder.folder_token);
-                        file_processing::delete_temp_folder(&data, folder.folder_token);
+                        // Cleaning up the temp folder, the commit stands even if no connection is free for it
+                        if let Ok(data) = Db::connect(pool).await {
+                            database::delete_temp_folder(&data, folder.folder_token);
+                            file_processing::delete_temp_folder(&data, folder.folder_token);
+                        }
                         // No need to worry about sub folders, as we inforce that there should not be any
                         // although during execution of this command some might have been created
                         // we assume proper usage of the API (high expectations, I know, but this can only be done by the client also running this request, no one else has the folder token)
 
-                        return Json(Reply::Ok { value: commit, token: handle.token });
+                        return Json(Reply::Ok { value: CommitResult { commit, full_copies }, token: handle.token });
                     } else {
                         // Something went wrong
                         drop(repo);
-                        drop(conn);
+                        if let Err(CommitError::PreviousNotFound) = res {
+                            // History of the previous commit is broken
+                            return Json(Reply::NotFound { token: handle.token });
+                        }
                         return Json(Reply::Error { token: handle.token });
                     }
                 } else {
@@ -384,4 +665,305 @@ pub async fn create_commit(controller: Data<RwLock<RepoController>>, data: Data<
     }
 
     Json(Reply::Failed)
-}
\ No newline at end of file
+}
+
+#[get("/repo/commit/checkout")]
+pub async fn checkout_commit(controller: Data<RwLock<RepoController>>, data: Db, request: Json<RequestCommit>) -> Json<Reply<Folder>> {
+    let res = handle_auth_request(&data, request.token);
+    if let Ok(handle) = res {
+        if request.commit.is_none() && request.branch_name.is_none() {
+            return Json(Reply::MissingParameter { token: handle.token });
+        }
+
+        if let Err(e) = handle_repo_access(&data, &handle, &request.repo_name, AccessType::is_read_allowed) {
+            return e;
+        }
+
+        let repo = controller.read().await.get_repo(&request.repo_name);
+        if let Some(repo) = repo {
+            // Creating the folder to build into, the connection is not held while waiting on the repo
+            let mut folder = database::create_temp_folder(&data, None);
+            if !file_processing::create_temp_folder(&data, folder.folder_token) {
+                database::delete_temp_folder(&data, folder.folder_token);
+                return Json(Reply::Error { token: handle.token });
+            }
+            let path = file_processing::get_temp_folder_path(&data, folder.folder_token);
+            let pool = data.release();
+
+            // None if the commit does not exist
+            let built = if let Some(path) = path {
+                let mut repo = repo.lock().await;
+                resolve_commit(&mut repo, request.commit, &request.branch_name).map(|commit| repo.build_commit(commit, path.as_path()))
+            } else {
+                Some(false)
+            };
+
+            let data = if let Ok(data) = Db::connect(pool).await {
+                data
+            } else {
+                // The folder is left for the next prune
+                return Json(Reply::Error { token: handle.token });
+            };
+
+            if let Some(true) = built {
+                folder.content = file_processing::list_temp_folder_content(&data, folder.folder_token);
+                return Json(Reply::Ok { value: folder, token: handle.token });
+            } else {
+                // Cleaning up what we created
+                database::delete_temp_folder(&data, folder.folder_token);
+                file_processing::delete_temp_folder(&data, folder.folder_token);
+                if let None = built {
+                    return Json(Reply::NotFound { token: handle.token });
+                }
+                return Json(Reply::Error { token: handle.token });
+            }
+        } else {
+            return Json(Reply::NotFound { token: handle.token });
+        }
+    } else if let Err(e) = res {
+        return e;
+    }
+
+    Json(Reply::Failed)
+}
+
+#[get("/repo/commit/log")]
+pub async fn commit_log(controller: Data<RwLock<RepoController>>, data: Db, request: Json<RequestCommitLog>) -> Json<Reply<CommitLog>> {
+    let res = handle_auth_request(&data, request.token);
+    if let Ok(handle) = res {
+        if request.commit.is_none() && request.branch_name.is_none() {
+            return Json(Reply::MissingParameter { token: handle.token });
+        }
+
+        if let Err(e) = handle_repo_access(&data, &handle, &request.repo_name, AccessType::is_read_allowed) {
+            return e;
+        }
+
+        let limit = request.limit.unwrap_or(DEFAULT_LOG_LIMIT).clamp(1, MAX_LOG_LIMIT);
+        let depth = request.depth.unwrap_or(MAX_LOG_DEPTH).min(MAX_LOG_DEPTH);
+
+        let repo = controller.read().await.get_repo(&request.repo_name);
+        let pool = data.release();
+        let (entries, next) = if let Some(repo) = repo {
+            let mut repo = repo.lock().await;
+
+            if let Some(commit) = resolve_commit(&mut repo, request.commit, &request.branch_name) {
+                repo.get_commit_log(commit, limit, depth)
+            } else {
+                return Json(Reply::NotFound { token: handle.token });
+            }
+        } else {
+            return Json(Reply::NotFound { token: handle.token });
+        };
+
+        let data = if let Ok(data) = Db::connect(pool).await {
+            data
+        } else {
+            return Json(Reply::Error { token: handle.token });
+        };
+
+        // Resolving the names, with a cache as most commits come from the same few users
+        let mut user_names = HashMap::<u32, Option<String>>::new();
+        let mut device_names = HashMap::<(u32, u8), Option<String>>::new();
+
+        let mut commits = Vec::<CommitEntry>::new();
+        for entry in entries {
+            let mut output = CommitEntry {
+                commit: entry.id,
+                previous_commit: entry.previous_commit,
+                commit_type: entry.commit_type,
+                user_id: None,
+                user_name: None,
+                device_id: None,
+                device_name: None,
+                message: None,
+                timestamp: entry.timestamp
+            };
+
+            if let Some(info) = entry.info {
+                let user_id = info.get_user();
+                let device_id = info.get_device();
+
+                output.user_name = user_names.entry(user_id).or_insert_with(|| {
+                    database::get_user(&data, user_id).map(|user| user.user_name)
+                }).clone();
+                output.device_name = device_names.entry((user_id, device_id)).or_insert_with(|| {
+                    database::get_device(&data, user_id, device_id).map(|device| device.device_name)
+                }).clone();
+
+                output.user_id = Some(user_id);
+                output.device_id = Some(device_id);
+                
+                let text = info.get_text();
+                if !text.is_empty() {
+                    output.message = Some(text);
+                }
+            }
+
+            commits.push(output);
+        }
+
+        return Json(Reply::Ok { value: CommitLog { commits, next }, token: handle.token });
+    } else if let Err(e) = res {
+        return e;
+    }
+
+    Json(Reply::Failed)
+}
+
+#[get("/repo/commit/tree")]
+pub async fn commit_tree(controller: Data<RwLock<RepoController>>, data: Db, request: Json<RequestCommit>) -> Json<Reply<TreeEntry>> {
+    let res = handle_auth_request(&data, request.token);
+    if let Ok(handle) = res {
+        if request.commit.is_none() && request.branch_name.is_none() {
+            return Json(Reply::MissingParameter { token: handle.token });
+        }
+
+        if let Err(e) = handle_repo_access(&data, &handle, &request.repo_name, AccessType::is_read_allowed) {
+            return e;
+        }
+
+        let repo = controller.read().await.get_repo(&request.repo_name);
+        if let Some(repo) = repo {
+            drop(data);
+            let mut repo = repo.lock().await;
+
+            let commit = if let Some(commit) = resolve_commit(&mut repo, request.commit, &request.branch_name) {
+                commit
+            } else {
+                return Json(Reply::NotFound { token: handle.token });
+            };
+
+            if let Some(tree) = repo.get_commit_tree(commit) {
+                return Json(Reply::Ok { value: tree, token: handle.token });
+            } else {
+                // Either a delete commit, or the history is broken
+                return Json(Reply::Error { token: handle.token });
+            }
+        } else {
+            return Json(Reply::NotFound { token: handle.token });
+        }
+    } else if let Err(e) = res {
+        return e;
+    }
+
+    Json(Reply::Failed)
+}
+
+#[get("/repo/commit/diff")]
+pub async fn commit_diff(controller: Data<RwLock<RepoController>>, data: Db, request: Json<RequestDiff>) -> Json<Reply<CommitDiff>> {
+    let res = handle_auth_request(&data, request.token);
+    if let Ok(handle) = res {
+        if (request.from_commit.is_none() && request.from_branch.is_none()) || (request.to_commit.is_none() && request.to_branch.is_none()) {
+            return Json(Reply::MissingParameter { token: handle.token });
+        }
+
+        if let Err(e) = handle_repo_access(&data, &handle, &request.repo_name, AccessType::is_read_allowed) {
+            return e;
+        }
+
+        let repo = controller.read().await.get_repo(&request.repo_name);
+        if let Some(repo) = repo {
+            drop(data);
+            let mut repo = repo.lock().await;
+
+            let from = resolve_commit(&mut repo, request.from_commit, &request.from_branch);
+            let to = resolve_commit(&mut repo, request.to_commit, &request.to_branch);
+            let (from, to) = if let (Some(from), Some(to)) = (from, to) {
+                (from, to)
+            } else {
+                return Json(Reply::NotFound { token: handle.token });
+            };
+
+            if let Some(diff) = repo.diff_commits(from, to) {
+                return Json(Reply::Ok { value: diff, token: handle.token });
+            } else {
+                return Json(Reply::Error { token: handle.token });
+            }
+        } else {
+            return Json(Reply::NotFound { token: handle.token });
+        }
+    } else if let Err(e) = res {
+        return e;
+    }
+
+    Json(Reply::Failed)
+}
+#[cfg(test)]
+mod tests {
+    use std::{rc::Rc, time::Duration};
+
+    use actix_web::{test, App, rt, web::Data};
+    use common::{U256, LargeU, data::{Reply, RequestRepository, Repository, Branch}};
+    use uuid::Uuid;
+
+    use crate::{database, file_processing::{io, test_util::open_server}};
+    use super::{create_repo, list_branches};
+
+    const WAITING:usize = 24; // More than the pool has connections
+
+    fn request(token: Uuid, name: &str) -> RequestRepository {
+        RequestRepository { token: Some(token), repo_name: Some(name.to_string()), display_name: None, game: None, compression: None }
+    }
+
+    // Requests waiting on a busy repo may not hold the controller, else a queued write to it stalls the requests for every other repo
+    // Neither may they hold a connection, else they use up the pool
+    #[actix_web::test]
+    async fn busy_repo_does_not_stall_others() {
+        let (root, pool, controller) = open_server("busy");
+        let db = pool.get().unwrap();
+        assert!(database::create_user(&db, "user".to_string(), U256::from_u8arr(&[1; 32]), false));
+        let token = database::login(&db, "user".to_string(), U256::from_u8arr(&[1; 32]), 0).unwrap().token;
+        drop(db);
+
+        let app = Rc::new(test::init_service(App::new()
+            .app_data(Data::new(pool))
+            .app_data(Data::from(controller.clone()))
+            .service(create_repo)
+            .service(list_branches)).await);
+
+        for name in ["Busy", "Other"] {
+            let req = test::TestRequest::get().uri("/repo/create").set_json(request(token, name)).to_request();
+            let res: Reply<Repository> = test::call_and_read_body_json(&*app, req).await;
+            assert!(matches!(res, Reply::Ok { .. }));
+        }
+
+        // Holding the repo, like a long running commit would
+        let busy = controller.read().await.get_repo(&"Busy".to_string()).unwrap();
+        let guard = busy.lock().await;
+
+        let mut waiting = Vec::new();
+        for _ in 0..WAITING {
+            let app = app.clone();
+            waiting.push(rt::spawn(async move {
+                let req = test::TestRequest::get().uri("/repo/branch/list").set_json(request(token, "Busy")).to_request();
+                let res: Reply<Vec<Branch>> = test::call_and_read_body_json(&*app, req).await;
+                res
+            }));
+        }
+        rt::time::sleep(Duration::from_millis(100)).await;
+
+        // Creating a repo writes to the controller, the other repo has to stay usable meanwhile
+        let other = {
+            let app = app.clone();
+            rt::spawn(async move {
+                let req = test::TestRequest::get().uri("/repo/create").set_json(request(token, "New")).to_request();
+                let res: Reply<Repository> = test::call_and_read_body_json(&*app, req).await;
+                assert!(matches!(res, Reply::Ok { .. }));
+
+                let req = test::TestRequest::get().uri("/repo/branch/list").set_json(request(token, "Other")).to_request();
+                let res: Reply<Vec<Branch>> = test::call_and_read_body_json(&*app, req).await;
+                assert!(matches!(res, Reply::Ok { .. }));
+            })
+        };
+        assert!(rt::time::timeout(Duration::from_secs(10), other).await.unwrap().is_ok());
+        assert!(waiting.iter().all(|handle| !handle.is_finished()));
+
+        drop(guard);
+        for handle in waiting {
+            assert!(matches!(handle.await.unwrap(), Reply::Ok { .. }));
+        }
+
+        let _ = io::delete_folder(root.as_path());
+    }
+}
@@ -1,8 +1,7 @@
 use actix_web::{get, post, web::{Data, Json}, HttpResponse, HttpRequest};
 use actix_web_lab::__reexports::{tokio::sync::RwLock};
 use common::data::{RequestUser, Reply, RequestRepository};
-use rusqlite::Connection;
-use crate::{database, file_processing::RepoController, api::handle_auth_request};
+use crate::{database, file_processing::RepoController, api::{Db, handle_auth_request}};
 
 #[get("/ping")]
 pub async fn get_ping() -> Json<String> {
@@ -10,7 +9,7 @@ pub async fn get_ping() -> Json<String> {
 }
 
 #[get("/user/all")]
-pub async fn get_all_user(data: Data<Connection>) -> Json<Vec<RequestUser>> {
+pub async fn get_all_user(data: Db) -> Json<Vec<RequestUser>> {
     let res = database::get_all_users(&data);
 
 
@@ -22,7 +21,7 @@ pub async fn get_all_user(data: Data<Connection>) -> Json<Vec<RequestUser>> {
 
 
 #[get("/placeholder")]
-pub async fn placeholder(data: Data<Connection>, request: Json<RequestRepository>) -> Json<Reply<()>> {
+pub async fn placeholder(data: Db, request: Json<RequestRepository>) -> Json<Reply<()>> {
     let res = handle_auth_request(&data, request.token);
     if let Ok(_handle) = res {
 
@@ -1,13 +1,14 @@
-use actix_web::{web::{Data, Json, Payload, Path}, get, post, HttpResponse};
+use actix_web::{web::{Data, Json, Payload, Path}, get, post, HttpResponse, HttpRequest, http::header};
+use actix_web_lab::__reexports::tokio::sync::RwLock;
 use actix_web_lab::__reexports::futures_util::StreamExt;
 use common::data::{Reply, RequestFolder, Folder, UploadFile};
 use rusqlite::Connection;
 use uuid::Uuid;
 
-use crate::{database, api::handle_auth_request, file_processing};
+use crate::{database, api::{Db, handle_auth_request}, file_processing::{self, RepoController}};
 
 #[get("/upload/folder")]
-pub async fn upload_folder(data: Data<Connection>, request: Json<RequestFolder>) -> Json<Reply<Folder>> {
+pub async fn upload_folder(data: Db, request: Json<RequestFolder>) -> Json<Reply<Folder>> {
     let res = handle_auth_request(&data, request.token);
     if let Ok(handle) = res {
         let folder_name = if let Some(name) = &request.folder_name {
@@ -47,6 +48,7 @@ pub async fn upload_folder(data: Data<Connection>, request: Json<RequestFolder>)
 
 
         let folder = database::create_temp_folder(&data, folder_name);
+        database::set_temp_folder_owner(&data, folder.folder_token, handle.user_id);
         if file_processing::create_temp_folder(&data, folder.folder_token) {
             if let Some(parent) = request.parent_folder {
                 if !database::link_temp_parent_folder(&data, parent, folder.folder_token) {
@@ -70,7 +72,7 @@ pub async fn upload_folder(data: Data<Connection>, request: Json<RequestFolder>)
 }
 
 #[post("/upload/file/{folder_token}/{path}")]
-pub async fn upload_file(data: Data<Connection>, mut body: Payload, target: Path<UploadFile>) -> HttpResponse {
+pub async fn upload_file(controller: Data<RwLock<RepoController>>, data: Db, req: HttpRequest, mut body: Payload, target: Path<UploadFile>) -> HttpResponse {
     let res = file_processing::get_temp_folder_path(&data, target.folder_token);
     if let Some(mut path) = res {
         for item in database::get_sub_folders(&data, target.folder_token) {
@@ -82,18 +84,44 @@ pub async fn upload_file(data: Data<Connection>, mut body: Payload, target: Path
             }
         }
 
-        let mut data = Vec::<u8>::new();
+        // The upload counts towards the quota of whoever created the folder
+        let limit = database::get_temp_folder_owner(&data, target.folder_token).and_then(|owner| file_processing::get_user_limit(&data, owner));
+        drop(data); // Not held while waiting on the repos or the body
+        let usage = if let Some(limit) = limit {
+            Some(file_processing::get_usage(&controller, &limit).await)
+        } else {
+            None
+        };
+        let check_quota = |additional: u64| -> Option<HttpResponse> {
+            let (used, quota) = usage?;
+            let used = used.saturating_add(additional);
+            if file_processing::is_over_quota(used, quota) {
+                return Some(HttpResponse::PayloadTooLarge().json(Reply::<()>::QuotaExceeded { used, quota, token: None }));
+            }
+            None
+        };
+
+        // If the client tells us the size we can reject it before reading anything
+        let length = req.headers().get(header::CONTENT_LENGTH).and_then(|len| len.to_str().ok()).and_then(|len| len.parse::<u64>().ok());
+        if let Some(response) = length.and_then(|len| check_quota(len)) {
+            return response;
+        }
+
+        let mut bytes = Vec::<u8>::new();
 
         while let Some(item) = body.next().await {
             if let Ok(item) = item {
-                for by in item {
-                    data.push(by);
+                bytes.extend_from_slice(&item);
+
+                // Else the size might have been left out or be wrong
+                if let Some(response) = check_quota(bytes.len().try_into().unwrap_or(u64::MAX)) {
+                    return response;
                 }
             }
         }
 
         path.push(target.path.clone());
-        if let Ok(_) = file_processing::io::write_bytes(path.as_path(), data) {
+        if let Ok(_) = file_processing::io::write_bytes(path.as_path(), bytes) {
             return HttpResponse::Ok().finish()
         } else {
             return HttpResponse::InternalServerError().finish();
@@ -104,7 +132,7 @@ pub async fn upload_file(data: Data<Connection>, mut body: Payload, target: Path
 }
 
 #[get("/upload/merge")]
-pub async fn merge_folders(data: Data<Connection>, request: Json<RequestFolder>) -> Json<Reply<Folder>> {
+pub async fn merge_folders(data: Db, request: Json<RequestFolder>) -> Json<Reply<Folder>> {
     fn recursive_folder_merger(data: &Connection, folder_token: Uuid) -> Result<(),()> {
         if let Some(_folder) = database::get_temp_folder(&data, folder_token) {
             let subs = database::get_sub_folders(&data, folder_token);
@@ -164,7 +192,7 @@ pub async fn merge_folders(data: Data<Connection>, request: Json<RequestFolder>)
 }
 
 #[get("/download/list")]
-pub async fn get_download_folder(data: Data<Connection>, request: Json<RequestFolder>) -> Json<Reply<Folder>> {
+pub async fn get_download_folder(data: Db, request: Json<RequestFolder>) -> Json<Reply<Folder>> {
     let res = handle_auth_request(&data, request.token);
     if let Ok(handle) = res {
         if let Some(folder_token) = request.folder_token {
@@ -184,7 +212,7 @@ pub async fn get_download_folder(data: Data<Connection>, request: Json<RequestFo
 }
 
 #[get("/download")]
-pub async fn download(data: Data<Connection>, request: Json<UploadFile>) -> HttpResponse {
+pub async fn download(data: Db, request: Json<UploadFile>) -> HttpResponse {
     // There is not authentication, maybe we should?
     if let Some(mut folder) = file_processing::get_temp_folder_path(&data, request.folder_token) {
         folder.push(request.path.clone());
@@ -203,7 +231,7 @@ pub async fn download(data: Data<Connection>, request: Json<UploadFile>) -> Http
 }
 
 #[get("/download/clear")]
-pub async fn clear_temp_folder(data: Data<Connection>, request: Json<RequestFolder>) -> Json<Reply<()>> {
+pub async fn clear_temp_folder(data: Db, request: Json<RequestFolder>) -> Json<Reply<()>> {
     fn recursive_delete(data: &Connection, folder: Uuid) {
         // Deleting the subs
         for item in database::get_sub_folders(data, folder) {
@@ -232,4 +260,60 @@ pub async fn clear_temp_folder(data: Data<Connection>, request: Json<RequestFold
     }
 
     Json(Reply::Failed)
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use actix_web::{test, App, web::Data, http::{StatusCode, header}};
+    use common::{U256, LargeU};
+
+    use crate::{database, file_processing::{self, io, test_util::open_server}};
+    use super::upload_file;
+
+    #[actix_web::test]
+    async fn upload_stops_at_quota() {
+        let (root, pool, controller) = open_server("upload");
+        let db = pool.get().unwrap();
+        assert!(database::create_user(&db, "uploader".to_string(), U256::from_u8arr(&[1; 32]), false));
+        assert!(database::set_user_quota(&db, 1, Some(1000)));
+        let folder = database::create_temp_folder(&db, None);
+        assert!(database::set_temp_folder_owner(&db, folder.folder_token, 1));
+        assert!(file_processing::create_temp_folder(&db, folder.folder_token));
+        drop(db);
+
+        let app = test::init_service(App::new()
+            .app_data(Data::new(pool))
+            .app_data(Data::from(controller))
+            .service(upload_file)).await;
+        let uri = |name: &str| format!("/upload/file/{}/{}", folder.folder_token, name);
+        let uploaded = |name: &str| -> PathBuf {
+            let mut file = root.clone();
+            file.push("temp");
+            file.push(folder.folder_token.to_string());
+            file.push(name);
+            file
+        };
+
+        // Rejected by the announced size
+        let req = test::TestRequest::post().uri(&uri("announced.sav"))
+            .insert_header((header::CONTENT_LENGTH, "2000"))
+            .set_payload(vec![1_u8; 2000]).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(!uploaded("announced.sav").exists());
+
+        // A size that lies is caught while reading
+        let req = test::TestRequest::post().uri(&uri("lying.sav"))
+            .insert_header((header::CONTENT_LENGTH, "10"))
+            .set_payload(vec![1_u8; 2000]).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(!uploaded("lying.sav").exists());
+
+        // Exactly reaching the quota is fine
+        let req = test::TestRequest::post().uri(&uri("fits.sav")).set_payload(vec![1_u8; 1000]).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        assert_eq!(io::get_file_size(uploaded("fits.sav").as_path()).unwrap(), 1000);
+
+        let _ = io::delete_folder(root.as_path());
+    }
+}
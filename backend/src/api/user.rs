@@ -1,21 +1,49 @@
-use actix_web::{web::{Data, Json}, get};
-use common::data::{RequestUser, Reply, TokenCarrier, User, RequestDevice, Device};
-use rusqlite::Connection;
+use actix_web::{web::{Data, Json}, get, HttpRequest};
+use actix_web_lab::__reexports::tokio::sync::RwLock;
+use common::data::{RequestUser, Reply, TokenCarrier, User, RequestDevice, Device, LockoutSource, RequestSession, Session, AccessType, RequestApiKey, ApiKey, NewApiKey};
 use uuid::Uuid;
 
-use crate::{database, api::handle_auth_request};
+use crate::{database, api::{Db, handle_auth_request, handle_login_only, get_client_address}, file_processing::{self, RepoController}};
 
 #[get("/login")]
-pub async fn login(data: Data<Connection>, user: Json<RequestUser>) -> Json<Reply<TokenCarrier>> {
+pub async fn login(data: Db, req: HttpRequest, user: Json<RequestUser>) -> Json<Reply<TokenCarrier>> {
     if let Some(name) = &user.user_name {
         if let Some(password) = user.password {
-            let car = if let Some(device_id) = user.device_id {
-                database::login(&data, name.clone(), password, device_id)
+            // The attempt counts before the password is checked, attempts during a lockout are not checked, so they don't count either
+            let address = get_client_address(&req);
+            let now = chrono::Utc::now().timestamp();
+            let user_lock = database::record_login_attempt(&data, &LockoutSource::User, name, now);
+            let address_lock = database::record_login_attempt(&data, &LockoutSource::Address, &address, now);
+
+            let address_lock = if let (Some(_), Some(address_lock)) = (user_lock, address_lock) {
+                address_lock
             } else {
-                database::login(&data, name.clone(), password, 0_u8)
+                // Only one of them was locked, the other one does not count the attempt either
+                if let Some(user_lock) = user_lock {
+                    database::forgive_login_attempt(&data, &LockoutSource::User, name, user_lock);
+                }
+                if let Some(address_lock) = address_lock {
+                    database::forgive_login_attempt(&data, &LockoutSource::Address, &address, address_lock);
+                }
+
+                let wait = database::get_login_lockout(&data, &LockoutSource::User, name, now)
+                    .max(database::get_login_lockout(&data, &LockoutSource::Address, &address, now));
+                return Json(Reply::TooManyAttempts { retry_after: wait });
+            };
+
+            // Checking the password hash takes a while
+            let device_id = user.device_id.unwrap_or(0_u8);
+            let user_name = name.clone();
+            let (data, car) = if let Ok(res) = data.block(move |conn| database::login(conn, user_name, password, device_id)).await {
+                res
+            } else {
+                return Json(Reply::Failed);
             };
 
             return if let Some(token) = car {
+                // The address keeps it's earlier failures, otherwise logging into your own account would reset them
+                database::clear_login_failures(&data, &LockoutSource::User, name);
+                database::forgive_login_attempt(&data, &LockoutSource::Address, &address, address_lock);
                 Json(Reply::new(token))
             } else {
                 Json(Reply::AuthFailed)
@@ -27,9 +55,20 @@ pub async fn login(data: Data<Connection>, user: Json<RequestUser>) -> Json<Repl
 }
 
 #[get("/auth")]
-pub async fn auth(data: Data<Connection>, token: Json<TokenCarrier>) -> Json<Reply<TokenCarrier>> {
+pub async fn auth(data: Db, req: HttpRequest, token: Json<TokenCarrier>) -> Json<Reply<TokenCarrier>> {
+    // Guessing tokens is only limited by address, as there is no user yet
+    let address = get_client_address(&req);
+    let now = chrono::Utc::now().timestamp();
+    let lock = if let Some(lock) = database::record_login_attempt(&data, &LockoutSource::Address, &address, now) {
+        lock
+    } else {
+        let wait = database::get_login_lockout(&data, &LockoutSource::Address, &address, now);
+        return Json(Reply::TooManyAttempts { retry_after: wait });
+    };
+
     let auth = database::authenticate(&data, &token);
     if let Some(new_token) = auth {
+        database::forgive_login_attempt(&data, &LockoutSource::Address, &address, lock);
         if new_token.token != token.token {
             return Json(Reply::new(new_token));
         }
@@ -37,14 +76,13 @@ pub async fn auth(data: Data<Connection>, token: Json<TokenCarrier>) -> Json<Rep
         return Json(Reply::new(new_token));
     }
 
-
     Json(Reply::AuthFailed)
 }
 
 
 
 #[get("/user/create")]
-pub async fn create_new_user(data: Data<Connection>, user: Json<RequestUser>) -> Json<Reply<()>> {
+pub async fn create_new_user(data: Db, user: Json<RequestUser>) -> Json<Reply<()>> {
     if let Some(name) = &user.user_name {
         if let Some(password) = user.password {
 
@@ -54,7 +92,8 @@ pub async fn create_new_user(data: Data<Connection>, user: Json<RequestUser>) ->
                     let res = handle_auth_request(&data, user.token);
                     if let Ok(handle) = res {
                         if handle.admin {
-                            if database::create_user(&data, name.clone(), password, true) {
+                            let user_name = name.clone();
+                            if let Ok((_, true)) = data.block(move |conn| database::create_user(conn, user_name, password, true)).await {
                                 return Json(Reply::Ok { value: (), token: handle.token }); // Normal registration does not auth the current user, this one does, therefore token update
                             }
                         } else {
@@ -69,7 +108,9 @@ pub async fn create_new_user(data: Data<Connection>, user: Json<RequestUser>) ->
                 }
             }
 
-            if database::create_user(&data, name.clone(), password, false) {
+            // Hashing the password takes a while
+            let user_name = name.clone();
+            if let Ok((_, true)) = data.block(move |conn| database::create_user(conn, user_name, password, false)).await {
                 return Json(Reply::new(()));
             }
         }
@@ -79,7 +120,7 @@ pub async fn create_new_user(data: Data<Connection>, user: Json<RequestUser>) ->
 }
 
 #[get("/user/info")]
-pub async fn get_user(data: Data<Connection>, user: Json<RequestUser>) -> Json<Reply<User>> {
+pub async fn get_user(controller: Data<RwLock<RepoController>>, data: Db, user: Json<RequestUser>) -> Json<Reply<User>> {
     let res = handle_auth_request(&data, user.token);
     if let Ok(handle) = res {
         let target_user_id = if let Some(requested) = user.user_id {
@@ -95,7 +136,11 @@ pub async fn get_user(data: Data<Connection>, user: Json<RequestUser>) -> Json<R
             
             
         let res = database::get_user(&data, target_user_id);
-        if let Some(user) = res {
+        if let Some(mut user) = res {
+            user.storage_quota = database::get_user_quota(&data, user.user_id);
+            let owned = database::get_owned_repos(&data, user.user_id);
+            drop(data);
+            user.storage_used = Some(file_processing::get_storage(&controller, &owned).await);
             return Json(Reply::Ok { value: user, token: handle.token });
         } else {
             return Json(Reply::NotFound { token: handle.token });
@@ -108,9 +153,13 @@ pub async fn get_user(data: Data<Connection>, user: Json<RequestUser>) -> Json<R
 }
 
 #[get("/user/delete")]
-pub async fn delete_user(data: Data<Connection>, user: Json<RequestUser>) -> Json<Reply<()>> {
+pub async fn delete_user(data: Db, user: Json<RequestUser>) -> Json<Reply<()>> {
     let res = handle_auth_request(&data, user.token);
     if let Ok(handle) = res {
+        if let Err(e) = handle_login_only(&handle) {
+            return e;
+        }
+
         let target_user_id = if let Some(requested) = user.user_id {
             if handle.admin {
                 requested
@@ -146,7 +195,7 @@ pub async fn delete_user(data: Data<Connection>, user: Json<RequestUser>) -> Jso
 }
 
 #[get("/device/info")]
-pub async fn get_device(data: Data<Connection>, device: Json<RequestDevice>) -> Json<Reply<Device>> {
+pub async fn get_device(data: Db, device: Json<RequestDevice>) -> Json<Reply<Device>> {
     let res = handle_auth_request(&data, device.token);
     if let Ok(handle) = res {
         let target_user_id = if let Some(requested) = device.user_id {
@@ -187,9 +236,13 @@ pub async fn get_device(data: Data<Connection>, device: Json<RequestDevice>) ->
 }
 
 #[get("/device/create")]
-pub async fn create_device(data: Data<Connection>, device: Json<RequestDevice>) -> Json<Reply<Device>> {
+pub async fn create_device(data: Db, device: Json<RequestDevice>) -> Json<Reply<Device>> {
     let res = handle_auth_request(&data, device.token);
     if let Ok(handle) = res {
+        if let Err(e) = handle_login_only(&handle) {
+            return e;
+        }
+
         if let Some(device_name) = &device.device_name {
             let target_user_id = if let Some(requested) = device.user_id {
                 if handle.admin {
@@ -219,9 +272,13 @@ pub async fn create_device(data: Data<Connection>, device: Json<RequestDevice>)
 }
 
 #[get("/device/delete")]
-pub async fn delete_device(data: Data<Connection>, device: Json<RequestDevice>) -> Json<Reply<()>> {
+pub async fn delete_device(data: Db, device: Json<RequestDevice>) -> Json<Reply<()>> {
     let res = handle_auth_request(&data, device.token);
     if let Ok(mut handle) = res {
+        if let Err(e) = handle_login_only(&handle) {
+            return e;
+        }
+
         let target_user_id = if let Some(requested) = device.user_id {
             if handle.admin {
                 requested
@@ -285,4 +342,236 @@ pub async fn delete_device(data: Data<Connection>, device: Json<RequestDevice>)
     }
 
     Json(Reply::Failed)
-}
\ No newline at end of file
+}
+
+#[get("/user/session/list")]
+pub async fn list_sessions(data: Db, request: Json<RequestSession>) -> Json<Reply<Vec<Session>>> {
+    let res = handle_auth_request(&data, request.token);
+    if let Ok(handle) = res {
+        if let Err(e) = handle_login_only(&handle) {
+            return e;
+        }
+
+        let target_user_id = if let Some(requested) = request.user_id {
+            if handle.admin {
+                requested
+            } else {
+                return Json(Reply::Denied { token: handle.token });
+            }
+        } else {
+            handle.user_id
+        };
+
+        if let None = database::get_user(&data, target_user_id) {
+            return Json(Reply::NotFound { token: handle.token });
+        }
+
+        let mut list = database::get_sessions(&data, target_user_id);
+        if target_user_id == handle.user_id {
+            for session in list.iter_mut() {
+                session.current = session.device_id == handle.device_id;
+            }
+        }
+
+        return Json(Reply::Ok { value: list, token: handle.token });
+    } else if let Err(e) = res {
+        return e;
+    }
+
+    Json(Reply::Failed)
+}
+
+// Logs out a single device, if it is the one making the request no new token is returned
+#[get("/user/session/revoke")]
+pub async fn revoke_session(data: Db, request: Json<RequestSession>) -> Json<Reply<()>> {
+    let res = handle_auth_request(&data, request.token);
+    if let Ok(handle) = res {
+        if let Err(e) = handle_login_only(&handle) {
+            return e;
+        }
+
+        let target_user_id = if let Some(requested) = request.user_id {
+            if handle.admin {
+                requested
+            } else {
+                return Json(Reply::Denied { token: handle.token });
+            }
+        } else {
+            handle.user_id
+        };
+
+        let target_device_id = if let Some(device_id) = request.device_id {
+            device_id
+        } else {
+            return Json(Reply::MissingParameter { token: handle.token });
+        };
+
+        if !database::revoke_session(&data, target_user_id, target_device_id) {
+            // No active token on that device
+            return Json(Reply::NotFound { token: handle.token });
+        }
+
+        return if target_user_id == handle.user_id && target_device_id == handle.device_id {
+            Json(Reply::Ok { value: (), token: None })
+        } else {
+            Json(Reply::Ok { value: (), token: handle.token })
+        };
+    } else if let Err(e) = res {
+        return e;
+    }
+
+    Json(Reply::Failed)
+}
+
+// Logs out every device of the user, including the one making the request
+#[get("/user/session/revoke_all")]
+pub async fn revoke_all_sessions(data: Db, request: Json<RequestSession>) -> Json<Reply<()>> {
+    let res = handle_auth_request(&data, request.token);
+    if let Ok(handle) = res {
+        if let Err(e) = handle_login_only(&handle) {
+            return e;
+        }
+
+        let target_user_id = if let Some(requested) = request.user_id {
+            if handle.admin {
+                requested
+            } else {
+                return Json(Reply::Denied { token: handle.token });
+            }
+        } else {
+            handle.user_id
+        };
+
+        if let None = database::get_user(&data, target_user_id) {
+            return Json(Reply::NotFound { token: handle.token });
+        }
+
+        if database::revoke_all_sessions(&data, target_user_id) {
+            return if target_user_id == handle.user_id {
+                Json(Reply::Ok { value: (), token: None })
+            } else {
+                Json(Reply::Ok { value: (), token: handle.token })
+            };
+        } else {
+            return Json(Reply::Error { token: handle.token });
+        }
+    } else if let Err(e) = res {
+        return e;
+    }
+
+    Json(Reply::Failed)
+}
+
+#[get("/device/key/create")]
+pub async fn create_api_key(data: Db, request: Json<RequestApiKey>) -> Json<Reply<NewApiKey>> {
+    let res = handle_auth_request(&data, request.token);
+    if let Ok(handle) = res {
+        if let Err(e) = handle_login_only(&handle) {
+            return e;
+        }
+
+        let target_user_id = if let Some(requested) = request.user_id {
+            if handle.admin {
+                requested
+            } else {
+                return Json(Reply::Denied { token: handle.token });
+            }
+        } else {
+            handle.user_id
+        };
+
+        let device_id = if let Some(device_id) = request.device_id {
+            device_id
+        } else {
+            return Json(Reply::MissingParameter { token: handle.token });
+        };
+
+        let max_access = request.max_access.clone().unwrap_or(AccessType::ReadWrite);
+        if let AccessType::No = max_access {
+            return Json(Reply::Error { token: handle.token });
+        }
+
+        if let Some(repos) = &request.repos {
+            for repo_name in repos {
+                if let None = database::get_repo(&data, repo_name.clone()) {
+                    return Json(Reply::NotFound { token: handle.token });
+                }
+            }
+        }
+
+        // Fails if the device does not exist
+        let res = database::create_api_key(&data, target_user_id, device_id, request.name.clone(), request.repos.clone(), max_access);
+        if let Some((key, info)) = res {
+            return Json(Reply::Ok { value: NewApiKey { key, info }, token: handle.token });
+        } else {
+            return Json(Reply::NotFound { token: handle.token });
+        }
+    } else if let Err(e) = res {
+        return e;
+    }
+
+    Json(Reply::Failed)
+}
+
+// The keys of all devices, unless a device is given
+#[get("/device/key/list")]
+pub async fn list_api_keys(data: Db, request: Json<RequestApiKey>) -> Json<Reply<Vec<ApiKey>>> {
+    let res = handle_auth_request(&data, request.token);
+    if let Ok(handle) = res {
+        if let Err(e) = handle_login_only(&handle) {
+            return e;
+        }
+
+        let target_user_id = if let Some(requested) = request.user_id {
+            if handle.admin {
+                requested
+            } else {
+                return Json(Reply::Denied { token: handle.token });
+            }
+        } else {
+            handle.user_id
+        };
+
+        return Json(Reply::Ok { value: database::get_api_keys(&data, target_user_id, request.device_id), token: handle.token });
+    } else if let Err(e) = res {
+        return e;
+    }
+
+    Json(Reply::Failed)
+}
+
+#[get("/device/key/revoke")]
+pub async fn revoke_api_key(data: Db, request: Json<RequestApiKey>) -> Json<Reply<()>> {
+    let res = handle_auth_request(&data, request.token);
+    if let Ok(handle) = res {
+        if let Err(e) = handle_login_only(&handle) {
+            return e;
+        }
+
+        let target_user_id = if let Some(requested) = request.user_id {
+            if handle.admin {
+                requested
+            } else {
+                return Json(Reply::Denied { token: handle.token });
+            }
+        } else {
+            handle.user_id
+        };
+
+        let key_id = if let Some(key_id) = request.key_id {
+            key_id
+        } else {
+            return Json(Reply::MissingParameter { token: handle.token });
+        };
+
+        if database::revoke_api_key(&data, target_user_id, key_id) {
+            return Json(Reply::Ok { value: (), token: handle.token });
+        } else {
+            return Json(Reply::NotFound { token: handle.token });
+        }
+    } else if let Err(e) = res {
+        return e;
+    }
+
+    Json(Reply::Failed)
+}
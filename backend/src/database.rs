@@ -1,19 +1,119 @@
-use std::{path::{Path, PathBuf}, usize};
+use std::{path::{Path, PathBuf}, usize, time::Duration};
 
-use common::{U256, LargeU, data::{RequestUser, Device, TokenCarrier, User, AccessType, Repository, RequestRepository, Folder}};
-use rusqlite::{Connection, params};
+use common::{U256, LargeU, data::{RequestUser, Device, TokenCarrier, User, AccessType, Repository, RequestRepository, Folder, LockoutSource, LoginLockout, Session, ApiKey}};
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier, password_hash::{SaltString, rand_core::OsRng}};
+use rusqlite::{Connection, params, params_from_iter};
+use sha3::{Digest, Sha3_256};
 use uuid::Uuid;
 
 use crate::file_processing;
 
-const SCHEMA_VERSION:usize = 0;
+// Every step brings the schema from the version of it's index to the next one
+// Released steps must never change, add a new one instead
+const MIGRATIONS: [&str; 6] = [
+    // 1: per repository settings, key value pairs just like keyvalues
+    "CREATE TABLE IF NOT EXISTS repo_settings(
+        repo_name TEXT NOT NULL,
+        key TEXT NOT NULL,
+        value TEXT,
+
+        PRIMARY KEY (repo_name, key)
+    );",
+    // 2: quotas per user in bytes (users without entry have none), and who created a temp folder, so uploads count towards their quota
+    "CREATE TABLE IF NOT EXISTS user_quota(
+        user_id INTEGER PRIMARY KEY,
+        quota INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS temp_folder_owner(
+        folder_token BLOB PRIMARY KEY,
+        user_id INTEGER NOT NULL
+    );",
+    // 3: salted argon2id hash of the password (PHC string), old rows get it on their next login
+    "ALTER TABLE users ADD COLUMN password_hash TEXT;",
+    // 4: failed log ins per user name and per address, for the backoff
+    "CREATE TABLE IF NOT EXISTS login_attempts(
+        source CHAR(1) NOT NULL,
+        subject TEXT NOT NULL,
+        failures INTEGER NOT NULL,
+        last_failure INTEGER NOT NULL,
+        locked_until INTEGER NOT NULL DEFAULT 0,
+
+        PRIMARY KEY (source, subject)
+    );",
+    // 5: when a token was last used to authenticate, to show active sessions
+    "ALTER TABLE tokens ADD COLUMN last_use INTEGER;",
+    // 6: api keys of devices, stored hashed, optionally limited to the repos in api_key_repos
+    "CREATE TABLE IF NOT EXISTS api_keys(
+        key_id INTEGER PRIMARY KEY,
+        key_hash BLOB NOT NULL UNIQUE,
+        user_id INTEGER NOT NULL,
+        device_id UNSIGNED TINYINT NOT NULL,
+        name TEXT,
+        max_access TEXT NOT NULL,
+        all_repos BOOL NOT NULL,
+        creation_time INTEGER DEFAULT (strftime('%s','now')),
+        last_use INTEGER,
+
+        FOREIGN KEY (user_id, device_id) REFERENCES devices(user_id, device_id)
+    );
+    CREATE TABLE IF NOT EXISTS api_key_repos(
+        key_id INTEGER NOT NULL,
+        repo_name TEXT NOT NULL,
+
+        PRIMARY KEY (key_id, repo_name),
+        FOREIGN KEY (key_id) REFERENCES api_keys(key_id)
+    );"
+];
+const SCHEMA_VERSION:usize = MIGRATIONS.len();
+
+const POOL_SIZE:u32 = 16;
+const BUSY_TIMEOUT:u64 = 5000; // ms a connection waits for the write lock
 
 const KEY_VERSION:&str = "version";
 const KEY_EXPIRE_TIME:&str = "expire_time";
 const KEY_REPLACEMENT_TIME:&str = "replacement_time";
 
+const KEY_REPO_QUOTA:&str = "quota"; // in repo_settings
 
-pub fn init_sql() -> Connection {
+// Failed log ins before the backoff starts, addresses get more as several users might share one
+const FREE_USER_ATTEMPTS:u32 = 5;
+const FREE_ADDRESS_ATTEMPTS:u32 = 20;
+const LOGIN_BACKOFF:i64 = 2; // seconds, doubled with every further failure
+const MAX_LOGIN_LOCKOUT:i64 = 60 * 60;
+const LOGIN_ATTEMPT_RESET:i64 = 24 * 60 * 60; // failures older than this are forgotten
+const LAST_USE_INTERVAL:i64 = 60; // seconds, last_use is only written again once it is older, not on every request
+
+
+pub type DbPool = r2d2::Pool<SqliteManager>;
+
+// Opens the connections of the pool, all of them on the same file in WAL mode, so readers don't block the writer
+pub struct SqliteManager {
+    path: String
+}
+
+impl r2d2::ManageConnection for SqliteManager {
+    type Connection = Connection;
+    type Error = rusqlite::Error;
+
+    fn connect(&self) -> Result<Connection, rusqlite::Error> {
+        let connection = Connection::open(&self.path)?;
+        let _mode: String = connection.query_row("PRAGMA journal_mode=WAL", params![], |row| row.get(0))?;
+        connection.busy_timeout(Duration::from_millis(BUSY_TIMEOUT))?;
+
+        Ok(connection)
+    }
+
+    fn is_valid(&self, conn: &mut Connection) -> Result<(), rusqlite::Error> {
+        conn.execute_batch("")
+    }
+
+    fn has_broken(&self, _conn: &mut Connection) -> bool {
+        false
+    }
+}
+
+// Sets up the database once, before handing out connections to the workers
+pub fn init_pool() -> DbPool {
     let mut path = std::env::var("DB_PATH").unwrap_or("./target/db/dat.db".to_string()); // TODO handle release, although this technically works as a default there too
     
     // We need to insure the folder exists
@@ -37,13 +137,30 @@ pub fn init_sql() -> Connection {
         panic!("Unable to create database folder at {}\nError: {}",folder.to_str().unwrap_or("*this is very broken, send help*"), e.to_string());
     }
 
+    open_pool(path)
+}
+
+// Opens (or creates) the database file, brings the schema up to date and builds the pool on top
+pub fn open_pool(path: String) -> DbPool {
+    let manager = SqliteManager { path: path.clone() };
+
     // Opening the DB
-    let connection = match Connection::open(&path) {
+    let connection = match r2d2::ManageConnection::connect(&manager) {
         Ok(conn) => conn,
         Err(e) => panic!("Unable to load DB at {}:\nError{}",path, e.to_string())
     };
-    
-    // Generating the Schema
+    init_schema(&connection, Some(Path::new(&path)));
+    drop(connection);
+
+    match r2d2::Pool::builder().max_size(POOL_SIZE).build(manager) {
+        Ok(pool) => pool,
+        Err(e) => panic!("Unable to open the connection pool for {}:\nError{}", path, e.to_string())
+    }
+}
+
+// Generates the schema, or brings an existing one up to date
+// db_file is backed up before migrating, pass None for databases that are not on disk
+fn init_schema(connection: &Connection, db_file: Option<&Path>) {
     fn error_handle<T>(res: Result<T,rusqlite::Error>) {
         if let Err(e) = res {
             panic!("Unable to set up database: {}", e.to_string());
@@ -63,7 +180,7 @@ pub fn init_sql() -> Connection {
     if let Some(val) = res {
         if let Ok(version) = val.parse() {
             if version != SCHEMA_VERSION {
-                migrate_db(&connection, version);
+                migrate_db(&connection, version, db_file);
             }
             // else everything fine
         } else {
@@ -71,12 +188,21 @@ pub fn init_sql() -> Connection {
             panic!("Database could not be loaded, version number is corrupted and reads: {}", val);
         }
     } else {
-        set_key_value(&connection, KEY_VERSION.to_string(), SCHEMA_VERSION.to_string());
+        // Database is new, we generate the original schema and run all migrations on it
+        let res = create_base_schema(connection);
+        error_handle(res);
+
+        set_key_value(&connection, KEY_VERSION.to_string(), 0.to_string());
         set_key_value(&connection, KEY_EXPIRE_TIME.to_string(), (7 * 24 * 60 * 60).to_string()); // 7 days
         set_key_value(&connection, KEY_REPLACEMENT_TIME.to_string(), (2 * 60 * 60).to_string()); // 2 h
 
-        // Database is new, we generate the whole schema
-        let res = connection.execute_batch(format!(
+        migrate_db(&connection, 0, None); // nothing to back up
+    }
+}
+
+// The schema of version 0, changes to it go into MIGRATIONS
+fn create_base_schema(connection: &Connection) -> Result<(), rusqlite::Error> {
+    connection.execute_batch(
             "CREATE TABLE users(
                 user_id INTEGER PRIMARY KEY,
                 user_name TINYTEXT NOT NULL UNIQUE,
@@ -128,47 +254,53 @@ pub fn init_sql() -> Connection {
                 FOREIGN KEY (parent_token) REFERENCES temp_folder(folder_token),
                 FOREIGN KEY (sub_token) REFERENCES temp_folder(folder_token)
             );
-                ").as_str()
-        );
-
-        error_handle(res);
-    }
-    
-    
-    
-
-    connection
-}
-
-// This isn't great, but better then nothing
-pub fn sanetize_string(input: &String) -> String {
-    input.replace(|c: char| c == '\'' || c == '\"', "")
-    //input.clone()
+                "
+    )
 }
 
 pub fn set_key_value(conn: &Connection, key: String, value: String) {
     //SET TRANSACTION ISOLATION LEVEL SERIALIZABLE
-    let _res = conn.execute(format!("
-            INSERT OR REPLACE INTO keyvalues (key, value) VALUES ('{0}','{1}');
-            ", sanetize_string(&key), sanetize_string(&value)).as_str(), params![]);
+    let _res = conn.execute("INSERT OR REPLACE INTO keyvalues (key, value) VALUES (?1, ?2)", params![key, value]);
 
     let _res = conn.cache_flush();
 }
 
 pub fn get_key_value(conn: &Connection, key: String) -> Option<String> {
-    let res: Result<String, rusqlite::Error> = conn.query_row(format!("SELECT value FROM keyvalues WHERE key='{}'",sanetize_string(&key)).as_str(), params![], |row| row.get(0));
+    let res: Result<String, rusqlite::Error> = conn.query_row("SELECT value FROM keyvalues WHERE key=?1", params![key], |row| row.get(0));
     if let Ok(val) = res {
         return Some(val)
     }
     None
 }
 
+// Returns (expire time, replacement time)
+pub fn get_token_times(conn: &Connection) -> Option<(u64, u64)> {
+    let expire = get_key_value(conn, KEY_EXPIRE_TIME.to_string())?.parse().ok()?;
+    let replace = get_key_value(conn, KEY_REPLACEMENT_TIME.to_string())?.parse().ok()?;
+    Some((expire, replace))
+}
+
+// Tokens have to be replaced before they expire, otherwise everyone gets logged out regularly
+pub fn check_token_times(expire: u64, replace: u64) -> bool {
+    replace != 0 && replace < expire && i64::try_from(expire).is_ok()
+}
+
+pub fn set_token_times(conn: &Connection, expire: u64, replace: u64) -> bool {
+    if !check_token_times(expire, replace) {
+        return false;
+    }
+
+    set_key_value(conn, KEY_EXPIRE_TIME.to_string(), expire.to_string());
+    set_key_value(conn, KEY_REPLACEMENT_TIME.to_string(), replace.to_string());
+    get_token_times(conn) == Some((expire, replace))
+}
+
 fn delete_token(conn: &Connection, token: TokenCarrier) -> Result<usize, rusqlite::Error> {
-    conn.execute(format!("DELETE FROM tokens WHERE token=x'{}'", token.token_as_hex_string()).as_str(), params![])
+    conn.execute("DELETE FROM tokens WHERE token=?1", params![token.token])
 }
 
 pub fn authenticate(conn: &Connection, input_carrier:&TokenCarrier) -> Option<TokenCarrier> {
-    let res:Result<(TokenCarrier,u32,i64), rusqlite::Error> = conn.query_row(format!("SELECT token, device_id, user_id, creation_time FROM tokens WHERE token=x'{}'", input_carrier.token_as_hex_string()).as_str(), params![],
+    let res:Result<(TokenCarrier,u32,i64), rusqlite::Error> = conn.query_row("SELECT token, device_id, user_id, creation_time FROM tokens WHERE token=?1", params![input_carrier.token],
              |row| Ok((TokenCarrier::new(row.get(0)?, row.get(1)?),row.get(2)?, row.get(3)?)));
 
     if let Ok((car, user_id, creation_timestamp)) = res {
@@ -222,22 +354,80 @@ fn token_replacement_check(conn: &Connection, token: TokenCarrier, user_id: u32,
                         return None
                     }
             
-                    return Some(TokenCarrier { token: create_token(conn, user_id, device_id), device_id: Some(device_id) });
+                    let new_token = create_token(conn, user_id, device_id);
+                    touch_token(conn, new_token, curr);
+                    return Some(TokenCarrier { token: new_token, device_id: Some(device_id) });
                 }
             }
         }
     }
     
+    touch_token(conn, token.token, curr);
     Some(token)
 }
 
+fn touch_token(conn: &Connection, token: Uuid, time: i64) {
+    let _res = conn.execute("UPDATE tokens SET last_use=?1 WHERE token=?2 AND (last_use IS NULL OR last_use<=?1-?3)", params![time, token, LAST_USE_INTERVAL]);
+}
+
+// Tokens that have not expired yet, oldest device first
+pub fn get_sessions(conn: &Connection, user_id: u32) -> Vec<Session> {
+    let mut data = Vec::<Session>::new();
+
+    let expire: i64 = get_key_value(conn, KEY_EXPIRE_TIME.to_string()).and_then(|e| e.parse().ok()).unwrap_or(i64::MAX);
+    let oldest = chrono::Utc::now().timestamp().saturating_sub(expire);
+
+    let mut stmt = conn.prepare(
+        "SELECT tokens.device_id, device_name, creation_time, last_use FROM tokens INNER JOIN devices ON tokens.user_id=devices.user_id AND tokens.device_id=devices.device_id
+        WHERE tokens.user_id=?1 AND creation_time>=?2 ORDER BY tokens.device_id").unwrap();
+    let iter = stmt.query_map(params![user_id, oldest], |row| {
+        Ok(Session { device_id: row.get(0)?, device_name: row.get(1)?, creation_time: row.get(2)?, last_use: row.get(3)?, current: false })
+    });
+
+    if let Ok(iter) = iter {
+        for item in iter {
+            if let Ok(session) = item {
+                data.push(session);
+            }
+        }
+    }
+
+    data
+}
+
+// Logs the device out, returns false if it had no token
+pub fn revoke_session(conn: &Connection, user_id: u32, device_id: u8) -> bool {
+    let res: Result<Uuid, rusqlite::Error> = conn.query_row("SELECT token FROM tokens WHERE user_id=?1 AND device_id=?2", params![user_id, device_id], |row| row.get(0));
+    if let Ok(token) = res {
+        if let Ok(count) = delete_token(conn, TokenCarrier::new(token, device_id)) {
+            return count > 0;
+        }
+    }
+
+    false
+}
+
+pub fn revoke_all_sessions(conn: &Connection, user_id: u32) -> bool {
+    let res = conn.execute("DELETE FROM tokens WHERE user_id=?1", params![user_id]);
+    res.is_ok()
+}
+
 pub fn login(conn: &Connection, name: String, password: U256, device_id: u8) -> Option<TokenCarrier> {
-    let res:Result<(u32, [u8;32]), rusqlite::Error> = conn.query_row(format!("SELECT user_id, password FROM users WHERE user_name='{}'", sanetize_string(&name)).as_str(), params![], |row| Ok((row.get(0)?, row.get(1)?)));
+    let res:Result<(u32, [u8;32], Option<String>), rusqlite::Error> = conn.query_row("SELECT user_id, password, password_hash FROM users WHERE user_name=?1", params![name], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)));
 
 
-    if let Ok((user_id, pw_bytes)) = res {
-        let pw_hash = U256::from_u8arr(&pw_bytes);
-        if password == pw_hash {
+    if let Ok((user_id, pw_bytes, pw_hash)) = res {
+        let authenticated = if let Some(pw_hash) = pw_hash {
+            verify_password(&password, &pw_hash)
+        } else if password == U256::from_u8arr(&pw_bytes) {
+            // Still stored the old way, now that we know it's right we can hash it
+            set_password(conn, user_id, &password);
+            true
+        } else {
+            false
+        };
+
+        if authenticated {
             // Authenticated
             if let None = get_device(conn, user_id, device_id) {
                 // Falling back to default
@@ -256,6 +446,104 @@ pub fn login(conn: &Connection, name: String, password: U256, device_id: u8) ->
     None
 }
 
+// Seconds until the next attempt is allowed, 0 if there is no lockout
+pub fn get_login_lockout(conn: &Connection, source: &LockoutSource, subject: &String, now: i64) -> u64 {
+    let res: Result<i64, rusqlite::Error> = conn.query_row("SELECT locked_until FROM login_attempts WHERE source=?1 AND subject=?2", params![source.cast(), subject], |row| row.get(0));
+    if let Ok(locked_until) = res {
+        return (locked_until - now).max(0).try_into().unwrap_or_default();
+    }
+
+    0
+}
+
+// Counted before the password is checked, so parallel attempts can not all get in before the lockout
+// Returns until when the subject is locked after this attempt, None if it was locked already, those attempts don't count
+pub fn record_login_attempt(conn: &Connection, source: &LockoutSource, subject: &String, now: i64) -> Option<i64> {
+    let free = match source {
+        LockoutSource::User => FREE_USER_ATTEMPTS,
+        LockoutSource::Address => FREE_ADDRESS_ATTEMPTS
+    };
+
+    // The update sees the old row everywhere, so the new count is repeated for the lockout
+    // the shift is capped, the lockout reaches the max long before anyway
+    let failures = "CASE WHEN ?3 - login_attempts.last_failure > ?4 THEN 1 ELSE login_attempts.failures + 1 END";
+    let lockout = |failures: &str| format!("CASE WHEN {0} > ?5 THEN ?3 + MIN(?6 << MIN({0} - ?5 - 1, 32), ?7) ELSE 0 END", failures);
+    let sql = format!("INSERT INTO login_attempts (source, subject, failures, last_failure, locked_until) VALUES (?1,?2,1,?3,{})
+        ON CONFLICT (source, subject) DO UPDATE SET failures={}, last_failure=?3, locked_until={} WHERE login_attempts.locked_until<=?3
+        RETURNING locked_until", lockout("1"), failures, lockout(failures));
+
+    let res: Result<i64, rusqlite::Error> = conn.query_row(&sql, params![source.cast(), subject, now, LOGIN_ATTEMPT_RESET, free, LOGIN_BACKOFF, MAX_LOGIN_LOCKOUT], |row| row.get(0));
+    res.ok()
+}
+
+// Takes back an attempt that was fine after all, locked_until is what record_login_attempt returned for it
+// If another attempt changed the lockout since, that one stays
+pub fn forgive_login_attempt(conn: &Connection, source: &LockoutSource, subject: &String, locked_until: i64) -> bool {
+    let res = conn.execute("UPDATE login_attempts SET failures=MAX(failures - 1, 0), locked_until=CASE WHEN locked_until=?3 THEN 0 ELSE locked_until END WHERE source=?1 AND subject=?2",
+        params![source.cast(), subject, locked_until]);
+    if res.is_err() {
+        return false;
+    }
+
+    let res = conn.execute("DELETE FROM login_attempts WHERE source=?1 AND subject=?2 AND failures=0", params![source.cast(), subject]);
+    res.is_ok()
+}
+
+pub fn clear_login_failures(conn: &Connection, source: &LockoutSource, subject: &String) -> bool {
+    let res = conn.execute("DELETE FROM login_attempts WHERE source=?1 AND subject=?2", params![source.cast(), subject]);
+    res.is_ok()
+}
+
+pub fn clear_all_login_failures(conn: &Connection) -> bool {
+    let res = conn.execute("DELETE FROM login_attempts", params![]);
+    res.is_ok()
+}
+
+pub fn get_login_lockouts(conn: &Connection) -> Vec<LoginLockout> {
+    let mut data = Vec::<LoginLockout>::new();
+
+    let mut stmt = conn.prepare("SELECT source, subject, failures, last_failure, locked_until FROM login_attempts ORDER BY locked_until DESC").unwrap();
+    let iter = stmt.query_map(params![], |row| {
+        let source: String = row.get(0)?;
+        Ok(LoginLockout { source: LockoutSource::from_str(source), subject: row.get(1)?, failures: row.get(2)?, last_failure: row.get(3)?, locked_until: row.get(4)? })
+    });
+
+    if let Ok(iter) = iter {
+        for item in iter {
+            if let Ok(lockout) = item {
+                data.push(lockout);
+            }
+        }
+    }
+
+    data
+}
+
+// The client already hashes the password, but whatever it sends is what we have to protect
+fn hash_password(password: &U256) -> Option<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default().hash_password(&password.to_be_bytes(), &salt).ok()?;
+    Some(hash.to_string())
+}
+
+fn verify_password(password: &U256, pw_hash: &String) -> bool {
+    if let Ok(parsed) = PasswordHash::new(pw_hash) {
+        return Argon2::default().verify_password(&password.to_be_bytes(), &parsed).is_ok();
+    }
+
+    false
+}
+
+// The old password column is zeroed, so it can't be used to log in anymore
+fn set_password(conn: &Connection, user_id: u32, password: &U256) -> bool {
+    if let Some(pw_hash) = hash_password(password) {
+        let res = conn.execute("UPDATE users SET password=zeroblob(32), password_hash=?1 WHERE user_id=?2", params![pw_hash, user_id]);
+        return res.is_ok();
+    }
+
+    false
+}
+
 // If the device does not exist we get a /*Stack overflow*/ panic, to prevent a Stack overflow
 fn create_token(conn: &Connection, user_id: u32, device_id: u8) -> Uuid {
     let token = Uuid::new_v4();
@@ -268,7 +556,7 @@ fn create_token(conn: &Connection, user_id: u32, device_id: u8) -> Uuid {
         }
 
         // There ought to be only one Token per user and device
-        let _res = conn.execute(format!("DELETE FROM tokens WHERE user_id='{}' AND device_id='{}' AND NOT token=x'{}'", user_id, device_id, TokenCarrier::new_token(token).token_as_hex_string()).as_str(), params![]);
+        let _res = conn.execute("DELETE FROM tokens WHERE user_id=?1 AND device_id=?2 AND NOT token=?3", params![user_id, device_id, token]);
 
         return token;
     } else if let Err(_e) = res {
@@ -283,9 +571,9 @@ fn create_token(conn: &Connection, user_id: u32, device_id: u8) -> Uuid {
 }
 
 pub fn get_auth_handle_from_token(conn: &Connection, token: Uuid) -> Option<AuthHandle> {
-    let res: Result<(u32, u8, i64, bool), rusqlite::Error> = conn.query_row(format!(
-        "SELECT users.user_id, device_id, creation_time, admin FROM (SELECT * FROM tokens WHERE token=x'{}') as tok INNER JOIN users ON tok.user_id=users.user_id",
-        TokenCarrier::new_token(token).token_as_hex_string()).as_str(), params![], 
+    let res: Result<(u32, u8, i64, bool), rusqlite::Error> = conn.query_row(
+        "SELECT users.user_id, device_id, creation_time, admin FROM (SELECT * FROM tokens WHERE token=?1) as tok INNER JOIN users ON tok.user_id=users.user_id",
+        params![token], 
         |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)));
 
     if let Ok((user_id, device_id, creation_timestamp, admin)) = res {
@@ -295,10 +583,10 @@ pub fn get_auth_handle_from_token(conn: &Connection, token: Uuid) -> Option<Auth
         if let Some(new_token) = res {
             if token == new_token {
                 // Meaning this is a valid token, don't need to return it
-                return Some(AuthHandle{ user_id, device_id, token: None, admin });
+                return Some(AuthHandle{ user_id, device_id, token: None, admin, api_key: None });
             } else {
                 // Token was updated
-                return Some(AuthHandle{ user_id, device_id, token: Some(new_token), admin });
+                return Some(AuthHandle{ user_id, device_id, token: Some(new_token), admin, api_key: None });
             }
         } else {
             // Reauth failed, meaning we deny access
@@ -309,6 +597,110 @@ pub fn get_auth_handle_from_token(conn: &Connection, token: Uuid) -> Option<Auth
     None
 }
 
+// Keys are random like tokens, so unlike passwords a fast hash is enough
+fn hash_api_key(key: Uuid) -> Vec<u8> {
+    Sha3_256::digest(key.as_bytes()).to_vec()
+}
+
+// Keys are never replaced and don't expire, they stay until revoked or the device is deleted
+// Admins don't keep their rights when using one
+pub fn get_auth_handle_from_api_key(conn: &Connection, key: Uuid) -> Option<AuthHandle> {
+    let res: Result<(u32, u32, u8, String, bool), rusqlite::Error> = conn.query_row(
+        "SELECT key_id, user_id, device_id, max_access, all_repos FROM api_keys WHERE key_hash=?1",
+        params![hash_api_key(key)],
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?)));
+
+    if let Ok((key_id, user_id, device_id, max_access, all_repos)) = res {
+        let _res = conn.execute("UPDATE api_keys SET last_use=?1 WHERE key_id=?2 AND (last_use IS NULL OR last_use<=?1-?3)", params![chrono::Utc::now().timestamp(), key_id, LAST_USE_INTERVAL]);
+
+        let repos = if all_repos { None } else { Some(get_api_key_repos(conn, key_id)) };
+        let scope = ApiKeyScope { repos, max_access: AccessType::from_str(max_access) };
+        return Some(AuthHandle { user_id, device_id, token: None, admin: false, api_key: Some(scope) });
+    }
+
+    None
+}
+
+fn get_api_key_repos(conn: &Connection, key_id: u32) -> Vec<String> {
+    let mut data = Vec::<String>::new();
+
+    let mut stmt = conn.prepare("SELECT repo_name FROM api_key_repos WHERE key_id=?1 ORDER BY repo_name").unwrap();
+    let iter = stmt.query_map(params![key_id], |row| row.get(0));
+    if let Ok(iter) = iter {
+        for item in iter {
+            if let Ok(name) = item {
+                data.push(name);
+            }
+        }
+    }
+
+    data
+}
+
+pub fn create_api_key(conn: &Connection, user_id: u32, device_id: u8, name: Option<String>, repos: Option<Vec<String>>, max_access: AccessType) -> Option<(Uuid, ApiKey)> {
+    get_device(conn, user_id, device_id)?;
+
+    let key = Uuid::new_v4();
+    let transaction = conn.unchecked_transaction().ok()?;
+    transaction.execute("INSERT INTO api_keys (key_hash, user_id, device_id, name, max_access, all_repos) VALUES (?1,?2,?3,?4,?5,?6)",
+        params![hash_api_key(key), user_id, device_id, name, max_access.cast(), repos.is_none()]).ok()?;
+    let key_id: u32 = transaction.last_insert_rowid().try_into().ok()?;
+
+    if let Some(repos) = &repos {
+        for repo_name in repos {
+            transaction.execute("INSERT OR IGNORE INTO api_key_repos (key_id, repo_name) VALUES (?1,?2)", params![key_id, repo_name]).ok()?;
+        }
+    }
+    transaction.commit().ok()?;
+
+    let info = get_api_keys(conn, user_id, Some(device_id)).into_iter().find(|k| k.key_id == key_id)?;
+    Some((key, info))
+}
+
+pub fn get_api_keys(conn: &Connection, user_id: u32, device_id: Option<u8>) -> Vec<ApiKey> {
+    let mut list = Vec::<(ApiKey, bool)>::new();
+
+    let mut stmt = conn.prepare(
+        "SELECT key_id, device_id, name, max_access, all_repos, creation_time, last_use FROM api_keys
+        WHERE user_id=?1 AND (?2 IS NULL OR device_id=?2) ORDER BY key_id").unwrap();
+    let iter = stmt.query_map(params![user_id, device_id], |row| {
+        let max_access: String = row.get(3)?;
+        Ok((ApiKey { key_id: row.get(0)?, device_id: row.get(1)?, name: row.get(2)?, repos: None, max_access: AccessType::from_str(max_access), creation_time: row.get(5)?, last_use: row.get(6)? }, row.get(4)?))
+    });
+
+    if let Ok(iter) = iter {
+        for item in iter {
+            if let Ok(key) = item {
+                list.push(key);
+            }
+        }
+    }
+
+    list.into_iter().map(|(mut key, all_repos)| {
+        if !all_repos {
+            key.repos = Some(get_api_key_repos(conn, key.key_id));
+        }
+        key
+    }).collect()
+}
+
+// Returns false if the user has no such key
+pub fn revoke_api_key(conn: &Connection, user_id: u32, key_id: u32) -> bool {
+    // The repos reference the key, so they go first
+    let res = conn.execute("DELETE FROM api_key_repos WHERE key_id IN (SELECT key_id FROM api_keys WHERE key_id=?1 AND user_id=?2)", params![key_id, user_id])
+        .and_then(|_c| conn.execute("DELETE FROM api_keys WHERE key_id=?1 AND user_id=?2", params![key_id, user_id]));
+    if let Ok(count) = res {
+        return count > 0;
+    }
+
+    false
+}
+
+fn delete_api_keys(conn: &Connection, user_id: u32, device_id: Option<u8>) -> Result<usize, rusqlite::Error> {
+    conn.execute("DELETE FROM api_key_repos WHERE key_id IN (SELECT key_id FROM api_keys WHERE user_id=?1 AND (?2 IS NULL OR device_id=?2))", params![user_id, device_id])?;
+    conn.execute("DELETE FROM api_keys WHERE user_id=?1 AND (?2 IS NULL OR device_id=?2)", params![user_id, device_id])
+}
+
 pub fn create_device(conn: &Connection, user_id: u32, device_name: String) -> Option<Device> {
     let res = get_user(conn, user_id);
     if let Some(user) = res {
@@ -317,7 +709,7 @@ pub fn create_device(conn: &Connection, user_id: u32, device_name: String) -> Op
             if let None = get_device(conn, user.user_id, i) {
                 // Finally a free ID
                 let res = conn.execute("INSERT INTO devices(user_id, device_id, device_name) VALUES (?1, ?2, ?3)", 
-                        (user_id, i, sanetize_string(&device_name)));
+                        (user_id, i, &device_name));
 
                 if let Ok(_c) = res {
                     return get_device(conn, user_id, i);
@@ -331,7 +723,7 @@ pub fn create_device(conn: &Connection, user_id: u32, device_name: String) -> Op
 }
 
 pub fn get_device(conn: &Connection, user_id: u32, device_id: u8) -> Option<Device> {
-    let res:Result<Device, rusqlite::Error> = conn.query_row(format!("SELECT device_id, device_name FROM devices WHERE user_id='{}' AND device_id='{}'", user_id, device_id).as_str(), params![],
+    let res:Result<Device, rusqlite::Error> = conn.query_row("SELECT device_id, device_name FROM devices WHERE user_id=?1 AND device_id=?2", params![user_id, device_id],
              |row| Ok(Device { device_id: row.get(0)?, device_name: row.get(1)? }));
 
     if let Ok(dev) = res {
@@ -346,11 +738,12 @@ pub fn delete_device(conn: &Connection, user_id: u32, device_id: u8) -> bool{
         return false; // Default device shall never be deleted
     }
 
-    // Removing all tokens attached to the device
-    let res = conn.execute(format!("DELETE FROM tokens WHERE user_id='{}' AND device_id='{}'", user_id, device_id).as_str(), params![]);
+    // Removing all tokens and keys attached to the device
+    let res = conn.execute("DELETE FROM tokens WHERE user_id=?1 AND device_id=?2", params![user_id, device_id])
+        .and_then(|_c| delete_api_keys(conn, user_id, Some(device_id)));
     if let Ok(_s) = res {
         // Deleting the device
-        let res = conn.execute(format!("DELETE FROM devices WHERE user_id='{}' AND device_id='{}'", user_id, device_id).as_str(), params![]);
+        let res = conn.execute("DELETE FROM devices WHERE user_id=?1 AND device_id=?2", params![user_id, device_id]);
         if let Ok(_s) = res {
             return true;
         }
@@ -373,10 +766,16 @@ pub fn create_user(conn: &Connection, name: String, password: U256, admin: bool)
     };
 
 
-    let res = conn.execute("INSERT INTO users (user_name, password, admin) VALUES (?1, ?2, ?3)", (sanetize_string(&name), password.to_be_bytes(), admin));
+    let pw_hash = if let Some(pw_hash) = hash_password(&password) {
+        pw_hash
+    } else {
+        return false;
+    };
+
+    let res = conn.execute("INSERT INTO users (user_name, password, password_hash, admin) VALUES (?1, zeroblob(32), ?2, ?3)", (&name, pw_hash, admin));
 
     if let Ok(_c) = res {
-        let res:Result<u32, rusqlite::Error> = conn.query_row(format!("SELECT user_id FROM users WHERE user_name='{}'", sanetize_string(&name)).as_str(), params![],|row| row.get(0));
+        let res:Result<u32, rusqlite::Error> = conn.query_row("SELECT user_id FROM users WHERE user_name=?1", params![name],|row| row.get(0));
         if let Ok(user_id) = res {
             let res = conn.execute("INSERT INTO devices (user_id, device_id, device_name) VALUES (?1, ?2, ?3)", (user_id, 0, "DEFAULT"));
             return res.is_ok();
@@ -390,8 +789,8 @@ pub fn create_user(conn: &Connection, name: String, password: U256, admin: bool)
 }
 
 pub fn get_user(conn: &Connection, user_id: u32) -> Option<User> {
-    let res:Result<User, rusqlite::Error> = conn.query_row(format!("SELECT user_id, user_name, admin FROM users WHERE user_id='{}'", user_id).as_str(), params![],|row| {
-        Ok(User{user_id: row.get(0)?, user_name: row.get(1)?, admin: row.get(2)?})
+    let res:Result<User, rusqlite::Error> = conn.query_row("SELECT user_id, user_name, admin FROM users WHERE user_id=?1", params![user_id],|row| {
+        Ok(User{user_id: row.get(0)?, user_name: row.get(1)?, admin: row.get(2)?, storage_used: None, storage_quota: None})
     });
 
     if let Ok(user) = res {
@@ -405,7 +804,7 @@ pub fn get_user(conn: &Connection, user_id: u32) -> Option<User> {
 
 pub fn delete_user(conn: &Connection, user_id: u32) -> bool {
     // Check if this is the last admin
-    let res:Result<i64, rusqlite::Error> = conn.query_row(format!("SELECT count(user_id) FROM users WHERE admin=TRUE AND NOT user_id={}",user_id).as_str(), params![], |row| Ok(row.get(0)?));
+    let res:Result<i64, rusqlite::Error> = conn.query_row("SELECT count(user_id) FROM users WHERE admin=TRUE AND NOT user_id=?1", params![user_id], |row| Ok(row.get(0)?));
     if let Ok(count) = res {
         if count == 0 {
             // Can't let you delete the last admin
@@ -414,17 +813,19 @@ pub fn delete_user(conn: &Connection, user_id: u32) -> bool {
     }
 
 
-    // Removing all tokens
-    let res = conn.execute(format!("DELETE FROM tokens WHERE user_id='{}'", user_id).as_str(), params![]);
+    // Removing all tokens and keys
+    let res = conn.execute("DELETE FROM tokens WHERE user_id=?1", params![user_id])
+        .and_then(|_c| delete_api_keys(conn, user_id, None));
     if let Ok(_s) = res {
         // Deleting the devices
-        let res = conn.execute(format!("DELETE FROM devices WHERE user_id='{}'", user_id).as_str(), params![]);
+        let res = conn.execute("DELETE FROM devices WHERE user_id=?1", params![user_id]);
         if let Ok(_s) = res {
             // Delete all the access permission
-            let res = conn.execute(format!("DELETE FROM repo_access WHERE user_id='{}'", user_id).as_str(), params![]);
+            let res = conn.execute("DELETE FROM repo_access WHERE user_id=?1", params![user_id])
+                .and_then(|_c| conn.execute("DELETE FROM user_quota WHERE user_id=?1", params![user_id]));
             if let Ok(_s) = res {
                 // Deleting the user finally
-                let res = conn.execute(format!("DELETE FROM users WHERE user_id='{}'", user_id).as_str(), params![]);
+                let res = conn.execute("DELETE FROM users WHERE user_id=?1", params![user_id]);
                 if let Ok(_s) = res {
                     return true;
                 }
@@ -459,12 +860,11 @@ pub fn get_all_users(conn: &Connection) -> Vec<RequestUser> {
 }
 
 pub fn create_repo_fast(conn: &Connection, name: String) {
-    create_repo(conn, RequestRepository{ repo_name: Some(name), token: None, display_name: None, game: None});
+    create_repo(conn, RequestRepository{ repo_name: Some(name), token: None, display_name: None, game: None, compression: None});
 }
 
 pub fn create_repo(conn: &Connection, request: RequestRepository) -> Option<Repository> {
     if let Some(name) = request.repo_name {
-        let name = sanetize_string(&name);
         let res = conn.execute("INSERT INTO repository (repo_name, display_name, game) VALUES (?1,?2,?3)", 
             (&name, request.display_name, request.game));
 
@@ -479,11 +879,9 @@ pub fn create_repo(conn: &Connection, request: RequestRepository) -> Option<Repo
 }
 
 pub fn get_repo(conn: &Connection, repo_name: String) -> Option<Repository> {
-    let repo_name = sanetize_string(&repo_name);
-
-    let res:Result<Repository, rusqlite::Error> = conn.query_row(format!(
-        "SELECT repo_name, display_name, game FROM repository WHERE repo_name='{}'", repo_name).as_str(), params![], 
-        |row| Ok(Repository { repo_name: row.get(0)?, display_name: row.get(1)?, game: row.get(2)?, permission: None }));
+    let res:Result<Repository, rusqlite::Error> = conn.query_row(
+        "SELECT repo_name, display_name, game FROM repository WHERE repo_name=?1", params![repo_name], 
+        |row| Ok(Repository { repo_name: row.get(0)?, display_name: row.get(1)?, game: row.get(2)?, permission: None, size: None, quota: None }));
 
     if let Ok(repo) = res {
         return Some(repo);
@@ -493,25 +891,25 @@ pub fn get_repo(conn: &Connection, repo_name: String) -> Option<Repository> {
 }
 
 pub fn list_repos(conn: &Connection, user_id: Option<u32>) -> Vec<Repository> {
-    let res = if let Some(user_id) = user_id {
-        conn.prepare(format!("SELECT repository.repo_name, display_name, game, permission FROM repository JOIN
-            (SELECT * FROM repo_access WHERE user_id={} AND NOT permission='N') as acc ON acc.repo_name = repository.repo_name",user_id).as_str())
+    let (query, values) = if let Some(user_id) = user_id {
+        ("SELECT repository.repo_name, display_name, game, permission FROM repository JOIN
+            (SELECT * FROM repo_access WHERE user_id=?1 AND NOT permission='N') as acc ON acc.repo_name = repository.repo_name", vec![user_id])
     } else {
         // No user_id provided, just querrying all items
-        conn.prepare("SELECT repo_name, display_name, game, 'A' FROM repository")
+        ("SELECT repo_name, display_name, game, 'A' FROM repository", Vec::<u32>::new())
     };
 
     let mut data = Vec::<Repository>::new();
 
-    if let Ok(mut stmt) = res {
-        let repo_iter = stmt.query_map([], |row| {
+    if let Ok(mut stmt) = conn.prepare(query) {
+        let repo_iter = stmt.query_map(params_from_iter(values.iter()), |row| {
             let val:Option<String> = row.get(3)?;
             let acc = if let Some(val) = val {
                 Some(AccessType::from_str(val))
             } else {
                 None
             };
-            Ok(Repository { repo_name: row.get(0)?, display_name: row.get(1)?, game: row.get(2)?, permission: acc })
+            Ok(Repository { repo_name: row.get(0)?, display_name: row.get(1)?, game: row.get(2)?, permission: acc, size: None, quota: None })
         }).unwrap();
         
         for item in repo_iter {
@@ -527,13 +925,13 @@ pub fn list_repos(conn: &Connection, user_id: Option<u32>) -> Vec<Repository> {
 }
 
 pub fn delete_repo(conn: &Connection, repo_name: String) -> bool {
-    let repo_name = sanetize_string(&repo_name);
-
-    // Deleting the access permissions first
-    let res = conn.execute(format!("DELETE FROM repo_access WHERE repo_name='{}'", &repo_name).as_str(), params![]);
+    // Deleting the access permissions and settings first, api keys would otherwise grant a new repo of the same name
+    let res = conn.execute("DELETE FROM repo_access WHERE repo_name=?1", params![&repo_name])
+        .and_then(|_c| conn.execute("DELETE FROM repo_settings WHERE repo_name=?1", params![&repo_name]))
+        .and_then(|_c| conn.execute("DELETE FROM api_key_repos WHERE repo_name=?1", params![&repo_name]));
     if let Ok(_c) = res {
         // Deleting the repo
-        let res = conn.execute(format!("DELETE FROM repository WHERE repo_name='{}'",&repo_name).as_str(), params![]);
+        let res = conn.execute("DELETE FROM repository WHERE repo_name=?1", params![&repo_name]);
         if let Ok(_c) = res {
             return true;
         }
@@ -542,10 +940,86 @@ pub fn delete_repo(conn: &Connection, repo_name: String) -> bool {
     false
 }
 
+pub fn set_repo_setting(conn: &Connection, repo_name: &String, key: &str, value: String) -> bool {
+    let res = conn.execute("INSERT OR REPLACE INTO repo_settings (repo_name, key, value) VALUES (?1,?2,?3)", 
+        params![repo_name, key, value]);
+
+    res.is_ok()
+}
+
+pub fn get_repo_setting(conn: &Connection, repo_name: &String, key: &str) -> Option<String> {
+    let res: Result<String, rusqlite::Error> = conn.query_row("SELECT value FROM repo_settings WHERE repo_name=?1 AND key=?2", 
+        params![repo_name, key], |row| row.get(0));
+    if let Ok(val) = res {
+        return Some(val)
+    }
+    None
+}
+
+pub fn delete_repo_setting(conn: &Connection, repo_name: &String, key: &str) -> bool {
+    let res = conn.execute("DELETE FROM repo_settings WHERE repo_name=?1 AND key=?2", params![repo_name, key]);
+
+    res.is_ok()
+}
+
+pub fn get_repo_quota(conn: &Connection, repo_name: &String) -> Option<u64> {
+    get_repo_setting(conn, repo_name, KEY_REPO_QUOTA).and_then(|val| val.parse().ok())
+}
+
+pub fn set_repo_quota(conn: &Connection, repo_name: &String, quota: Option<u64>) -> bool {
+    if let Some(quota) = quota {
+        return set_repo_setting(conn, repo_name, KEY_REPO_QUOTA, quota.to_string());
+    }
+
+    delete_repo_setting(conn, repo_name, KEY_REPO_QUOTA)
+}
+
+pub fn get_user_quota(conn: &Connection, user_id: u32) -> Option<u64> {
+    let res: Result<i64, rusqlite::Error> = conn.query_row("SELECT quota FROM user_quota WHERE user_id=?1", params![user_id], |row| row.get(0));
+    if let Ok(val) = res {
+        return val.try_into().ok();
+    }
+    None
+}
+
+pub fn set_user_quota(conn: &Connection, user_id: u32, quota: Option<u64>) -> bool {
+    let res = if let Some(quota) = quota {
+        // sqlite only has signed integers
+        let quota: i64 = quota.try_into().unwrap_or(i64::MAX);
+        conn.execute("INSERT OR REPLACE INTO user_quota (user_id, quota) VALUES (?1,?2)", params![user_id, quota])
+    } else {
+        conn.execute("DELETE FROM user_quota WHERE user_id=?1", params![user_id])
+    };
+
+    res.is_ok()
+}
+
+// Repos where the user has the Owner permission
+pub fn get_owned_repos(conn: &Connection, user_id: u32) -> Vec<String> {
+    let mut data = Vec::<String>::new();
+
+    if let Ok(mut stmt) = conn.prepare("SELECT repo_name FROM repo_access WHERE user_id=?1 AND permission='O'") {
+        if let Ok(iter) = stmt.query_map(params![user_id], |row| row.get(0)) {
+            for item in iter {
+                if let Ok(name) = item {
+                    data.push(name);
+                }
+            }
+        }
+    }
+
+    data
+}
+
+pub fn get_repo_owner(conn: &Connection, repo_name: &String) -> Option<u32> {
+    let res: Result<u32, rusqlite::Error> = conn.query_row("SELECT user_id FROM repo_access WHERE repo_name=?1 AND permission='O'", 
+        params![repo_name], |row| row.get(0));
+    res.ok()
+}
+
 pub fn get_user_repo_permission(conn: &Connection, user_id: u32, repo_name: String) -> Option<AccessType> {
-    let repo_name = sanetize_string(&repo_name);
-    let res:Result<AccessType, rusqlite::Error> = conn.query_row(format!(
-        "SELECT permission FROM repo_access WHERE user_id={} AND repo_name='{}'", user_id, repo_name).as_str(), params![], 
+    let res:Result<AccessType, rusqlite::Error> = conn.query_row(
+        "SELECT permission FROM repo_access WHERE user_id=?1 AND repo_name=?2", params![user_id, repo_name], 
         |row| Ok(AccessType::from_str(row.get(0)?)));
     
     if let Ok(acc) = res {
@@ -555,13 +1029,22 @@ pub fn get_user_repo_permission(conn: &Connection, user_id: u32, repo_name: Stri
     None
 }
 
+// The permission of the user, lowered to what the api key allows if one was used
+pub fn get_handle_repo_permission(conn: &Connection, handle: &AuthHandle, repo_name: String) -> Option<AccessType> {
+    let res = get_user_repo_permission(conn, handle.user_id, repo_name.clone());
+    if let Some(scope) = &handle.api_key {
+        return res.map(|acc| scope.limit(&repo_name, acc));
+    }
+
+    res
+}
+
 pub fn set_user_repo_permission(conn: &Connection, user_id: u32, repo_name: String, permission: AccessType) -> bool {
-    let repo_name = sanetize_string(&repo_name);
     if let Some(_user) = get_user(conn, user_id) {
         if let Some(_repo) = get_repo(conn, repo_name.clone()) {
             if permission == AccessType::Owner {
                 // There can only be one owner
-                let res = conn.execute(format!("UPDATE repo_access SET permission='A' WHERE repo_name='{}' AND permission='O'", &repo_name).as_str(), params![]);
+                let res = conn.execute("UPDATE repo_access SET permission='A' WHERE repo_name=?1 AND permission='O'", params![&repo_name]);
                 if let Err(_e) = res {
                     return false;
                 }
@@ -596,6 +1079,15 @@ pub fn create_temp_folder(conn: &Connection, name: Option<String>) -> Folder {
     create_temp_folder(conn, name)
 }
 
+pub fn set_temp_folder_owner(conn: &Connection, folder_token: Uuid, user_id: u32) -> bool {
+    conn.execute("INSERT OR REPLACE INTO temp_folder_owner(folder_token, user_id) VALUES (?1, ?2)", (&folder_token, user_id)).is_ok()
+}
+
+pub fn get_temp_folder_owner(conn: &Connection, folder_token: Uuid) -> Option<u32> {
+    let res: Result<u32, rusqlite::Error> = conn.query_row("SELECT user_id FROM temp_folder_owner WHERE folder_token=?1", params![&folder_token], |row| row.get(0));
+    res.ok()
+}
+
 pub fn link_temp_parent_folder(conn: &Connection, parent: Uuid, sub: Uuid) -> bool {
     let res = conn.execute("INSERT INTO temp_folder_reference(parent_token, sub_token) VALUES (?1, ?2)", (&parent, &sub));
     if let Ok(rows) = res {
@@ -607,9 +1099,14 @@ pub fn link_temp_parent_folder(conn: &Connection, parent: Uuid, sub: Uuid) -> bo
     false
 }
 
+pub fn count_temp_folders(conn: &Connection) -> usize {
+    let res: Result<usize, rusqlite::Error> = conn.query_row("SELECT count(*) FROM temp_folder", params![], |row| row.get(0));
+    res.unwrap_or(usize::MAX) // if we can't tell, we assume they are in use
+}
+
 pub fn get_temp_folder(conn: &Connection, folder_token: Uuid) -> Option<Folder> {
-    let res = conn.query_row(format!(
-        "SELECT folder_token, folder_name FROM temp_folder WHERE folder_token=x'{}'", TokenCarrier::new_token(folder_token).token_as_hex_string()).as_str(), params![], 
+    let res = conn.query_row(
+        "SELECT folder_token, folder_name FROM temp_folder WHERE folder_token=?1", params![folder_token], 
         |row| Ok(Folder {folder_token: row.get(0)?, folder_name: row.get(1)?, content: None}));
 
     if let Ok(folder) = res {
@@ -627,8 +1124,9 @@ pub fn delete_temp_folder(conn: &Connection, folder_token: Uuid) -> bool {
         }
     }
 
-    let res = conn.execute_batch(format!(
-        "DELETE FROM temp_folder_reference WHERE parent_token=x'{0}' OR sub_token=x'{0}'; DELETE FROM temp_folder WHERE folder_token=x'{0}'", TokenCarrier::new_token(folder_token).token_as_hex_string()).as_str());
+    let res = conn.execute("DELETE FROM temp_folder_reference WHERE parent_token=?1 OR sub_token=?1", params![folder_token])
+        .and_then(|_c| conn.execute("DELETE FROM temp_folder_owner WHERE folder_token=?1", params![folder_token]))
+        .and_then(|_c| conn.execute("DELETE FROM temp_folder WHERE folder_token=?1", params![folder_token]));
 
     if let Ok(_) = res {
         return true;
@@ -638,13 +1136,13 @@ pub fn delete_temp_folder(conn: &Connection, folder_token: Uuid) -> bool {
 }
 
 pub fn get_sub_folders(conn: &Connection, folder_token: Uuid) -> Vec<Folder> {
-    let res = conn.prepare(format!("SELECT folder_token, folder_name FROM temp_folder JOIN
-            (SELECT * FROM temp_folder_reference WHERE parent_token=x'{}') as ref ON ref.sub_token = temp_folder.folder_token",TokenCarrier::new_token(folder_token).token_as_hex_string()).as_str());
+    let res = conn.prepare("SELECT folder_token, folder_name FROM temp_folder JOIN
+            (SELECT * FROM temp_folder_reference WHERE parent_token=?1) as ref ON ref.sub_token = temp_folder.folder_token");
 
     let mut data = Vec::<Folder>::new();
 
     if let Ok(mut stmt) = res {
-        let repo_iter = stmt.query_map([], |row| {
+        let repo_iter = stmt.query_map(params![folder_token], |row| {
             Ok(Folder { folder_token: row.get(0)?, folder_name: row.get(1)?, content: None})
         }).unwrap();
         
@@ -711,24 +1209,562 @@ pub fn prune_temp_folders(conn: &Connection, existing_folders: Vec<Uuid>) -> Vec
 }
 
 
-fn migrate_db(_conn: &Connection, curr_version:usize) {
-    // fn error_handle<T>(res: Result<T,rusqlite::Error>) {
-    //     if let Err(e) = res {
-    //         panic!("Unable to set up database: {}", e.to_string());
-    //     }
-    // }
-
+fn migrate_db(conn: &Connection, curr_version:usize, db_file: Option<&Path>) {
     if curr_version > SCHEMA_VERSION {
         panic!("Current Database version newer then SCHEMA, please update the software\nDB: {}; Schema: {}", curr_version, SCHEMA_VERSION);
     }
 
+    if let Some(file) = db_file {
+        if let Err(e) = backup_db(conn, file, curr_version) {
+            panic!("Unable to back up the database before migrating, refusing to continue: {}", e.to_string());
+        }
+    }
+
+    if let Err((version, e)) = apply_migrations(conn, curr_version, &MIGRATIONS) {
+        panic!("Migrating the database to version {} failed, it remains at version {}: {}", version + 1, version, e.to_string());
+    }
+}
+
+// Runs every step after from, each in its own transaction together with the new version number
+// So a failed step leaves the database at the last completed version, returned with the error
+fn apply_migrations(conn: &Connection, from: usize, steps: &[&str]) -> Result<(), (usize, rusqlite::Error)> {
+    for version in from..steps.len() {
+        let res = conn.unchecked_transaction().and_then(|transaction| {
+            transaction.execute_batch(steps[version])?;
+            transaction.execute("INSERT OR REPLACE INTO keyvalues (key, value) VALUES (?1, ?2)", params![KEY_VERSION, (version + 1).to_string()])?;
+            transaction.commit()
+        });
+
+        if let Err(e) = res {
+            return Err((version, e));
+        }
+    }
+
+    Ok(())
+}
+
+// Writes a copy next to the database file, named after the version it had
+fn backup_db(conn: &Connection, db_file: &Path, version: usize) -> Result<PathBuf, rusqlite::Error> {
+    let name = db_file.file_name().and_then(|n| n.to_str()).unwrap_or("dat.db");
+    let backup = db_file.with_file_name(format!("{}.v{}-{}.bak", name, version, chrono::Utc::now().timestamp()));
+
+    conn.execute("VACUUM INTO ?1", params![backup.to_str()])?;
+    Ok(backup)
 }
 
 pub struct AuthHandle {
     pub user_id: u32,
     pub device_id: u8,
     pub token: Option<TokenCarrier>,
-    pub admin: bool
+    pub admin: bool,
+    pub api_key: Option<ApiKeyScope> // None when authenticated with a token
+}
+
+pub struct ApiKeyScope {
+    pub repos: Option<Vec<String>>,
+    pub max_access: AccessType
+}
+
+impl ApiKeyScope {
+    pub fn allows_repo(&self, repo_name: &String) -> bool {
+        if let Some(repos) = &self.repos {
+            return repos.contains(repo_name);
+        }
+
+        true
+    }
+
+    pub fn limit(&self, repo_name: &String, access: AccessType) -> AccessType {
+        if self.allows_repo(repo_name) {
+            access.limit(&self.max_access)
+        } else {
+            AccessType::No
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use common::{U256, LargeU, data::{AccessType, RequestRepository}};
+    use rusqlite::Connection;
+    use uuid::Uuid;
+
+    use super::*;
+
+    // Inputs that broke out of (or were mangled by) the old string formatted queries
+    const HOSTILE: [&str; 8] = [
+        "O'Brien",
+        "\"quoted\"",
+        "'; DROP TABLE users; --",
+        "' OR '1'='1",
+        "x' OR user_id>0 --",
+        "back\\slash %_ wildcards",
+        "ünïcødé ' \" mixed",
+        "'"
+    ];
+
+    fn open() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        init_schema(&conn, None);
+        conn
+    }
+
+    fn password(seed: u8) -> U256 {
+        U256::from_u8arr(&[seed; 32])
+    }
+
+    #[test]
+    fn user_names_round_trip() {
+        let conn = open();
+
+        for (index, name) in HOSTILE.iter().enumerate() {
+            assert!(create_user(&conn, name.to_string(), password(index as u8), false), "failed to create {}", name);
+        }
+        assert_eq!(get_all_users(&conn).len(), HOSTILE.len());
+
+        for (index, name) in HOSTILE.iter().enumerate() {
+            let token = login(&conn, name.to_string(), password(index as u8), 0);
+            assert!(token.is_some(), "failed to log in as {}", name);
+
+            let handle = get_auth_handle_from_token(&conn, token.unwrap().token).unwrap();
+            assert_eq!(get_user(&conn, handle.user_id).unwrap().user_name, name.to_string());
+        }
+
+        // Names that only differ in quotes are different users
+        assert!(create_user(&conn, "OBrien".to_string(), password(100), false));
+        assert!(login(&conn, "OBrien".to_string(), password(0), 0).is_none());
+    }
+
+    #[test]
+    fn login_is_not_injectable() {
+        let conn = open();
+        assert!(create_user(&conn, "admin".to_string(), password(1), true));
+
+        for name in HOSTILE.iter() {
+            assert!(login(&conn, name.to_string(), password(1), 0).is_none(), "logged in as {}", name);
+        }
+        assert!(login(&conn, "admin' --".to_string(), password(2), 0).is_none());
+        assert!(login(&conn, "admin".to_string(), password(1), 0).is_some());
+    }
+
+    #[test]
+    fn device_names_round_trip() {
+        let conn = open();
+        assert!(create_user(&conn, "user".to_string(), password(1), false));
+
+        for name in HOSTILE.iter() {
+            let device = create_device(&conn, 1, name.to_string()).unwrap();
+            assert_eq!(get_device(&conn, 1, device.device_id).unwrap().device_name, name.to_string());
+        }
+    }
+
+    #[test]
+    fn repo_names_round_trip() {
+        let conn = open();
+        assert!(create_user(&conn, "user".to_string(), password(1), false));
+
+        for name in HOSTILE.iter() {
+            let request = RequestRepository { token: None, repo_name: Some(name.to_string()), display_name: Some(name.to_string()), game: Some(name.to_string()), compression: None };
+            let repo = create_repo(&conn, request).unwrap();
+            assert_eq!(repo.repo_name, name.to_string());
+            assert_eq!(repo.display_name, Some(name.to_string()));
+
+            assert!(set_user_repo_permission(&conn, 1, name.to_string(), AccessType::Owner));
+            assert_eq!(get_user_repo_permission(&conn, 1, name.to_string()), Some(AccessType::Owner));
+
+            assert!(set_repo_setting(&conn, &name.to_string(), "key", name.to_string()));
+            assert_eq!(get_repo_setting(&conn, &name.to_string(), "key"), Some(name.to_string()));
+        }
+
+        let mut listed: Vec<String> = list_repos(&conn, Some(1)).into_iter().map(|r| r.repo_name).collect();
+        let mut owned = get_owned_repos(&conn, 1);
+        let mut expected: Vec<String> = HOSTILE.iter().map(|n| n.to_string()).collect();
+        listed.sort();
+        owned.sort();
+        expected.sort();
+        assert_eq!(listed, expected);
+        assert_eq!(owned, expected);
+
+        // Deleting one repo leaves the ones with similar names alone
+        assert!(delete_repo(&conn, "'".to_string()));
+        assert!(get_repo(&conn, "'".to_string()).is_none());
+        assert_eq!(list_repos(&conn, None).len(), HOSTILE.len() - 1);
+        assert_eq!(get_repo_owner(&conn, &"O'Brien".to_string()), Some(1));
+    }
+
+    #[test]
+    fn key_values_round_trip() {
+        let conn = open();
+
+        for name in HOSTILE.iter() {
+            set_key_value(&conn, name.to_string(), name.to_string());
+        }
+        for name in HOSTILE.iter() {
+            assert_eq!(get_key_value(&conn, name.to_string()), Some(name.to_string()));
+        }
+    }
+
+    #[test]
+    fn temp_folders_round_trip() {
+        let conn = open();
+
+        let parent = create_temp_folder(&conn, Some(HOSTILE[2].to_string()));
+        for name in HOSTILE.iter() {
+            let sub = create_temp_folder(&conn, Some(name.to_string()));
+            assert!(link_temp_parent_folder(&conn, parent.folder_token, sub.folder_token));
+            assert_eq!(get_temp_folder(&conn, sub.folder_token).unwrap().folder_name, Some(name.to_string()));
+        }
+        assert_eq!(get_sub_folders(&conn, parent.folder_token).len(), HOSTILE.len());
+
+        assert!(delete_temp_folder(&conn, parent.folder_token));
+        assert!(get_temp_folder(&conn, parent.folder_token).is_none());
+        assert!(get_temp_folder(&conn, Uuid::new_v4()).is_none());
+    }
+
+    // A database as the first release created it, with some data in it
+    fn fixture_v0(conn: &Connection) {
+        conn.execute("CREATE TABLE keyvalues(key TEXT NOT NULL UNIQUE PRIMARY KEY, value TEXT);", params![]).unwrap();
+        create_base_schema(conn).unwrap();
+        conn.execute_batch(
+            "INSERT INTO keyvalues (key, value) VALUES ('version', '0'), ('expire_time', '604800'), ('replacement_time', '7200');
+            INSERT INTO users (user_id, user_name, password, admin) VALUES (1, 'admin', x'0101010101010101010101010101010101010101010101010101010101010101', TRUE);
+            INSERT INTO devices (user_id, device_id, device_name) VALUES (1, 0, 'DEFAULT');
+            INSERT INTO repository (repo_name, display_name, game) VALUES ('saves', 'Saves', 'Game');
+            INSERT INTO repo_access (user_id, repo_name, permission) VALUES (1, 'saves', 'O');"
+        ).unwrap();
+    }
+
+    fn get_version(conn: &Connection) -> String {
+        get_key_value(conn, KEY_VERSION.to_string()).unwrap()
+    }
+
+    fn table_exists(conn: &Connection, table: &str) -> bool {
+        conn.query_row("SELECT count(*) FROM sqlite_master WHERE type='table' AND name=?1", params![table], |row| row.get::<_, i64>(0)).unwrap() == 1
+    }
+
+    fn check_fixture_data(conn: &Connection) {
+        assert_eq!(get_user(conn, 1).unwrap().user_name, "admin");
+        assert_eq!(get_repo(conn, "saves".to_string()).unwrap().display_name, Some("Saves".to_string()));
+        assert_eq!(get_user_repo_permission(conn, 1, "saves".to_string()), Some(AccessType::Owner));
+    }
+
+    #[test]
+    fn each_migration_applies_to_fixture() {
+        let conn = Connection::open_in_memory().unwrap();
+        fixture_v0(&conn);
+
+        for version in 0..SCHEMA_VERSION {
+            assert!(apply_migrations(&conn, version, &MIGRATIONS[..version + 1]).is_ok(), "migration {} failed", version + 1);
+            assert_eq!(get_version(&conn), (version + 1).to_string());
+            check_fixture_data(&conn);
+        }
+
+        assert!(table_exists(&conn, "repo_settings"));
+        assert!(table_exists(&conn, "user_quota"));
+        assert!(table_exists(&conn, "temp_folder_owner"));
+        assert!(login(&conn, "admin".to_string(), password(1), 0).is_some());
+        assert!(set_user_quota(&conn, 1, Some(1024)));
+        assert_eq!(get_user_quota(&conn, 1), Some(1024));
+        assert!(set_repo_quota(&conn, &"saves".to_string(), Some(2048)));
+        assert_eq!(get_repo_quota(&conn, &"saves".to_string()), Some(2048));
+
+        // Running them again does nothing
+        assert!(apply_migrations(&conn, SCHEMA_VERSION, &MIGRATIONS).is_ok());
+        assert_eq!(get_version(&conn), SCHEMA_VERSION.to_string());
+    }
+
+    #[test]
+    fn fresh_database_has_latest_schema() {
+        let conn = open();
+
+        assert_eq!(get_version(&conn), SCHEMA_VERSION.to_string());
+        assert!(table_exists(&conn, "users"));
+        assert!(table_exists(&conn, "repo_settings"));
+        assert!(table_exists(&conn, "user_quota"));
+        assert!(table_exists(&conn, "temp_folder_owner"));
+    }
+
+    #[test]
+    fn init_backs_up_before_migrating() {
+        let mut folder = std::env::temp_dir();
+        folder.push(format!("own_your_saves-db-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&folder).unwrap();
+        let mut file = folder.clone();
+        file.push("dat.db");
+
+        let conn = Connection::open(&file).unwrap();
+        fixture_v0(&conn);
+        init_schema(&conn, Some(file.as_path()));
+        assert_eq!(get_version(&conn), SCHEMA_VERSION.to_string());
+        check_fixture_data(&conn);
+        assert!(login(&conn, "admin".to_string(), password(1), 0).is_some());
+
+        let backups: Vec<PathBuf> = std::fs::read_dir(&folder).unwrap()
+            .filter_map(|e| e.ok().map(|e| e.path()))
+            .filter(|p| p.file_name().unwrap().to_str().unwrap().starts_with("dat.db.v0-"))
+            .collect();
+        assert_eq!(backups.len(), 1);
+
+        // The backup is the database as it was
+        let backup = Connection::open(&backups[0]).unwrap();
+        assert_eq!(get_version(&backup), "0");
+        assert!(!table_exists(&backup, "user_quota"));
+        check_fixture_data(&backup);
+
+        // Up to date databases are not backed up again
+        init_schema(&conn, Some(file.as_path()));
+        assert_eq!(std::fs::read_dir(&folder).unwrap().count(), 2);
+
+        drop(backup);
+        drop(conn);
+        let _ = std::fs::remove_dir_all(&folder);
+    }
+
+    #[test]
+    fn failed_migration_rolls_back() {
+        let conn = Connection::open_in_memory().unwrap();
+        fixture_v0(&conn);
+
+        let steps = ["CREATE TABLE first(x INTEGER);", "CREATE TABLE second(x INTEGER); NOT VALID SQL;"];
+        let res = apply_migrations(&conn, 0, &steps);
+        assert!(matches!(res, Err((1, _))));
+
+        assert_eq!(get_version(&conn), "1");
+        assert!(table_exists(&conn, "first"));
+        assert!(!table_exists(&conn, "second"));
+        check_fixture_data(&conn);
+    }
+
+    fn get_stored_password(conn: &Connection, user_id: u32) -> ([u8;32], Option<String>) {
+        conn.query_row("SELECT password, password_hash FROM users WHERE user_id=?1", params![user_id], |row| Ok((row.get(0)?, row.get(1)?))).unwrap()
+    }
+
+    #[test]
+    fn passwords_are_salted() {
+        let conn = open();
+        assert!(create_user(&conn, "first".to_string(), password(1), false));
+        assert!(create_user(&conn, "second".to_string(), password(1), false));
+
+        let (first_bytes, first_hash) = get_stored_password(&conn, 1);
+        let (_, second_hash) = get_stored_password(&conn, 2);
+        assert_eq!(first_bytes, [0; 32]);
+        assert!(first_hash.clone().unwrap().starts_with("$argon2id$"));
+        assert_ne!(first_hash, second_hash);
+
+        assert!(login(&conn, "first".to_string(), password(1), 0).is_some());
+        assert!(login(&conn, "second".to_string(), password(1), 0).is_some());
+        assert!(login(&conn, "first".to_string(), password(2), 0).is_none());
+
+        // The stored bytes are no password
+        assert!(login(&conn, "first".to_string(), password(0), 0).is_none());
+    }
+
+    #[test]
+    fn old_password_migrates_on_login() {
+        let conn = Connection::open_in_memory().unwrap();
+        fixture_v0(&conn);
+        assert!(apply_migrations(&conn, 0, &MIGRATIONS).is_ok());
+        assert_eq!(get_stored_password(&conn, 1), ([1; 32], None));
+
+        // A wrong password changes nothing
+        assert!(login(&conn, "admin".to_string(), password(2), 0).is_none());
+        assert_eq!(get_stored_password(&conn, 1), ([1; 32], None));
+
+        assert!(login(&conn, "admin".to_string(), password(1), 0).is_some());
+        let (bytes, pw_hash) = get_stored_password(&conn, 1);
+        assert_eq!(bytes, [0; 32]);
+        assert!(pw_hash.unwrap().starts_with("$argon2id$"));
+
+        assert!(login(&conn, "admin".to_string(), password(1), 0).is_some());
+        assert!(login(&conn, "admin".to_string(), password(0), 0).is_none());
+    }
+
+    #[test]
+    fn login_failures_back_off() {
+        let conn = open();
+        let user = LockoutSource::User;
+        let name = "user".to_string();
+        let now = 1_000_000;
+
+        for _ in 0..FREE_USER_ATTEMPTS {
+            assert_eq!(record_login_attempt(&conn, &user, &name, now), Some(0));
+        }
+        assert_eq!(get_login_lockout(&conn, &user, &name, now), 0);
+
+        // Every further failure doubles the wait, up to the max
+        assert_eq!(record_login_attempt(&conn, &user, &name, now), Some(now + 2));
+        assert_eq!(get_login_lockout(&conn, &user, &name, now), 2);
+
+        // Attempts during the lockout don't count
+        assert_eq!(record_login_attempt(&conn, &user, &name, now + 1), None);
+        assert_eq!(record_login_attempt(&conn, &user, &name, now + 2), Some(now + 2 + 4));
+        let mut time = now + 6;
+        for _ in 0..100 {
+            time = record_login_attempt(&conn, &user, &name, time).unwrap();
+        }
+        assert_eq!(get_login_lockout(&conn, &user, &name, time - MAX_LOGIN_LOCKOUT), MAX_LOGIN_LOCKOUT as u64);
+        assert_eq!(get_login_lockout(&conn, &user, &name, time), 0);
+
+        // The same name as address is counted separately
+        assert_eq!(get_login_lockout(&conn, &LockoutSource::Address, &name, now), 0);
+
+        let list = get_login_lockouts(&conn);
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].failures, FREE_USER_ATTEMPTS + 102);
+
+        // Old failures are forgotten
+        let later = time + LOGIN_ATTEMPT_RESET + 1;
+        assert_eq!(record_login_attempt(&conn, &user, &name, later), Some(0));
+        assert_eq!(get_login_lockout(&conn, &user, &name, later), 0);
+        assert_eq!(get_login_lockouts(&conn)[0].failures, 1);
+
+        assert!(clear_login_failures(&conn, &user, &name));
+        assert!(get_login_lockouts(&conn).is_empty());
+    }
+
+    #[test]
+    fn forgiven_attempt_leaves_no_lockout() {
+        let conn = open();
+        let address = LockoutSource::Address;
+        let name = "127.0.0.1".to_string();
+        let now = 1_000_000;
+
+        for _ in 0..FREE_ADDRESS_ATTEMPTS {
+            assert_eq!(record_login_attempt(&conn, &address, &name, now), Some(0));
+        }
+
+        // The attempt past the free ones locks, until it turns out to be fine
+        let locked_until = record_login_attempt(&conn, &address, &name, now).unwrap();
+        assert_eq!(get_login_lockout(&conn, &address, &name, now), 2);
+        assert!(forgive_login_attempt(&conn, &address, &name, locked_until));
+        assert_eq!(get_login_lockout(&conn, &address, &name, now), 0);
+        assert_eq!(get_login_lockouts(&conn)[0].failures, FREE_ADDRESS_ATTEMPTS);
+
+        // Nothing is left once every attempt was fine
+        let other = "::1".to_string();
+        let locked_until = record_login_attempt(&conn, &address, &other, now).unwrap();
+        assert!(forgive_login_attempt(&conn, &address, &other, locked_until));
+        assert_eq!(get_login_lockouts(&conn).len(), 1);
+    }
+
+    #[test]
+    fn sessions_list_and_revoke() {
+        let conn = open();
+        assert!(create_user(&conn, "user".to_string(), password(1), false));
+        let device = create_device(&conn, 1, "Handheld".to_string()).unwrap();
+
+        let first = login(&conn, "user".to_string(), password(1), 0).unwrap();
+        let second = login(&conn, "user".to_string(), password(1), device.device_id).unwrap();
+
+        let list = get_sessions(&conn, 1);
+        assert_eq!(list.len(), 2);
+        assert_eq!(list[1].device_name, "Handheld");
+        assert!(list.iter().all(|s| s.last_use.is_none()));
+
+        assert!(get_auth_handle_from_token(&conn, first.token).is_some());
+        let last_use = get_sessions(&conn, 1)[0].last_use.unwrap();
+
+        // Only written again once it is older than the interval
+        touch_token(&conn, first.token, last_use + LAST_USE_INTERVAL - 1);
+        assert_eq!(get_sessions(&conn, 1)[0].last_use, Some(last_use));
+        touch_token(&conn, first.token, last_use + LAST_USE_INTERVAL);
+        assert_eq!(get_sessions(&conn, 1)[0].last_use, Some(last_use + LAST_USE_INTERVAL));
+
+        // Only the revoked device is logged out
+        assert!(revoke_session(&conn, 1, device.device_id));
+        assert!(!revoke_session(&conn, 1, device.device_id));
+        assert!(get_auth_handle_from_token(&conn, second.token).is_none());
+        assert!(get_auth_handle_from_token(&conn, first.token).is_some());
+        assert_eq!(get_sessions(&conn, 1).len(), 1);
+
+        assert!(revoke_all_sessions(&conn, 1));
+        assert!(get_auth_handle_from_token(&conn, first.token).is_none());
+        assert!(get_sessions(&conn, 1).is_empty());
+    }
+
+    #[test]
+    fn token_times_apply_without_restart() {
+        let conn = open();
+        assert!(create_user(&conn, "user".to_string(), password(1), false));
+        let token = login(&conn, "user".to_string(), password(1), 0).unwrap().token;
+        conn.execute("UPDATE tokens SET creation_time=creation_time-1000", params![]).unwrap();
+
+        assert!(get_auth_handle_from_token(&conn, token).unwrap().token.is_none());
+
+        assert!(set_token_times(&conn, 2000, 500));
+        assert_eq!(get_token_times(&conn), Some((2000, 500)));
+        let token = get_auth_handle_from_token(&conn, token).unwrap().token.unwrap().token;
+
+        conn.execute("UPDATE tokens SET creation_time=creation_time-1000", params![]).unwrap();
+        assert!(set_token_times(&conn, 900, 100));
+        assert!(get_auth_handle_from_token(&conn, token).is_none());
+
+        assert!(!set_token_times(&conn, 100, 100));
+        assert!(!set_token_times(&conn, 100, 0));
+        assert!(!set_token_times(&conn, u64::MAX, 100));
+        assert_eq!(get_token_times(&conn), Some((900, 100)));
+    }
+
+    #[test]
+    fn api_keys_are_scoped() {
+        let conn = open();
+        assert!(create_user(&conn, "user".to_string(), password(1), false));
+        let device = create_device(&conn, 1, "Handheld".to_string()).unwrap();
+        for name in ["first", "second"] {
+            create_repo_fast(&conn, name.to_string());
+            assert!(set_user_repo_permission(&conn, 1, name.to_string(), AccessType::Owner));
+        }
+
+        let (key, info) = create_api_key(&conn, 1, device.device_id, Some("sync".to_string()), Some(vec!["first".to_string()]), AccessType::ReadWrite).unwrap();
+        assert_eq!(info.repos, Some(vec!["first".to_string()]));
+        assert!(create_api_key(&conn, 1, 9, None, None, AccessType::Read).is_none());
+
+        // Only the hash is stored
+        let stored: Vec<u8> = conn.query_row("SELECT key_hash FROM api_keys WHERE key_id=?1", params![info.key_id], |row| row.get(0)).unwrap();
+        assert_ne!(stored, key.as_bytes().to_vec());
+
+        // Keys are no tokens, but work wherever a handle is needed
+        assert!(get_auth_handle_from_token(&conn, key).is_none());
+        let handle = get_auth_handle_from_api_key(&conn, key).unwrap();
+        assert_eq!(handle.device_id, device.device_id);
+        assert!(handle.token.is_none());
+        assert_eq!(get_handle_repo_permission(&conn, &handle, "first".to_string()), Some(AccessType::ReadWrite));
+        assert_eq!(get_handle_repo_permission(&conn, &handle, "second".to_string()), Some(AccessType::No));
+        assert!(get_api_keys(&conn, 1, None)[0].last_use.is_some());
+
+        // A key without repos only lowers the permission
+        let (all, _) = create_api_key(&conn, 1, device.device_id, None, None, AccessType::Read).unwrap();
+        let handle = get_auth_handle_from_api_key(&conn, all).unwrap();
+        assert_eq!(get_handle_repo_permission(&conn, &handle, "second".to_string()), Some(AccessType::Read));
+        assert_eq!(get_api_keys(&conn, 1, Some(device.device_id)).len(), 2);
+        assert!(get_api_keys(&conn, 1, Some(0)).is_empty());
+
+        // A new repo with the name of a deleted one is not part of the key
+        assert!(delete_repo(&conn, "first".to_string()));
+        create_repo_fast(&conn, "first".to_string());
+        assert!(set_user_repo_permission(&conn, 1, "first".to_string(), AccessType::Owner));
+        let handle = get_auth_handle_from_api_key(&conn, key).unwrap();
+        assert_eq!(get_handle_repo_permission(&conn, &handle, "first".to_string()), Some(AccessType::No));
+
+        assert!(revoke_api_key(&conn, 1, info.key_id));
+        assert!(!revoke_api_key(&conn, 1, info.key_id));
+        assert!(get_auth_handle_from_api_key(&conn, key).is_none());
+        assert!(get_auth_handle_from_api_key(&conn, all).is_some());
+
+        // Deleting the device takes it's keys along
+        assert!(delete_device(&conn, 1, device.device_id));
+        assert!(get_auth_handle_from_api_key(&conn, all).is_none());
+        assert!(get_api_keys(&conn, 1, None).is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "newer")]
+    fn refuses_newer_database() {
+        let conn = open();
+        set_key_value(&conn, KEY_VERSION.to_string(), (SCHEMA_VERSION + 1).to_string());
+
+        init_schema(&conn, None);
+    }
 }
 
 
@@ -1,25 +1,49 @@
-use std::{path::PathBuf, collections::HashMap, sync::Mutex, str::FromStr};
+use std::{path::PathBuf, collections::{HashMap, HashSet}, sync::Arc, str::FromStr};
 
-use storage::StorageRepo;
+use actix_web_lab::__reexports::tokio::sync::{RwLock, Mutex};
+
+use storage::{StorageRepo, SnapshotPolicy};
+use blob_store::BlobStore;
 use rusqlite::Connection;
 use uuid::Uuid;
+use common::{U232, data::{VerifyReport, Compression, RetentionPolicy}};
 
-use crate::database;
+use crate::database::{self, DbPool};
 
 pub mod io;
 pub mod storage;
 pub mod repository_file;
+pub mod blob_store;
+#[cfg(test)]
+pub mod test_util;
 
 const KEY_TEMP_FOLDER:&str = "temp_folder";
-
+const KEY_SNAPSHOT_DEPTH:&str = "snapshot_depth";
+const KEY_SNAPSHOT_SIZE:&str = "snapshot_size";
+const KEY_COMPRESSION:&str = "compression";
+const KEY_RETENTION_ALL:&str = "retention_keep_all";
+const KEY_RETENTION_DAILY:&str = "retention_daily";
+const KEY_RETENTION_WEEKLY:&str = "retention_weekly";
+const KEY_RETENTION_INTERVAL:&str = "retention_interval"; // in the key values, seconds between scheduled runs
+const DEFAULT_RETENTION_INTERVAL:u64 = 24 * 60 * 60;
+const MAX_RETENTION_DAYS:u64 = 100 * 366;
+
+// One controller is shared by all workers, the map itself only changes when repos are created or deleted
+// Each repo has it's own async lock, so work on different repos runs in parallel, while writes to the same repo are serialized
 pub struct RepoController {
     root_path: String,
-    repos: HashMap<String,Mutex<StorageRepo>>
+    repos: HashMap<String,Arc<Mutex<StorageRepo>>>,
+    blob_store: BlobStore,
+    temp_folder: PathBuf // Large files are build in here
 }
 
 pub fn init(db: &Connection) -> RepoController {
     let path = std::env::var("REPO_PATH").unwrap_or("./target/repo/".to_string()); // TODO handle release, although this technically works as a default there too
-    
+    open(db, path)
+}
+
+// Same as init, but with the repositories in path
+pub fn open(db: &Connection, path: String) -> RepoController {
     let place = PathBuf::from(&path);
     if place.exists() && !place.is_dir() {
         panic!("REPO_PATH has to be a folder. REPO_PATH value was: {}", path);
@@ -30,14 +54,11 @@ pub fn init(db: &Connection) -> RepoController {
         panic!("Unable to create folder for repositories at {}\nError: {}",path, e.to_string());
     }
 
-    // Building the storage controller
-    let mut con = RepoController {
-        root_path: path,
-        repos: HashMap::<String,Mutex<StorageRepo>>::new()
+    let blob_store = match blob_store::init(place.as_path()) {
+        Ok(store) => store,
+        Err(e) => panic!("Unable to create the blob store in {}\nError: {}", path, e.to_string())
     };
 
-    con.reload_folder(db);
-
     // Temp folder handling
     let temp_folder = PathBuf::from(if let Ok(temp_folder) = std::env::var("TEMP_PATH") {
         database::set_key_value(&db, KEY_TEMP_FOLDER.to_string(), temp_folder.clone());
@@ -58,7 +79,7 @@ pub fn init(db: &Connection) -> RepoController {
             panic!("Unable to create temp folder at:{} \n Error code: {}", temp_folder.to_str().unwrap(), e.to_string())
         }
 
-        let res = db.execute_batch("DELETE FROM temp_folder_reference; DELETE FROM temp_folder;");
+        let res = db.execute_batch("DELETE FROM temp_folder_reference; DELETE FROM temp_folder_owner; DELETE FROM temp_folder;");
         if let Err(e) = res {
             panic!("Unable to clear out temp data from the database: {}", e.to_string());
         }
@@ -69,9 +90,45 @@ pub fn init(db: &Connection) -> RepoController {
         panic!("Something went wrong when cleaning out the temp folder");
     }
 
+    // Building the storage controller
+    let mut con = RepoController {
+        root_path: path,
+        repos: HashMap::<String,Arc<Mutex<StorageRepo>>>::new(),
+        blob_store,
+        temp_folder
+    };
+
+    con.reload_folder(db);
+
     con
 }
 
+// A quota is the most that may be used, so reaching it is still fine
+pub fn is_over_quota(used: u64, quota: u64) -> bool {
+    used > quota
+}
+
+// Applies the retention policies of all repos in the background
+// Repos are locked one at a time, so the workers only wait on the repo currently being pruned
+pub fn start_retention_schedule(pool: DbPool, controller: Arc<RwLock<RepoController>>) {
+    std::thread::spawn(move || {
+        loop {
+            // Read every time, so changes apply after the current wait
+            let interval = pool.get().ok()
+                .map(|db| get_retention_interval(&db))
+                .unwrap_or(DEFAULT_RETENTION_INTERVAL);
+            std::thread::sleep(std::time::Duration::from_secs(interval));
+
+            let repos = controller.blocking_read().get_repos();
+            for (name, repo) in repos {
+                if let Some(report) = repo.blocking_lock().apply_retention(get_unix_time(), false) {
+                    log::info!("Retention on {}: kept {}, pruned {}, deleted {} files", name, report.kept, report.pruned, report.garbage.deleted);
+                }
+            }
+        }
+    });
+}
+
 impl RepoController {
     pub fn reload_folder(&mut self, db: &Connection) {
         let dir = io::get_folder_content(PathBuf::from(&self.root_path).as_path());
@@ -79,11 +136,21 @@ impl RepoController {
         self.repos.clear();
         let mut list = database::list_repos(&db, None);
         for folder in dir {
+            if folder.file_name().and_then(|n| n.to_str()) == Some(blob_store::BLOB_FOLDER) {
+                continue;
+            }
+
             let res = storage::read_storage_info(folder.as_path());
-            if let Ok(rep) = res {
+            if let Ok(mut rep) = res {
                 let name = folder.file_name().unwrap().to_str().unwrap().to_string(); // TODO maybe do this better
 
-                self.repos.insert(name.clone(), Mutex::new(rep));
+                rep.set_snapshot_policy(get_snapshot_policy(db, &name));
+                rep.set_retention_policy(get_retention_policy(db, &name));
+                rep.set_blob_store(self.blob_store.clone());
+                rep.set_temp_folder(self.temp_folder.clone());
+                rep.set_compression(get_compression(db, &name));
+
+                self.repos.insert(name.clone(), Arc::new(Mutex::new(rep)));
                 
                 // Seeing if it already exists in the DB, if not add it
                 let mut found = false;
@@ -111,38 +178,247 @@ impl RepoController {
     }
 
     pub fn create_repo(&mut self, name: String) -> bool {
+        if name == blob_store::BLOB_FOLDER {
+            return false;
+        }
+
         let mut path = PathBuf::from(&self.root_path);
         path.push(&name);
 
         let res = storage::new_repo(path.as_path(), name.clone());
-        if let Ok(repo) = res {
-            self.repos.insert(name, Mutex::new(repo));
+        if let Ok(mut repo) = res {
+            repo.set_blob_store(self.blob_store.clone());
+            repo.set_temp_folder(self.temp_folder.clone());
+            self.repos.insert(name, Arc::new(Mutex::new(repo)));
             return true;
         }
 
         false
     }
 
-    pub fn delete_repo(&mut self, name: &String) -> bool {
-        if let Some(old_repo_mu) = self.repos.remove(name) {
-            let old_repo = (&old_repo_mu).lock().unwrap();
-            let path = PathBuf::from(old_repo.get_folder());
+    // The controller does not have to stay locked while waiting for the repo
+    pub fn get_repo(& self, name: &String) -> Option<Arc<Mutex<StorageRepo>>> {
+        self.repos.get(name).cloned()
+    }
 
-            if let Ok(_) = io::delete_folder(path.as_path()) {
-                return true;
-            } else {
-                // Undo
-                drop(old_repo);
-                self.repos.insert(name.clone(), old_repo_mu);
-                return false;
-            }
+    pub fn get_repos(& self) -> Vec<(String, Arc<Mutex<StorageRepo>>)> {
+        self.repos.iter().map(|(name, repo)| (name.clone(), repo.clone())).collect()
+    }
+
+    // Used by new repos, the returned repos still have to be updated by the caller
+    // This way the controller is not locked while waiting on each repo
+    pub fn set_temp_folder(&mut self, temp_folder: PathBuf) -> Vec<(String, Arc<Mutex<StorageRepo>>)> {
+        self.temp_folder = temp_folder;
+        self.get_repos()
+    }
+}
+
+// The functions below only lock the controller to look the repos up, and wait for the repos afterwards
+// Else a request waiting on a busy repo would hold the controller, and any queued write stalls every other request
+
+// Waits for everyone currently working on the repo
+pub async fn delete_repo(controller: &RwLock<RepoController>, name: &String) -> bool {
+    let (old_repo_mu, blob_store) = {
+        let mut controller = controller.write().await;
+        (controller.repos.remove(name), controller.blob_store.clone())
+    };
+
+    if let Some(old_repo_mu) = old_repo_mu {
+        let old_repo = old_repo_mu.lock().await;
+        let path = PathBuf::from(old_repo.get_folder());
+
+        if let Ok(_) = io::delete_folder(path.as_path()) {
+            // The repo no longer references any blobs
+            blob_store.release_repo(name, &HashSet::<U232>::new());
+            return true;
+        } else {
+            // Undo
+            drop(old_repo);
+            controller.write().await.repos.insert(name.clone(), old_repo_mu);
+            return false;
         }
-        false
-    } 
+    }
+    false
+}
+
+// Verifies the repository against a fresh read from disk, so cached commits can not hide broken files
+// The repo stays locked, so no commits are written while we check
+pub async fn verify_repo(controller: &RwLock<RepoController>, name: &String) -> Option<VerifyReport> {
+    let (repo, blob_store, temp_folder) = {
+        let controller = controller.read().await;
+        (controller.get_repo(name)?, controller.blob_store.clone(), controller.temp_folder.clone())
+    };
+    let repo = repo.lock().await;
+
+    let mut fresh = storage::read_storage_info(PathBuf::from(repo.get_folder()).as_path()).ok()?;
+    fresh.set_blob_store(blob_store);
+    fresh.set_temp_folder(temp_folder);
+    let report = fresh.verify();
+
+    drop(repo);
+    Some(report)
+}
+
+pub async fn get_repo_size(controller: &RwLock<RepoController>, name: &String) -> Option<u64> {
+    let repo = controller.read().await.get_repo(name)?;
+    let size = repo.lock().await.get_storage_size();
+    Some(size)
+}
+
+// Sum of the sizes of the repos, missing ones count as empty
+// Blobs shared between the repos are only counted once
+pub async fn get_storage(controller: &RwLock<RepoController>, names: &Vec<String>) -> u64 {
+    let mut sum = 0;
+    let mut blobs = HashMap::<U232, u64>::new();
+    for name in names {
+        let repo = if let Some(repo) = controller.read().await.get_repo(name) { repo } else { continue; };
+        let repo = repo.lock().await;
+        sum = sum + repo.get_folder_size();
+        blobs.extend(repo.get_referenced_blobs());
+    }
+
+    sum + blobs.values().sum::<u64>()
+}
+
+// A quota and the repos counted towards it
+// Read from the DB up front, so the repos are locked without holding a connection
+pub struct QuotaLimit {
+    pub quota: u64,
+    pub repos: Vec<String>
+}
+
+// None if the user has no quota, all repos owned by the user count towards it
+pub fn get_user_limit(db: &Connection, user_id: u32) -> Option<QuotaLimit> {
+    let quota = database::get_user_quota(db, user_id)?;
+    Some(QuotaLimit { quota, repos: database::get_owned_repos(db, user_id) })
+}
 
-    pub fn get_repo(& self, name: &String) -> Option<&Mutex<StorageRepo>> {
-        self.repos.get(name)
+// The quota of the repo, and the one of its owner
+pub fn get_repo_limits(db: &Connection, name: &String) -> Vec<QuotaLimit> {
+    let mut limits = Vec::new();
+    if let Some(quota) = database::get_repo_quota(db, name) {
+        limits.push(QuotaLimit { quota, repos: vec![name.clone()] });
+    }
+    if let Some(limit) = database::get_repo_owner(db, name).and_then(|owner| get_user_limit(db, owner)) {
+        limits.push(limit);
     }
+
+    limits
+}
+
+// Usage and quota of the limit
+pub async fn get_usage(controller: &RwLock<RepoController>, limit: &QuotaLimit) -> (u64, u64) {
+    (get_storage(controller, &limit.repos).await, limit.quota)
+}
+
+// Returns the usage and quota of the first limit the additional bytes would go over, commits are rejected once a quota is exceeded
+pub async fn check_quota(controller: &RwLock<RepoController>, limits: &Vec<QuotaLimit>, additional: u64) -> Option<(u64, u64)> {
+    for limit in limits {
+        let (used, quota) = get_usage(controller, limit).await;
+        let used = used.saturating_add(additional);
+        if is_over_quota(used, quota) {
+            return Some((used, quota));
+        }
+    }
+
+    None
+}
+
+pub fn get_unix_time() -> u64 {
+    chrono::Utc::now().timestamp().try_into().unwrap_or_default()
+}
+
+pub fn get_snapshot_policy(db: &Connection, name: &String) -> SnapshotPolicy {
+    let mut policy = SnapshotPolicy::default();
+
+    if let Some(val) = database::get_repo_setting(db, name, KEY_SNAPSHOT_DEPTH).and_then(|v| v.parse().ok()) {
+        policy.max_depth = val;
+    }
+    if let Some(val) = database::get_repo_setting(db, name, KEY_SNAPSHOT_SIZE).and_then(|v| v.parse().ok()) {
+        policy.max_size = val;
+    }
+
+    policy
+}
+
+pub fn save_snapshot_policy(db: &Connection, name: &String, policy: &SnapshotPolicy) -> bool {
+    database::set_repo_setting(db, name, KEY_SNAPSHOT_DEPTH, policy.max_depth.to_string()) &&
+        database::set_repo_setting(db, name, KEY_SNAPSHOT_SIZE, policy.max_size.to_string())
+}
+
+// Only complete policies are loaded, the weekly limit is optional
+pub fn get_retention_policy(db: &Connection, name: &String) -> Option<RetentionPolicy> {
+    let keep_all_days = database::get_repo_setting(db, name, KEY_RETENTION_ALL).and_then(|v| v.parse().ok())?;
+    let daily_days = database::get_repo_setting(db, name, KEY_RETENTION_DAILY).and_then(|v| v.parse().ok())?;
+    let weekly_days = database::get_repo_setting(db, name, KEY_RETENTION_WEEKLY).and_then(|v| v.parse().ok());
+
+    Some(RetentionPolicy { keep_all_days, daily_days, weekly_days })
+}
+
+// Every span has to fit into the next one, and none may be longer then a century
+pub fn is_valid_retention_policy(policy: &RetentionPolicy) -> bool {
+    let weekly_days = policy.weekly_days.unwrap_or(MAX_RETENTION_DAYS);
+    policy.keep_all_days <= policy.daily_days && policy.daily_days <= weekly_days && weekly_days <= MAX_RETENTION_DAYS
+}
+
+// None removes the policy
+pub fn save_retention_policy(db: &Connection, name: &String, policy: &Option<RetentionPolicy>) -> bool {
+    if let Some(policy) = policy {
+        database::set_repo_setting(db, name, KEY_RETENTION_ALL, policy.keep_all_days.to_string()) &&
+        database::set_repo_setting(db, name, KEY_RETENTION_DAILY, policy.daily_days.to_string()) &&
+        if let Some(weekly) = policy.weekly_days {
+            database::set_repo_setting(db, name, KEY_RETENTION_WEEKLY, weekly.to_string())
+        } else {
+            database::delete_repo_setting(db, name, KEY_RETENTION_WEEKLY)
+        }
+    } else {
+        database::delete_repo_setting(db, name, KEY_RETENTION_ALL) &&
+        database::delete_repo_setting(db, name, KEY_RETENTION_DAILY) &&
+        database::delete_repo_setting(db, name, KEY_RETENTION_WEEKLY)
+    }
+}
+
+pub fn get_compression(db: &Connection, name: &String) -> Compression {
+    database::get_repo_setting(db, name, KEY_COMPRESSION).map(|c| Compression::from_str(c)).unwrap_or_default()
+}
+
+pub fn save_compression(db: &Connection, name: &String, compression: &Compression) -> bool {
+    database::set_repo_setting(db, name, KEY_COMPRESSION, compression.cast())
+}
+
+pub fn get_retention_interval(db: &Connection) -> u64 {
+    database::get_key_value(db, KEY_RETENTION_INTERVAL.to_string())
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_RETENTION_INTERVAL)
+}
+
+pub fn set_retention_interval(db: &Connection, interval: u64) -> bool {
+    if interval == 0 {
+        return false;
+    }
+
+    database::set_key_value(db, KEY_RETENTION_INTERVAL.to_string(), interval.to_string());
+    get_retention_interval(db) == interval
+}
+
+pub fn get_temp_folder_root(db: &Connection) -> Option<String> {
+    database::get_key_value(db, KEY_TEMP_FOLDER.to_string())
+}
+
+// Temp folders are found relative to the root, so it can only be moved while there are none
+// TEMP_PATH still overwrites it on the next start
+pub fn set_temp_folder_root(db: &Connection, root: String) -> bool {
+    if database::count_temp_folders(db) != 0 {
+        return false;
+    }
+
+    let path = PathBuf::from(&root);
+    if path.is_file() || io::create_folder(path.as_path()).is_err() {
+        return false;
+    }
+
+    database::set_key_value(db, KEY_TEMP_FOLDER.to_string(), root.clone());
+    get_temp_folder_root(db) == Some(root)
 }
 
 pub fn create_temp_folder(db: &Connection, folder_token: Uuid) -> bool {
@@ -276,4 +552,347 @@ pub fn prune_temp_folders(db: &Connection) -> bool {
     }
 
     false
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::Path, sync::Arc};
+
+    use actix_web_lab::__reexports::tokio::sync::RwLock;
+    use common::{U232, U256, LargeU, data::{AccessType, RetentionPolicy}};
+
+    use crate::database;
+    use super::{io, test_util::{get_root, new_controller}, storage::{StorageRepo, BranchUpdate}, verify_repo, get_repo_size, get_storage, get_user_limit, get_repo_limits, check_quota, is_valid_retention_policy};
+
+    const THREADS:usize = 8;
+    const COMMITS:usize = 5;
+
+    // Every commit gets different content, so no two commits end up with the same id
+    fn write_save(folder: &Path, text: String) {
+        io::create_folder(folder).unwrap();
+        let mut file = folder.to_path_buf();
+        file.push("game.sav");
+        io::write_bytes(file.as_path(), text.repeat(64).into_bytes()).unwrap();
+    }
+
+    // What the create commit handler followed by a push does
+    fn commit_onto_branch(repo: &mut StorageRepo, folder: &Path, branch: &String) -> BranchUpdate {
+        repo.update_header_and_branches();
+        let tip = repo.get_branch(branch.clone()).unwrap().get_previous_commit();
+        let prev = if tip == U232::new() { None } else { Some(tip) };
+
+        let commit = repo.create_commit(prev, folder, true).unwrap();
+        repo.push_commit_onto_branch(commit, branch.clone(), false)
+    }
+
+    #[test]
+    fn parallel_commits_to_one_branch() {
+        let root = get_root("parallel");
+        let name = "Parallel".to_string();
+        let branch = "master".to_string();
+
+        let mut controller = new_controller(&root);
+        assert!(controller.create_repo(name.clone()));
+        let controller = Arc::new(RwLock::new(controller));
+
+        {
+            let controller = controller.blocking_read();
+            let repo = controller.get_repo(&name).unwrap();
+            let mut repo = repo.blocking_lock();
+            assert!(matches!(repo.create_branch(branch.clone(), U232::new()), BranchUpdate::Ok));
+
+            let mut folder = root.clone();
+            folder.push("initial");
+            write_save(&folder, "initial".to_string());
+            assert!(matches!(commit_onto_branch(&mut repo, &folder, &branch), BranchUpdate::Ok));
+        }
+
+        // Each thread acts like a worker, committing on top of whatever the branch currently points to
+        let mut handles = Vec::new();
+        for thread in 0..THREADS {
+            let controller = controller.clone();
+            let name = name.clone();
+            let branch = branch.clone();
+            let mut folder = root.clone();
+            folder.push(format!("client{}", thread));
+
+            handles.push(std::thread::spawn(move || {
+                actix_web::rt::System::new().block_on(async move {
+                    for index in 0..COMMITS {
+                        write_save(&folder, format!("thread {} commit {};", thread, index));
+
+                        let repo = controller.read().await.get_repo(&name).unwrap();
+                        let mut repo = repo.lock().await;
+                        assert!(matches!(commit_onto_branch(&mut repo, &folder, &branch), BranchUpdate::Ok));
+                    }
+                });
+            }));
+        }
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        // No commit may have been lost, and the files on disk have to be intact
+        let controller = controller.blocking_read();
+        let repo = controller.get_repo(&name).unwrap();
+        let mut repo = repo.blocking_lock();
+        repo.update_header_and_branches();
+        let tip = repo.get_branch(branch.clone()).unwrap().get_previous_commit();
+        let (log, _) = repo.get_commit_log(tip, usize::MAX, usize::MAX);
+        assert_eq!(log.len(), THREADS * COMMITS + 1);
+
+        let report = repo.verify();
+        assert!(report.problems.is_empty());
+
+        let _ = io::delete_folder(root.as_path());
+    }
+
+    // New files this large go into the blob store, which the fresh read of the repo has to find too
+    #[test]
+    fn verify_repo_with_blobs() {
+        let root = get_root("verify");
+        let name = "Verify".to_string();
+        let branch = "master".to_string();
+
+        let mut controller = new_controller(&root);
+        assert!(controller.create_repo(name.clone()));
+
+        let mut folder = root.clone();
+        folder.push("client");
+        {
+            let repo = controller.get_repo(&name).unwrap();
+            let mut repo = repo.blocking_lock();
+            assert!(matches!(repo.create_branch(branch.clone(), U232::new()), BranchUpdate::Ok));
+
+            for index in 0..COMMITS {
+                write_save(&folder, format!("a save that is larger then the minimum blob size, commit number {};", index));
+                assert!(matches!(commit_onto_branch(&mut repo, &folder, &branch), BranchUpdate::Ok));
+            }
+        }
+
+        let mut first = root.clone();
+        first.push("first.sav");
+        io::write_bytes(first.as_path(), "a save that is larger then the minimum blob size, commit number 0;".repeat(64).into_bytes()).unwrap();
+        assert!(controller.blob_store.contains(&io::hash_file(first.as_path()).unwrap()));
+
+        let controller = RwLock::new(controller);
+        let report = actix_web::rt::System::new().block_on(verify_repo(&controller, &name)).unwrap();
+        assert_eq!(report.checked, COMMITS * 2);
+        assert!(report.problems.is_empty());
+
+        let _ = io::delete_folder(root.as_path());
+    }
+
+    // A save full of zeros would be worse then no save at all
+    #[test]
+    fn missing_blob_fails_build() {
+        let root = get_root("missing");
+        let name = "Missing".to_string();
+        let branch = "master".to_string();
+
+        let mut controller = new_controller(&root);
+        assert!(controller.create_repo(name.clone()));
+        let repo = controller.get_repo(&name).unwrap();
+        let mut repo = repo.blocking_lock();
+        assert!(matches!(repo.create_branch(branch.clone(), U232::new()), BranchUpdate::Ok));
+
+        let mut folder = root.clone();
+        folder.push("client");
+        write_save(&folder, "a save that is larger then the minimum blob size, commit number 0;".to_string());
+        assert!(matches!(commit_onto_branch(&mut repo, &folder, &branch), BranchUpdate::Ok));
+        let first = repo.get_branch(branch.clone()).unwrap().get_previous_commit();
+        write_save(&folder, "a save that is larger then the minimum blob size, commit number 1;".to_string());
+        assert!(matches!(commit_onto_branch(&mut repo, &folder, &branch), BranchUpdate::Ok));
+        repo.update_header_and_branches();
+        let second = repo.get_branch(branch.clone()).unwrap().get_previous_commit();
+
+        let mut save = folder.clone();
+        save.push("game.sav");
+        let mut target = root.clone();
+        target.push("build");
+        io::create_folder(target.as_path()).unwrap();
+        assert!(repo.build_commit(second, target.as_path()));
+
+        // Gone from the shared store, like after a bad release
+        io::write_bytes(save.as_path(), "a save that is larger then the minimum blob size, commit number 0;".repeat(64).into_bytes()).unwrap();
+        let blob = io::hash_file(save.as_path()).unwrap();
+        io::delete_file(controller.blob_store.get_path(&blob).as_path()).unwrap();
+
+        assert!(!repo.build_commit(first, target.as_path()));
+        assert!(!repo.build_commit(second, target.as_path()));
+        assert!(!repo.verify().problems.is_empty());
+
+        drop(repo);
+        let _ = io::delete_folder(root.as_path());
+    }
+
+    #[test]
+    fn quota_counts_blobs() {
+        let root = get_root("quota");
+        let name = "Quota".to_string();
+        let branch = "master".to_string();
+
+        let mut db_file = root.clone();
+        db_file.push("dat.db");
+        let pool = database::open_pool(db_file.to_str().unwrap().to_string());
+        let db = pool.get().unwrap();
+        assert!(database::create_user(&db, "owner".to_string(), U256::from_u8arr(&[1; 32]), false));
+        database::create_repo_fast(&db, name.clone());
+        assert!(database::set_user_repo_permission(&db, 1, name.clone(), AccessType::Owner));
+
+        let mut controller = new_controller(&root);
+        assert!(controller.create_repo(name.clone()));
+        {
+            let repo = controller.get_repo(&name).unwrap();
+            let mut repo = repo.blocking_lock();
+            assert!(matches!(repo.create_branch(branch.clone(), U232::new()), BranchUpdate::Ok));
+
+            let mut folder = root.clone();
+            folder.push("client");
+            write_save(&folder, "a save that is larger then the minimum blob size, commit number 0;".to_string());
+            assert!(matches!(commit_onto_branch(&mut repo, &folder, &branch), BranchUpdate::Ok));
+        }
+
+        // The blob is stored outside the repo folder, but still charged to it
+        let blobs = controller.blob_store.get_referenced_size(&name);
+        assert_eq!(blobs, 4224);
+        let controller = RwLock::new(controller);
+
+        actix_web::rt::System::new().block_on(async {
+            let used = get_repo_size(&controller, &name).await.unwrap();
+            assert!(used > blobs);
+            assert_eq!(get_storage(&controller, &database::get_owned_repos(&db, 1)).await, used);
+
+            // Reaching the quota is fine, going over it is not
+            assert!(database::set_repo_quota(&db, &name, Some(used)));
+            assert!(check_quota(&controller, &get_repo_limits(&db, &name), 0).await.is_none());
+            assert!(database::set_repo_quota(&db, &name, Some(used - 1)));
+            assert_eq!(check_quota(&controller, &get_repo_limits(&db, &name), 0).await, Some((used, used - 1)));
+            assert!(database::set_repo_quota(&db, &name, None));
+
+            assert!(database::set_user_quota(&db, 1, Some(used)));
+            let user = vec![get_user_limit(&db, 1).unwrap()];
+            assert!(check_quota(&controller, &get_repo_limits(&db, &name), 0).await.is_none());
+            assert!(check_quota(&controller, &user, 0).await.is_none());
+            assert_eq!(check_quota(&controller, &user, 1).await, Some((used + 1, used)));
+            assert!(database::set_user_quota(&db, 1, Some(used - 1)));
+            assert_eq!(check_quota(&controller, &get_repo_limits(&db, &name), 0).await, Some((used, used - 1)));
+
+            // A second repo of the owner with the same save, the shared blob is only charged once to the owner
+            let other = "Other".to_string();
+            database::create_repo_fast(&db, other.clone());
+            assert!(database::set_user_repo_permission(&db, 1, other.clone(), AccessType::Owner));
+            assert!(controller.write().await.create_repo(other.clone()));
+            let repo = controller.read().await.get_repo(&other).unwrap();
+            {
+                let mut repo = repo.lock().await;
+                assert!(matches!(repo.create_branch(branch.clone(), U232::new()), BranchUpdate::Ok));
+
+                let mut folder = root.clone();
+                folder.push("client");
+                assert!(matches!(commit_onto_branch(&mut repo, &folder, &branch), BranchUpdate::Ok));
+            }
+
+            let other_used = get_repo_size(&controller, &other).await.unwrap();
+            assert!(other_used > blobs);
+            assert_eq!(get_storage(&controller, &database::get_owned_repos(&db, 1)).await, used + other_used - blobs);
+        });
+
+        drop(db);
+        let _ = io::delete_folder(root.as_path());
+    }
+
+    #[test]
+    fn stale_push_conflicts() {
+        let root = get_root("stale");
+        let name = "Stale".to_string();
+        let branch = "master".to_string();
+
+        let mut controller = new_controller(&root);
+        assert!(controller.create_repo(name.clone()));
+        let repo = controller.get_repo(&name).unwrap();
+        let mut repo = repo.blocking_lock();
+        assert!(matches!(repo.create_branch(branch.clone(), U232::new()), BranchUpdate::Ok));
+
+        let mut folder = root.clone();
+        folder.push("client");
+        write_save(&folder, "first".to_string());
+        assert!(matches!(commit_onto_branch(&mut repo, &folder, &branch), BranchUpdate::Ok));
+        let base = repo.get_branch(branch.clone()).unwrap().get_previous_commit();
+
+        // Two clients start from the same commit, only the first one may move the branch
+        write_save(&folder, "second".to_string());
+        let second = repo.create_commit(Some(base), folder.as_path(), true).unwrap();
+        write_save(&folder, "third".to_string());
+        let third = repo.create_commit(Some(base), folder.as_path(), true).unwrap();
+
+        assert!(matches!(repo.push_commit_onto_branch(second, branch.clone(), false), BranchUpdate::Ok));
+        assert!(matches!(repo.push_commit_onto_branch(third, branch.clone(), false), BranchUpdate::Conflict));
+
+        repo.update_header_and_branches();
+        assert_eq!(repo.get_branch(branch.clone()).unwrap().get_previous_commit(), second);
+
+        drop(repo);
+        let _ = io::delete_folder(root.as_path());
+    }
+
+    #[test]
+    fn push_creates_missing_branch() {
+        let root = get_root("push");
+        let name = "Push".to_string();
+        let branch = "new-branch".to_string();
+
+        let mut controller = new_controller(&root);
+        assert!(controller.create_repo(name.clone()));
+        let repo = controller.get_repo(&name).unwrap();
+        let mut repo = repo.blocking_lock();
+
+        let mut folder = root.clone();
+        folder.push("client");
+        write_save(&folder, "first".to_string());
+        let commit = repo.create_commit(None, folder.as_path(), true).unwrap();
+
+        assert!(repo.get_branch(branch.clone()).is_none());
+        assert!(matches!(repo.push_commit_onto_branch(commit, branch.clone(), false), BranchUpdate::Ok));
+        assert_eq!(repo.get_branch(branch.clone()).unwrap().get_previous_commit(), commit);
+
+        drop(repo);
+        let _ = io::delete_folder(root.as_path());
+    }
+
+    #[test]
+    fn repos_lock_separately() {
+        let root = get_root("separate");
+
+        let mut controller = new_controller(&root);
+        assert!(controller.create_repo("First".to_string()));
+        assert!(controller.create_repo("Second".to_string()));
+
+        let first = controller.get_repo(&"First".to_string()).unwrap();
+        let second = controller.get_repo(&"Second".to_string()).unwrap();
+
+        let guard = first.try_lock().unwrap();
+        assert!(second.try_lock().is_ok());
+        assert!(controller.get_repo(&"First".to_string()).unwrap().try_lock().is_err());
+
+        drop(guard);
+        let _ = io::delete_folder(root.as_path());
+    }
+
+    #[test]
+    fn retention_policy_bounds() {
+        let policy = |keep_all_days, daily_days, weekly_days| RetentionPolicy { keep_all_days, daily_days, weekly_days };
+
+        assert!(is_valid_retention_policy(&policy(7, 30, Some(365))));
+        assert!(is_valid_retention_policy(&policy(7, 30, None)));
+        assert!(is_valid_retention_policy(&policy(0, 0, Some(0))));
+
+        // The spans have to be in order
+        assert!(!is_valid_retention_policy(&policy(30, 7, Some(365))));
+        assert!(!is_valid_retention_policy(&policy(7, 365, Some(30))));
+
+        // Days that would overflow once turned into seconds
+        assert!(!is_valid_retention_policy(&policy(7, 30, Some(u64::MAX))));
+        assert!(!is_valid_retention_policy(&policy(7, u64::MAX, None)));
+    }
+}
@@ -0,0 +1,257 @@
+use std::{path::{Path, PathBuf}, collections::{HashSet, HashMap}, sync::{Mutex, Arc}};
+
+use common::{U232, LargeU};
+
+use super::io;
+
+pub const BLOB_FOLDER:&str = ".blobs"; // Inside REPO_PATH
+pub const BLOB_MIN_SIZE:usize = 4 * 1024; // Smaller files are cheaper to store in the commit itself
+const REFS_EXTENSION:&str = "refs";
+
+// Repos are locked separately but all share the same folder, so changes to the refs have to go through here
+static BLOB_LOCK: Mutex<()> = Mutex::new(());
+
+// Files that are stored once for all repos, named after their content hash
+// Next to each blob is a .refs file, listing the names of the repos referencing it. Once this is empty the blob gets deleted
+// The refs are also kept per repo in memory, so sizes and releases don't have to go through the whole folder
+#[derive(Clone)]
+pub struct BlobStore {
+    folder: PathBuf,
+    index: Arc<Mutex<HashMap<String, HashMap<U232, u64>>>> // Repo name -> blob id -> blob size
+}
+
+pub fn init(root_path: &Path) -> std::io::Result<BlobStore> {
+    let mut folder = PathBuf::from(root_path);
+    folder.push(BLOB_FOLDER);
+    io::create_folder(folder.as_path())?;
+
+    let store = BlobStore { folder, index: Arc::new(Mutex::new(HashMap::new())) };
+    store.build_index();
+    Ok(store)
+}
+
+impl BlobStore {
+    pub fn get_path(&self, id: &U232) -> PathBuf {
+        let mut path = self.folder.clone();
+        path.push(common::bytes_to_hex_string(id.to_be_bytes()));
+        path
+    }
+
+    fn get_refs_path(&self, id: &U232) -> PathBuf {
+        let mut path = self.get_path(id);
+        path.set_extension(REFS_EXTENSION);
+        path
+    }
+
+    pub fn contains(&self, id: &U232) -> bool {
+        self.get_path(id).is_file()
+    }
+
+    // Stores the file under the hash (if not already present) and marks the repo as referencing it
+    pub fn add(&self, id: &U232, location: &Path, repo_name: &String) -> std::io::Result<()> {
+        let _guard = BLOB_LOCK.lock().unwrap();
+
+        let path = self.get_path(id);
+        if !path.is_file() {
+            // Copying under a different name first, so a half written blob is never used
+            let mut temp = path.clone();
+            temp.set_extension("tmp");
+            io::copy_file(location, temp.as_path())?;
+
+            if !io::hash_file(temp.as_path())?.equal_224(id) {
+                let _ = io::delete_file(temp.as_path());
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "File changed while storing it as blob"));
+            }
+            std::fs::rename(temp.as_path(), path.as_path())?;
+        }
+
+        let mut refs = self.read_refs(id);
+        if !refs.contains(repo_name) {
+            refs.push(repo_name.clone());
+            io::write_bytes(self.get_refs_path(id).as_path(), refs.join("\n").into_bytes())?;
+        }
+
+        let size = io::get_file_size(path.as_path())?;
+        self.index.lock().unwrap().entry(repo_name.clone()).or_default().insert(*id, size);
+        Ok(())
+    }
+
+    pub fn is_referenced_by(&self, id: &U232, repo_name: &String) -> bool {
+        let _guard = BLOB_LOCK.lock().unwrap();
+        self.read_refs(id).contains(repo_name)
+    }
+
+    // Ids and sizes of all blobs the repo references
+    pub fn get_referenced(&self, repo_name: &String) -> HashMap<U232, u64> {
+        self.index.lock().unwrap().get(repo_name).cloned().unwrap_or_default()
+    }
+
+    // Size of all blobs the repo references, each is charged in full to every repo using it
+    pub fn get_referenced_size(&self, repo_name: &String) -> u64 {
+        self.index.lock().unwrap().get(repo_name).map(|blobs| blobs.values().sum()).unwrap_or_default()
+    }
+
+    pub fn read(&self, id: &U232) -> std::io::Result<Vec<u8>> {
+        io::read_bytes(self.get_path(id).as_path())
+    }
+
+    fn read_refs(&self, id: &U232) -> Vec<String> {
+        if let Ok(data) = io::read_bytes(self.get_refs_path(id).as_path()) {
+            return String::from_utf8_lossy(data.as_slice()).lines().filter(|l| !l.is_empty()).map(|l| l.to_string()).collect();
+        }
+
+        Vec::<String>::new()
+    }
+
+    // Removes the repo from every blob not in keep, deleting blobs nobody references anymore
+    // Returns the number of deleted blobs
+    pub fn release_repo(&self, repo_name: &String, keep: &HashSet<U232>) -> usize {
+        let _guard = BLOB_LOCK.lock().unwrap();
+
+        let mut deleted = 0;
+        for id in self.get_referenced(repo_name).into_keys() {
+            if keep.contains(&id) {
+                continue;
+            }
+
+            if self.remove_ref(&id, repo_name) {
+                deleted = deleted + 1;
+            }
+        }
+
+        deleted
+    }
+
+    // Removes the repo from a single blob, returns true if the blob got deleted
+    pub fn release(&self, id: &U232, repo_name: &String) -> bool {
+        let _guard = BLOB_LOCK.lock().unwrap();
+        self.remove_ref(id, repo_name)
+    }
+
+    // BLOB_LOCK has to be held by the caller
+    fn remove_ref(&self, id: &U232, repo_name: &String) -> bool {
+        let mut index = self.index.lock().unwrap();
+        if let Some(blobs) = index.get_mut(repo_name) {
+            blobs.remove(id);
+            if blobs.is_empty() {
+                index.remove(repo_name);
+            }
+        }
+        drop(index);
+
+        let mut refs = self.read_refs(id);
+        if let Some(index) = refs.iter().position(|r| r == repo_name) {
+            refs.remove(index);
+
+            if refs.is_empty() {
+                let _ = io::delete_file(self.get_path(id).as_path());
+                let _ = io::delete_file(self.get_refs_path(id).as_path());
+                return true;
+            } else {
+                let _ = io::write_bytes(self.get_refs_path(id).as_path(), refs.join("\n").into_bytes());
+            }
+        }
+
+        false
+    }
+
+    // Reads the refs of every blob once, on startup
+    fn build_index(&self) {
+        let _guard = BLOB_LOCK.lock().unwrap();
+        let mut index = self.index.lock().unwrap();
+
+        for item in io::get_folder_content(self.folder.as_path()) {
+            if item.extension().and_then(|e| e.to_str()) != Some(REFS_EXTENSION) {
+                continue;
+            }
+
+            if let Some(name) = item.file_stem().and_then(|n| n.to_str()) {
+                let id = U232::from_u8arr(common::hex_string_to_bytes(&name.to_string()).as_slice());
+                let size = io::get_file_size(self.get_path(&id).as_path()).unwrap_or_default();
+                for repo_name in self.read_refs(&id) {
+                    index.entry(repo_name).or_default().insert(id, size);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, collections::HashSet};
+
+    use common::U232;
+
+    use crate::file_processing::test_util::get_root;
+    use super::{init, io};
+
+    fn write_file(root: &PathBuf, name: &str, data: &[u8]) -> (PathBuf, U232) {
+        let mut file = root.clone();
+        file.push(name);
+        io::write_bytes(file.as_path(), data.to_vec()).unwrap();
+        let id = common::hash_data(data);
+        (file, id)
+    }
+
+    #[test]
+    fn add_stores_once() {
+        let root = get_root("blob");
+        let store = init(root.as_path()).unwrap();
+        let (file, id) = write_file(&root, "save.sav", &b"blob".repeat(2048));
+        let repo = "First".to_string();
+
+        store.add(&id, file.as_path(), &repo).unwrap();
+        store.add(&id, file.as_path(), &repo).unwrap();
+        assert!(store.contains(&id));
+        assert!(store.read(&id).unwrap() == b"blob".repeat(2048));
+        assert_eq!(store.read_refs(&id), vec![repo.clone()]);
+
+        // A file that does not match the hash is not stored
+        let (other, _) = write_file(&root, "other.sav", b"other");
+        let wrong = common::hash_data(b"wrong");
+        assert!(store.add(&wrong, other.as_path(), &repo).is_err());
+        assert!(!store.contains(&wrong));
+
+        let _ = io::delete_folder(root.as_path());
+    }
+
+    #[test]
+    fn blob_lives_while_referenced() {
+        let root = get_root("blob");
+        let store = init(root.as_path()).unwrap();
+        let (file, id) = write_file(&root, "save.sav", &b"shared".repeat(1024));
+        let first = "First".to_string();
+        let second = "Second".to_string();
+
+        store.add(&id, file.as_path(), &first).unwrap();
+        store.add(&id, file.as_path(), &second).unwrap();
+        assert!(store.is_referenced_by(&id, &first) && store.is_referenced_by(&id, &second));
+        assert_eq!(store.get_referenced_size(&first), 6144);
+
+        // The index is rebuilt from the refs on startup
+        let reopened = init(root.as_path()).unwrap();
+        assert_eq!(reopened.get_referenced(&first), store.get_referenced(&first));
+        assert_eq!(reopened.get_referenced_size(&second), 6144);
+
+        // Kept blobs are not touched
+        let mut keep = HashSet::<U232>::new();
+        keep.insert(id);
+        assert_eq!(store.release_repo(&first, &keep), 0);
+        assert!(store.is_referenced_by(&id, &first));
+
+        assert_eq!(store.release_repo(&first, &HashSet::<U232>::new()), 0);
+        assert!(store.contains(&id));
+        assert!(!store.is_referenced_by(&id, &first));
+        assert_eq!(store.get_referenced_size(&first), 0);
+
+        // Releasing a repo that does not reference it changes nothing
+        assert!(!store.release(&id, &first));
+        assert!(store.contains(&id));
+
+        assert!(store.release(&id, &second));
+        assert!(!store.contains(&id));
+        assert!(store.read_refs(&id).is_empty());
+
+        let _ = io::delete_folder(root.as_path());
+    }
+}
@@ -1,10 +1,14 @@
 use std::path::PathBuf;
 use std::{io, path::Path};
 use std::io::prelude::*;
+use std::io::SeekFrom;
 use std::fs::{File, self};
-use common::U232;
+use common::{U232, LargeU};
+use sha3::Digest;
 
-// If this is compiled in 32bit then we would be restricted to 2gb files
+pub const CHUNK_SIZE:usize = 1024 * 1024; // Bytes read at once when streaming files
+
+// If this is compiled in 32bit then we would be restricted to 2gb files, use read_chunk for large files
 pub fn read_bytes(file_name: &Path) -> io::Result<Vec<u8>> {
     let mut list = Vec::<u8>::new(); 
 
@@ -143,18 +147,47 @@ fn move_folder_int(from: &Path, to: &Path, og_to: &Path) -> io::Result<()> {
     }
 }
 
+pub fn delete_file(file_path: &Path) -> io::Result<()> {
+    fs::remove_file(file_path)
+}
+
 pub fn copy_file(from: &Path, to: &Path) -> io::Result<u64> {
     fs::copy(from, to)
 }
 
+// Same result as common::hash_data, but reads the file in chunks
 pub fn hash_file(file_name: &Path) -> io::Result<U232> {
-    let res = read_bytes(file_name);
-
-    if let Ok(bytes) = res {
-        return Ok(common::hash_data(&bytes[..]));
+    let mut f = File::open(file_name)?;
+    let mut hasher = sha3::Sha3_224::new();
+
+    let mut buffer = vec![0_u8; CHUNK_SIZE];
+    loop {
+        let read = f.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
     }
 
-    Err(res.unwrap_err())
+    Ok(U232::from_u8arr(hasher.finalize().as_slice()))
+}
+
+pub fn get_file_size(file_name: &Path) -> io::Result<u64> {
+    fs::metadata(file_name).map(|meta| meta.len())
+}
+
+// Reads up to len bytes from offset, less if the file ends before that
+pub fn read_chunk(file: &mut File, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+    file.seek(SeekFrom::Start(offset))?;
+
+    let mut data = Vec::<u8>::with_capacity(len);
+    Read::by_ref(file).take(len.try_into().unwrap_or_default()).read_to_end(&mut data)?;
+    Ok(data)
+}
+
+pub fn write_chunk(file: &mut File, offset: u64, data: &[u8]) -> io::Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(data)
 }
 
 // reverse is easily done with value.to_be_bytes();
@@ -1,9 +1,14 @@
-use std::path::{Path, PathBuf};
+use std::{path::{Path, PathBuf}, fs::File, io::{Read, BufReader}};
 
-use common::{U232, LargeU};
+use common::{U232, LargeU, data::Compression};
 
 use super::io;
 
+pub const VERSION_RAW:u8 = 0x00;
+pub const VERSION_ZSTD:u8 = 0x01; // Everything after the type byte is zstd compressed
+const ZSTD_LEVEL:i32 = 3;
+pub const MAX_CACHED_SIZE:usize = io::CHUNK_SIZE; // Commits decoding to more then this leave their edit on disk, it is streamed when run
+
 #[derive(Clone)]
 pub struct RepoFile {
     version: u8,
@@ -19,12 +24,14 @@ pub enum RepoFileType {
     BranchHead,
     Edit(Vec<Instruction>, usize), // usize is the pointer size
     EditNotProcessed(Vec<u8>),
+    EditOnDisk(PathBuf, usize), // Edit too large to hold, the instructions start at this offset of the decoded file
     NewFile,
     Resize(u64),
     Delete,
     Rename(String),
     NewFolder(String),
     Folder(Vec<U232>),
+    Blob(U232), // Content of a New File is in the shared blob store
     CommitInfo(CommitInfo),
     None
 }
@@ -56,7 +63,8 @@ pub enum Operation {
     Replace(Vec<u8>),
     Blank,
     SetTo(u8),
-    Copy(usize)
+    Copy(usize), // byte by byte from the front, so an overlapping source repeats what was just written
+    Move(usize) // the whole source is read before writing, so source and target may overlap
 }
 
 pub trait Writtable {
@@ -117,7 +125,11 @@ impl RepoFile {
             let hash = common::hash_data(data.as_slice());
 
             if hash != self.repo_file_hash { // file has changed, lets update
-                let mut other = decode_repo_file(data, file.file_name().unwrap().to_str().unwrap().to_string());
+                let mut other = if let Ok(other) = decode_repo_file(data, file.file_name().unwrap().to_str().unwrap().to_string()) {
+                    other
+                } else {
+                    return false; // we keep what we have, same as if the file could not be read
+                };
 
                 //Processing Edit, if possible
                 if let Ok(pointer_size) = other.get_pointer_size()  {
@@ -144,7 +156,15 @@ impl RepoFile {
         file.push(&self.name);
 
         // We check if anything changed
-        let data = self.to_bytes();
+        let on_disk = matches!(self.get_type(0x02), RepoFileType::EditOnDisk(_, _));
+        let data = if on_disk {
+            match self.load_edit() {
+                Ok(loaded) => loaded.to_bytes(),
+                Err(e) => return WritingStates::Err(e)
+            }
+        } else {
+            self.to_bytes()
+        };
         let new_hash = common::hash_data(data.as_slice());
         if self.repo_file_hash == new_hash {
             return WritingStates::NotNecessary;
@@ -152,13 +172,14 @@ impl RepoFile {
 
         // We check if the file changed since last pull
         if file.exists() {
-            let res = io::read_bytes(file.as_path());
-            if let Ok(file_data) = res {
-                let file_hash = common::hash_data(file_data.as_slice());
-                
+            let res = io::hash_file(file.as_path());
+            if let Ok(file_hash) = res {
                 if new_hash == file_hash {
                     // In case we have written the file already, but not updated since
                     self.repo_file_hash = new_hash;
+                    if on_disk {
+                        self.reread_edit_offset(file.as_path());
+                    }
                     return WritingStates::NotNecessary;
                 }
 
@@ -184,67 +205,67 @@ impl RepoFile {
         }
 
         self.repo_file_hash = new_hash;
+        if on_disk {
+            self.reread_edit_offset(file.as_path());
+        }
         WritingStates::Ok
     }
 
+    // The edit moves when the parts before it change, so we read where it starts now
+    fn reread_edit_offset(&mut self, file: &Path) {
+        if let Ok(other) = read_repo_file(file) {
+            if let RepoFileType::EditOnDisk(_, _) = other.get_type(0x02) {
+                self.content = other.content;
+            }
+        }
+    }
+
     pub fn parse_edit_instructions(&mut self, pointer_size: usize) {
         let end = self.content.len() - 1;
 
         // Edit is the final content piece, so we do a if let on it to get the data
         if let RepoFileType::EditNotProcessed(data) = &self.content[end] {
-            let data = data.as_slice(); // turning the vector reference into a array
-            let mut offset = 0;
-
-            let mut list = Vec::<Instruction>::new();
-
-            // Parsing the individual instructions
-            while offset < data.len() {
-                let typ = data[offset];
-                offset = offset + 1;
-
-                let pointer = io::u64_to_usize(io::get_u64(io::save_cut(io::save_slice(data, offset), pointer_size)));
-                offset = offset + pointer_size;
-
-                let (area, num_bytes) = io::get_utf8_value(io::save_slice(data, offset));
-                offset = offset + num_bytes;
-                let area = io::u64_to_usize(area);
-
-                let operation = match typ {
-                    0x01 => { // Replace
-                        let re = io::save_cut(io::save_slice(data, offset),area).to_vec();
-                        offset = offset + area;
-                        Operation::Replace(re)
-                    },
-                    0x02 => Operation::Blank, // Blank
-                    0x03 => { // Set To
-                        let mut val = 0x00_u8;
-                        if offset < data.len() {
-                            val = data[offset];
-                            offset = offset + 1;
-                        }
-
-                        Operation::SetTo(val)
-                    },
-                    0x04 => { // Copy From
-                        let mut pointer = 0;
-                        if (offset + pointer_size) < data.len() {
-                            pointer = io::u64_to_usize(io::get_u64(io::save_cut(io::save_slice(data, offset),pointer_size)));
-                            offset = offset + pointer_size;
-                        }
-
-                        Operation::Copy(pointer)
-                    },
-                    _ => Operation::None
-                };
+            // Parsing the individual instructions, a broken instruction ends the list
+            let list = InstructionReader::new(data.as_slice(), pointer_size, usize::MAX).map_while(|ins| ins.ok()).collect();
 
-                list.push(Instruction {
-                    pointer,
-                    num_bytes: area,
-                    operation
-                });
+            self.content[end] = RepoFileType::Edit(list, pointer_size);
+        }
+    }
+
+    // Streams the instructions of an edit that stayed on disk, Replace payloads are split into chunks
+    pub fn read_edit_instructions(& self, pointer_size: usize) -> std::io::Result<InstructionReader<Box<dyn Read>>> {
+        if let RepoFileType::EditOnDisk(path, offset) = self.get_type(0x02) {
+            let mut reader = open_decoded(path.as_path())?;
+            std::io::copy(&mut Read::by_ref(&mut reader).take(offset.clone().try_into().unwrap_or_default()), &mut std::io::sink())?;
+
+            return Ok(InstructionReader::new(reader, pointer_size, io::CHUNK_SIZE));
+        }
+
+        Err(std::io::Error::new(std::io::ErrorKind::NotFound, "Commit has no edit on disk"))
+    }
+
+    // Returns a copy with the edit loaded from disk, so it can be written out again
+    fn load_edit(& self) -> std::io::Result<RepoFile> {
+        let mut loaded = self.clone();
+        for item in loaded.content.iter_mut() {
+            if let RepoFileType::EditOnDisk(path, offset) = item {
+                let mut reader = open_decoded(path.as_path())?;
+                let mut data = Vec::<u8>::new();
+                reader.read_to_end(&mut data)?;
+
+                *item = RepoFileType::EditNotProcessed(io::save_slice(&data, offset.clone()).to_vec());
             }
+        }
 
-            self.content[end] = RepoFileType::Edit(list, pointer_size);
+        Ok(loaded)
+    }
+
+    // Bytes of file content held in memory by the edit
+    pub fn get_payload_size(& self) -> usize {
+        match self.get_type(0x02) {
+            RepoFileType::Edit(instructions, _) => instructions.iter().map(|ins| if let Operation::Replace(bytes) = &ins.operation { bytes.len() } else { 0 }).sum(),
+            RepoFileType::EditNotProcessed(data) => data.len(),
+            _ => 0
         }
     }
 
@@ -264,9 +285,15 @@ impl RepoFile {
                 RepoFileType::EditNotProcessed(_d) =>  if typ == 0x02 {
                     return element;
                 },
+                RepoFileType::EditOnDisk(_p, _o) =>  if typ == 0x02 {
+                    return element;
+                },
                 RepoFileType::NewFile => if typ == 0x03 {
                     return element;
                 },
+                RepoFileType::Blob(_d) => if typ == 0x07 {
+                    return element;
+                },
                 RepoFileType::Rename(_d) => if typ == 0x04 {
                     return element;
                 },
@@ -294,6 +321,17 @@ impl RepoFile {
         &RepoFileType::None
     }
 
+    // New File and New Folder start a new chain of instructions (and of commit info time stamps)
+    pub fn is_new(& self) -> bool {
+        if let RepoFileType::NewFile = self.get_type(0x03) {
+            return true;
+        } else if let RepoFileType::NewFolder(_) = self.get_type(0x0D) {
+            return true;
+        }
+
+        false
+    }
+
     pub fn get_content<'a>(&'a self) -> &'a Vec<RepoFileType> {
         &self.content
     }
@@ -301,7 +339,7 @@ impl RepoFile {
     // Returns the pointer size, or the previous commit which may contain it
     pub fn get_pointer_size(& self) -> Result<usize, U232> {
         if let RepoFileType::Resize(val) = self.get_type(0x08) {
-            let bits = val.ilog2(); // technically this is one bit short
+            let bits = val.checked_ilog2().unwrap_or_default(); // technically this is one bit short, and empty files still need 1 byte
             let bytes = bits / 8 + 1; // but this means it handles the rounding
                                       // for example 16bits is log2=15 is 15/8 = 1 + 1 = 2
                                       // 17 bits is log2=16 is 16/8 = 2 + 1 = 3
@@ -322,6 +360,26 @@ impl RepoFile {
 
 impl Writtable for RepoFile {
     fn to_bytes(& self) -> Vec<u8> {
+        let mut data = self.to_raw_bytes();
+
+        if self.version == VERSION_ZSTD {
+            // Small files can get larger when compressed, those we store raw
+            if let Ok(mut compressed) = zstd::bulk::compress(&data[2..], ZSTD_LEVEL) {
+                if compressed.len() < data.len() - 2 {
+                    data.truncate(2);
+                    data.append(&mut compressed);
+                    return data;
+                }
+            }
+            data[0] = VERSION_RAW;
+        }
+
+        data
+    }
+}
+
+impl RepoFile {
+    fn to_raw_bytes(& self) -> Vec<u8> {
         let mut data = Vec::<u8>::new();
         data.push(self.version);
         data.push(0x00); // Type
@@ -356,8 +414,13 @@ impl Writtable for RepoFile {
 
         // Handles the different new instructions
         if let RepoFileType::NewFile = self.get_type(0x03) {
-            // Branch Head
-            data[1] = data[1] + 0x03;
+            if let RepoFileType::Blob(_id) = self.get_type(0x07) {
+                // New File from Blob
+                data[1] = data[1] + 0x07;
+            } else {
+                // New File
+                data[1] = data[1] + 0x03;
+            }
         } else if let RepoFileType::NewFolder(name) = &self.get_type(0x0D) {
             // New Folder
             data[1] = data[1] + 0x0D;
@@ -419,6 +482,12 @@ impl Writtable for RepoFile {
 
             data.append(&mut dat.clone());
         }
+        // An EditOnDisk is not written here, write_file_back loads it first
+
+        if let RepoFileType::Blob(id) = self.get_type(0x07) {
+            // Blob
+            data.append(&mut id.to_be_bytes().to_vec());
+        }
 
         data
     }
@@ -489,7 +558,15 @@ impl Instruction {
         }
     }
 
-    fn to_bytes(& self, pointer_size: usize) -> Vec<u8> {
+    // Shifts the instruction (and the source of a copy) further into the file
+    pub fn move_by(&mut self, offset: usize) {
+        self.pointer = self.pointer + offset;
+        if let Operation::Copy(other_pointer) | Operation::Move(other_pointer) = &mut self.operation {
+            *other_pointer = *other_pointer + offset;
+        }
+    }
+
+    pub fn to_bytes(& self, pointer_size: usize) -> Vec<u8> {
         fn resize_output(num: usize, pointer_size: usize) -> Vec<u8> {
             let bytes = num.to_be_bytes();
             bytes[bytes.len() - pointer_size..].to_vec()
@@ -517,6 +594,10 @@ impl Instruction {
             //Copy
             data.append(&mut resize_output(other_pointer.clone(), pointer_size));
             data[0] = 0x04;
+        } else if let Operation::Move(other_pointer) = &self.operation {
+            //Move
+            data.append(&mut resize_output(other_pointer.clone(), pointer_size));
+            data[0] = 0x05;
         } else {
             return Vec::<u8>::new(); // we delete this instruction
         }
@@ -555,7 +636,7 @@ impl Instruction {
                 data[self.pointer + index] = byte;
                 index = index + 1;
             }
-        } else if let Operation::Copy(other_pointer) = &self.operation {
+        } else if let Operation::Copy(other_pointer) | Operation::Move(other_pointer) = &self.operation {
             let other_pointer = other_pointer.clone();
             if other_pointer >= data.len() {
                 return; // We can't copy something that is out of bounds
@@ -567,42 +648,252 @@ impl Instruction {
                 num_bytes
             };
 
-            let mut index = 0;
+            if let Operation::Move(_) = &self.operation {
+                // Source and target can overlap, so the source is read completely first
+                let source = data[other_pointer..other_pointer + num_bytes].to_vec();
+                data[self.pointer..self.pointer + num_bytes].copy_from_slice(&source);
+            } else {
+                let mut index = 0;
+
+                while index < num_bytes {
+                    data[self.pointer + index] = data[other_pointer + index].clone();
+                    index = index + 1;
+                }
+            }
+        }
+
+
+    }
+
+    // Same as run_instruction, but on a file of file_size bytes, only holding a chunk in memory at a time
+    pub fn run_instruction_on_file(& self, file: &mut File, file_size: usize) -> std::io::Result<()> {
+        if self.pointer >= file_size {
+            return Ok(()); //pointer is out of bounds, we exit
+        }
+
+        // In case the edit area is out of bounds
+        let num_bytes = if self.pointer + self.num_bytes > file_size {
+            file_size - self.pointer
+        } else {
+            self.num_bytes
+        };
+
+        let to_u64 = |val: usize| -> u64 { val.try_into().unwrap_or_default() };
 
+        if let Operation::Replace(bytes) = &self.operation {
+            io::write_chunk(file, to_u64(self.pointer), &bytes[..num_bytes])?;
+        } else if let Operation::Blank | Operation::SetTo(_) = &self.operation {
+            let byte = if let Operation::SetTo(byte) = &self.operation { byte.clone() } else { 0x00 };
+            let buffer = vec![byte; if num_bytes > io::CHUNK_SIZE { io::CHUNK_SIZE } else { num_bytes }];
+
+            let mut index = 0;
             while index < num_bytes {
-                data[self.pointer + index] = data[other_pointer + index].clone();
-                index = index + 1;
+                let len = if num_bytes - index > buffer.len() { buffer.len() } else { num_bytes - index };
+                io::write_chunk(file, to_u64(self.pointer + index), &buffer[..len])?;
+                index = index + len;
+            }
+        } else if let Operation::Copy(other_pointer) | Operation::Move(other_pointer) = &self.operation {
+            let other_pointer = other_pointer.clone();
+            if other_pointer >= file_size {
+                return Ok(()); // We can't copy something that is out of bounds
+            }
+            let num_bytes = if other_pointer + num_bytes > file_size {
+                file_size - other_pointer
+            } else {
+                num_bytes
+            };
+
+            // A move to the back has to start at the end, else we read what we just wrote
+            // A copy to the back does read what it just wrote, so chunks may not be longer then the distance
+            let is_move = matches!(self.operation, Operation::Move(_));
+            let backwards = is_move && self.pointer > other_pointer;
+            let max_len = if !is_move && self.pointer > other_pointer { io::CHUNK_SIZE.min(self.pointer - other_pointer) } else { io::CHUNK_SIZE };
+            let mut done = 0;
+            while done < num_bytes {
+                let len = if num_bytes - done > max_len { max_len } else { num_bytes - done };
+                let offset = if backwards { num_bytes - done - len } else { done };
+
+                let chunk = io::read_chunk(file, to_u64(other_pointer + offset), len)?;
+                io::write_chunk(file, to_u64(self.pointer + offset), &chunk)?;
+                done = done + len;
             }
         }
 
+        Ok(())
+    }
+}
+
+// Parses edit instructions from a reader, without holding more then one instruction in memory
+// Replace payloads longer then max_replace are returned as multiple Replace instructions
+pub struct InstructionReader<R: Read> {
+    reader: R,
+    pointer_size: usize,
+    max_replace: usize,
+    pending: Option<(usize, usize)>, // pointer and remaining bytes of a split Replace
+    failed: bool
+}
 
+impl<R: Read> InstructionReader<R> {
+    pub fn new(reader: R, pointer_size: usize, max_replace: usize) -> InstructionReader<R> {
+        InstructionReader { reader, pointer_size, max_replace, pending: None, failed: false }
+    }
+
+    fn read_bytes(&mut self, len: usize) -> std::io::Result<Vec<u8>> {
+        let mut data = vec![0_u8; len];
+        self.reader.read_exact(&mut data)?;
+        Ok(data)
+    }
+
+    fn read_pointer(&mut self) -> std::io::Result<usize> {
+        let data = self.read_bytes(self.pointer_size)?;
+        Ok(io::u64_to_usize(io::get_u64(data.as_slice())))
+    }
+
+    // The first byte tells us how many follow
+    fn read_utf8_value(&mut self) -> std::io::Result<usize> {
+        let mut data = self.read_bytes(1)?;
+        let number_of_bytes:usize = data[0].leading_ones().try_into().unwrap_or_default();
+        if number_of_bytes > 1 {
+            data.append(&mut self.read_bytes(number_of_bytes - 1)?);
+        }
+
+        Ok(io::u64_to_usize(io::get_utf8_value(data.as_slice()).0))
+    }
+
+    fn read_replace(&mut self, pointer: usize, remaining: usize) -> std::io::Result<Instruction> {
+        let len = remaining.min(self.max_replace);
+        let bytes = self.read_bytes(len)?;
+        if len < remaining {
+            self.pending = Some((pointer + len, remaining - len));
+        }
+
+        Ok(Instruction { pointer, num_bytes: len, operation: Operation::Replace(bytes) })
+    }
+
+    fn read_instruction(&mut self) -> std::io::Result<Option<Instruction>> {
+        if let Some((pointer, remaining)) = self.pending.take() {
+            return self.read_replace(pointer, remaining).map(Some);
+        }
+
+        let mut typ = [0_u8; 1];
+        if self.reader.read(&mut typ)? == 0 {
+            return Ok(None); // No more instructions
+        }
+
+        let pointer = self.read_pointer()?;
+        let area = self.read_utf8_value()?;
+
+        let operation = match typ[0] {
+            0x01 => return self.read_replace(pointer, area).map(Some), // Replace
+            0x02 => Operation::Blank, // Blank
+            0x03 => Operation::SetTo(self.read_bytes(1)?[0]), // Set To
+            0x04 => Operation::Copy(self.read_pointer()?), // Copy From
+            0x05 => Operation::Move(self.read_pointer()?), // Move From
+            _ => Operation::None
+        };
+
+        Ok(Some(Instruction { pointer, num_bytes: area, operation }))
     }
 }
 
+impl<R: Read> Iterator for InstructionReader<R> {
+    type Item = std::io::Result<Instruction>;
+
+    // After an error no further instructions are returned
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+
+        let res = self.read_instruction();
+        if res.is_err() {
+            self.failed = true;
+        }
+        res.transpose()
+    }
+}
+
+// The version new commit files are written with
+pub fn get_version(compression: &Compression) -> u8 {
+    match compression {
+        Compression::None => VERSION_RAW,
+        Compression::Zstd => VERSION_ZSTD
+    }
+}
+
+// Commits that decode to more then MAX_CACHED_SIZE keep their edit on disk, everything else is read in whole
 pub fn read_repo_file(file: &Path) -> std::io::Result<RepoFile> {
-    let res = io::read_bytes(file);
-    if let Ok(data) = res {
-        return Ok(decode_repo_file(data, file.file_name().unwrap().to_str().unwrap().to_string()));
-    } else if let Err(e) = res {
-        return Err(e)
+    let name = file.file_name().unwrap().to_str().unwrap().to_string();
+
+    let mut reader = open_decoded(file)?;
+    let mut data = Vec::<u8>::new();
+    Read::by_ref(&mut reader).take((MAX_CACHED_SIZE + 1).try_into().unwrap_or_default()).read_to_end(&mut data)?;
+
+    if data.len() <= MAX_CACHED_SIZE {
+        return parse_repo_file(data, name, io::hash_file(file)?);
+    }
+
+    // The edit comes last, so the start of the file holds everything else
+    let read = data.len();
+    let mut repo_file = parse_repo_file(data, name, io::hash_file(file)?)?;
+    let last = repo_file.content.len().saturating_sub(1);
+    if let Some(RepoFileType::EditNotProcessed(start)) = repo_file.content.get(last) {
+        let offset = read - start.len();
+        repo_file.content[last] = RepoFileType::EditOnDisk(file.to_path_buf(), offset);
+        return Ok(repo_file);
     }
 
+    // No edit (like a folder with a lot of files), so we need all of it
+    let mut data = Vec::<u8>::new();
+    open_decoded(file)?.read_to_end(&mut data)?;
+    parse_repo_file(data, repo_file.name, repo_file.repo_file_hash)
+}
+
+// Reads the file with the body decompressed, so it looks like a raw file (apart from the version byte)
+fn open_decoded(file: &Path) -> std::io::Result<Box<dyn Read>> {
+    let mut reader = BufReader::new(File::open(file)?);
+    let mut head = [0_u8; 2];
+    reader.read_exact(&mut head).map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidData, "Repo file is missing version and type"))?;
 
+    let body: Box<dyn Read> = if head[0] == VERSION_ZSTD {
+        Box::new(zstd::stream::read::Decoder::with_buffer(reader)?)
+    } else {
+        Box::new(reader)
+    };
 
-    Err(std::io::Error::new(
-        std::io::ErrorKind::Other,
-        "Failed to read File",
-    ))
+    Ok(Box::new(std::io::Cursor::new(head).chain(body)))
 }
 
 // This reads the repo file and processes it
-pub fn decode_repo_file(data: Vec<u8>, file_name: String) -> RepoFile {
+pub fn decode_repo_file(mut data: Vec<u8>, file_name: String) -> std::io::Result<RepoFile> {
+    if data.len() < 2 {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Repo file is missing version and type"));
+    }
+
+    let hash = common::hash_data(data.as_slice()); // hash of the file as it is on disk
+
+    if data[0] == VERSION_ZSTD {
+        // Files that do not get smaller are written raw, so a compressed file that fails to decode is broken
+        let mut body = zstd::decode_all(&data[2..])?;
+        data.truncate(2);
+        data.append(&mut body);
+    }
+
+    parse_repo_file(data, file_name, hash)
+}
+
+// Same as decode_repo_file, but the body is already decompressed
+fn parse_repo_file(data: Vec<u8>, file_name: String, hash: U232) -> std::io::Result<RepoFile> {
+    if data.len() < 2 {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Repo file is missing version and type"));
+    }
+
     let mut repo_file = RepoFile {
         version: data[0],
         name: file_name,
         content: Vec::<RepoFileType>::new(),
         previous_commit: U232::new(),
-        repo_file_hash: common::hash_data(data.as_slice()),
+        repo_file_hash: hash,
     };
 
     let mut typ = data[1];
@@ -634,7 +925,7 @@ pub fn decode_repo_file(data: Vec<u8>, file_name: String) -> RepoFile {
         }
 
         repo_file.content.push(RepoFileType::Head(head));
-        return repo_file; // No further data
+        return Ok(repo_file); // No further data
     }
 
     repo_file.previous_commit = U232::from_u8arr(io::save_slice(&data, offset));
@@ -643,7 +934,7 @@ pub fn decode_repo_file(data: Vec<u8>, file_name: String) -> RepoFile {
     if typ == 0x01 {
         // Branch Head
         repo_file.content.push(RepoFileType::BranchHead);
-        return repo_file; //No further data
+        return Ok(repo_file); //No further data
     }
 
     if (typ % 0x20) / 0x10 == 1 {
@@ -672,10 +963,17 @@ pub fn decode_repo_file(data: Vec<u8>, file_name: String) -> RepoFile {
         typ = 0x02 + 0x04 + 0x08;
     }
 
+    let blob = typ == 0x07;
+    if blob {
+        // New File from Blob, same as New File, but instead of the Edit there is the hash of the blob
+        repo_file.content.push(RepoFileType::NewFile);
+        typ = 0x04 + 0x08;
+    }
+
     if typ == 0x05 {
         // Delete
         repo_file.content.push(RepoFileType::Delete);
-        return repo_file; //No further data
+        return Ok(repo_file); //No further data
     }
 
     if typ == 0x0D {
@@ -697,7 +995,7 @@ pub fn decode_repo_file(data: Vec<u8>, file_name: String) -> RepoFile {
         }
         repo_file.content.push(RepoFileType::Folder(files));
 
-        return repo_file; //Nothing more to add
+        return Ok(repo_file); //Nothing more to add
     }
 
     if typ / 0x08 == 1 {
@@ -718,6 +1016,12 @@ pub fn decode_repo_file(data: Vec<u8>, file_name: String) -> RepoFile {
     }
     typ = typ % 0x04;
 
+    if blob {
+        // Blob
+        repo_file.content.push(RepoFileType::Blob(U232::from_u8arr(io::save_slice(&data, offset))));
+        return Ok(repo_file);
+    }
+
     if typ / 0x02 == 1 {
         // Edit
         // We can't process Edit instructions without knowing the pointer size, which we only find out when we know the file size
@@ -728,5 +1032,5 @@ pub fn decode_repo_file(data: Vec<u8>, file_name: String) -> RepoFile {
     }
     //typ = typ % 0x02;
 
-    repo_file
+    Ok(repo_file)
 }
\ No newline at end of file
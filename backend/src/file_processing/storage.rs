@@ -1,8 +1,15 @@
-use std::{path::{Path, PathBuf}, collections::HashMap, sync::{Mutex, MutexGuard}};
-use super::{io, repository_file::{self, RepoFileType, RepoFile, Head, CommitInfo}};
-use common::{U232,LargeU};
+use std::{path::{Path, PathBuf}, collections::{HashMap, HashSet}, sync::{Mutex, MutexGuard}, fs::File};
+use super::{io, blob_store::{self, BlobStore}, repository_file::{self, RepoFileType, RepoFile, Head, CommitInfo, WritingStates, Instruction}};
+use common::{U232,LargeU, data::{Compression, CommitType, TreeEntry, CommitDiff, GarbageReport, VerifyReport, RetentionPolicy, RetentionReport}};
 
 mod commit_generation;
+mod diff;
+mod maintenance;
+mod retention;
+
+const DEFAULT_SNAPSHOT_DEPTH:usize = 64;
+const DEFAULT_SNAPSHOT_SIZE:u64 = 1024 * 1024; // 1 MiB
+const LARGE_FILE_SIZE:usize = 64 * 1024 * 1024; // Files larger then this are processed in chunks, instead of in memory
 
 pub fn read_storage_info(folder: &Path) -> std::io::Result<StorageRepo>{
     let mut file = PathBuf::from(folder);
@@ -16,7 +23,13 @@ pub fn read_storage_info(folder: &Path) -> std::io::Result<StorageRepo>{
                 folder: folder.as_os_str().to_str().unwrap().to_string(),
                 header:head_file,
                 branches: Vec::<RepoFile>::new(),
-                commits: HashMap::<U232, Mutex<RepoFile>>::new()
+                commits: HashMap::<U232, Mutex<RepoFile>>::new(),
+                snapshot_policy: SnapshotPolicy::default(),
+                compression: Compression::default(),
+                retention: None,
+                blob_store: None,
+                temp_folder: None,
+                full_copies: Vec::<PathBuf>::new()
             };
 
             repo.read_branches(&head_info);
@@ -65,16 +78,65 @@ pub fn new_repo(folder: &Path, name: String) -> std::io::Result<StorageRepo> {
         folder: folder.to_str().unwrap().to_string(),
         header: header_repo_file,
         branches: Vec::<RepoFile>::new(),
-        commits: HashMap::<U232, Mutex<RepoFile>>::new()
+        commits: HashMap::<U232, Mutex<RepoFile>>::new(),
+        snapshot_policy: SnapshotPolicy::default(),
+        compression: Compression::default(),
+        retention: None,
+        blob_store: None,
+        temp_folder: None,
+        full_copies: Vec::<PathBuf>::new()
     })
 
 }
 
+pub enum BranchUpdate {
+    Ok,
+    Conflict,
+    NotFound,
+    Err
+}
+
+pub struct LogEntry {
+    pub id: U232,
+    pub previous_commit: U232,
+    pub commit_type: CommitType,
+    pub info: Option<CommitInfo>,
+    pub timestamp: Option<u64> // absolute, if it could be reconstructed
+}
+
+// When a file commit chain gets too long we write a full copy of the file (a NewFile that still links to the previous commit),
+// so building a file never has to replay more then these limits. A value of 0 disables the limit
+#[derive(Debug, Clone, PartialEq)]
+pub struct SnapshotPolicy {
+    pub max_depth: usize, // number of commits since the last NewFile
+    pub max_size: u64 // combined size of these commits in bytes
+}
+
+impl Default for SnapshotPolicy {
+    fn default() -> Self {
+        SnapshotPolicy { max_depth: DEFAULT_SNAPSHOT_DEPTH, max_size: DEFAULT_SNAPSHOT_SIZE }
+    }
+}
+
+#[derive(Debug)]
+pub enum CommitError {
+    NothingToCommit, // the location does not exist, and there is no previous commit to delete
+    PreviousNotFound, // the previous commit or it's history could not be read
+    Io(std::io::Error), // reading the files to commit failed
+    InstructionMismatch // the generated instructions do not reproduce the file
+}
+
 pub struct StorageRepo {
     folder: String,
     header: RepoFile,
     branches: Vec<RepoFile>,
-    commits: HashMap<U232, Mutex<RepoFile>>
+    commits: HashMap<U232, Mutex<RepoFile>>,
+    snapshot_policy: SnapshotPolicy,
+    compression: Compression, // Only applies to new commits, old ones keep their version
+    retention: Option<RetentionPolicy>,
+    blob_store: Option<BlobStore>,
+    temp_folder: Option<PathBuf>, // Root for building large files, set by the RepoController
+    full_copies: Vec<PathBuf>
 }
 
 impl StorageRepo {
@@ -131,19 +193,13 @@ impl StorageRepo {
         ))
     }
 
-    pub fn get_commit_info(&mut self, commit_id: U232) -> Option<CommitInfo> {
-        let history = self.get_commit_chain(commit_id);
-        if history.is_empty() {
-            return None;
-        }
-
+    // Time stamps are relative to the previous commit info, except on new files/folders, where they are the unix time
+    fn accumulate_timestamp(history: &[&Mutex<RepoFile>]) -> u64 {
         let mut time = 0;
         for item in history.iter().rev() {
             let loc = item.lock().unwrap();
             if let RepoFileType::CommitInfo(info) = loc.get_type(0x10) {
-                if let RepoFileType::NewFile = loc.get_type(0x03) {
-                    time = 0;
-                } else if let RepoFileType::NewFolder(_) = loc.get_type(0x0D) {
+                if loc.is_new() {
                     time = 0;
                 }
 
@@ -152,6 +208,17 @@ impl StorageRepo {
             drop(loc);
         }
 
+        time
+    }
+
+    pub fn get_commit_info(&mut self, commit_id: U232) -> Option<CommitInfo> {
+        let history = self.get_commit_chain(commit_id);
+        if history.is_empty() {
+            return None;
+        }
+
+        let time = StorageRepo::accumulate_timestamp(&history);
+
         let item = history[0].lock().unwrap();
         if let RepoFileType::CommitInfo(info) = item.get_type(0x10) {
             let i = CommitInfo::new(info.get_user(), info.get_device(), info.get_text(), time);
@@ -169,24 +236,17 @@ impl StorageRepo {
             return false;
         }
 
-        let item = history[0].clone(); // TODO make sure setting commit info actually works and doesn't update the first one
-
-        let mut time = 0;
-        for item in history.iter().rev() {
-            let loc = item.lock().unwrap();
-            if let RepoFileType::CommitInfo(info) = loc.get_type(0x10) {
-                if let RepoFileType::NewFile = loc.get_type(0x03) {
-                    time = 0;
-                } else if let RepoFileType::NewFolder(_) = loc.get_type(0x0D) {
-                    time = 0;
-                }
+        let item = history[0];
 
-                time += info.get_timestamp();
-            }
-            drop(loc);
-        }
+        // The commit itself is not included, as it might contain an old commit info
+        let is_new = item.lock().unwrap().is_new();
+        let time = if is_new {
+            0 // New files/folders store the unix time
+        } else {
+            StorageRepo::accumulate_timestamp(&history[1..])
+        };
 
-        time = info.get_timestamp().saturating_sub(time); // In case the time is lower we assume set it to be the same as the previous commit
+        let time = info.get_timestamp().saturating_sub(time); // In case the time is lower we assume set it to be the same as the previous commit
 
         let info = CommitInfo::new(info.get_user(), info.get_device(), info.get_text(), time);
 
@@ -216,16 +276,88 @@ impl StorageRepo {
         true
     }
 
+    // Walks the history of the commit, returning up to limit entries and the commit the next page starts with
+    // Absolute time stamps require the walk to reach the start of the history, so it continues for up to depth commits
+    pub fn get_commit_log(&mut self, commit: U232, limit: usize, depth: usize) -> (Vec<LogEntry>, Option<U232>) {
+        let depth = if depth < limit { limit } else { depth };
+
+        let mut list = Vec::<(LogEntry, bool)>::new();
+        let mut index = commit;
+        while list.len() < depth {
+            let res = if let Ok(res) = self.get_commit(index) {
+                res
+            } else {
+                break;
+            };
+            let file = res.lock().unwrap();
+
+            let commit_type = if let RepoFileType::Delete = file.get_type(0x05) {
+                CommitType::Delete
+            } else if let RepoFileType::Folder(_) = file.get_type(0x0F) {
+                CommitType::Folder
+            } else {
+                CommitType::File
+            };
+            let info = if let RepoFileType::CommitInfo(info) = file.get_type(0x10) {
+                Some(info.clone())
+            } else {
+                None
+            };
+
+            list.push((LogEntry { id: index, previous_commit: file.get_previous_commit(), commit_type, info, timestamp: None }, file.is_new()));
+            index = file.get_previous_commit();
+        }
+
+        // Reconstructing the time stamps, going from the oldest to the newest
+        let reached_start = if let Some((last, _)) = list.last() { last.previous_commit == U232::new() } else { false };
+        let mut time = if reached_start { Some(0) } else { None };
+        for (entry, is_new) in list.iter_mut().rev() {
+            if let Some(info) = &entry.info {
+                if *is_new {
+                    time = Some(info.get_timestamp());
+                } else if let Some(t) = time {
+                    time = Some(t + info.get_timestamp());
+                }
+
+                entry.timestamp = time;
+            }
+        }
+
+        // Cutting the page
+        let next = if list.len() > limit {
+            Some(list[limit].0.id)
+        } else if let Some((last, _)) = list.last() {
+            if last.previous_commit != U232::new() && list.len() == limit {
+                Some(last.previous_commit)
+            } else {
+                None
+            }
+        } else {
+            None
+        };
+        list.truncate(limit);
+
+        (list.into_iter().map(|(entry, _)| entry).collect(), next)
+    }
+
     fn insert_commit(&mut self, commit: Mutex<RepoFile>) -> U232 {
+        self.write_commit(commit.into_inner().unwrap()).0
+    }
+
+    // Writes the commit and adds it to the cache, returning how the writing went
+    fn write_commit(&mut self, mut commit: RepoFile) -> (U232, WritingStates) {
         let folder = PathBuf::from(&self.folder);
-        let hash = {
-            let mut commit = commit.lock().unwrap();
-            commit.write_file_back(folder.as_path());
-            U232::from_u8arr(common::hex_string_to_bytes(&commit.get_name()).as_slice())
-        };
-        
-        self.commits.insert(hash.clone(), commit);
-        hash
+        let state = commit.write_file_back(folder.as_path());
+        let hash = U232::from_u8arr(common::hex_string_to_bytes(&commit.get_name()).as_slice());
+
+        if commit.get_payload_size() > repository_file::MAX_CACHED_SIZE {
+            // Read again when needed, then the edit stays on disk
+            self.commits.remove(&hash);
+            return (hash, state);
+        }
+
+        self.commits.insert(hash.clone(), Mutex::new(commit));
+        (hash, state)
     }
 
     // TODO assess if this is okay, afterall it may stand in our way to figure out what was deleted in a commit
@@ -273,6 +405,13 @@ impl StorageRepo {
         }
     }
 
+    fn reload_branches(&mut self) {
+        if let RepoFileType::Head(head_info) = self.header.get_type(0x00) {
+            let head_info = head_info.clone();
+            self.read_branches(&head_info);
+        }
+    }
+
     pub fn get_branches(& self) -> &Vec<RepoFile> {
         &self.branches
     }
@@ -316,8 +455,13 @@ impl StorageRepo {
             self.header = new_header;
             self.header.write_file_back(PathBuf::from(&self.folder).as_path());
 
-            // Refreshing
-            self.update_header_and_branches();
+            // Removing the branch file, else recreating a branch with the same name would conflict with the old file
+            let mut file = PathBuf::from(&self.folder);
+            file.push(&name);
+            let _res = io::delete_file(file.as_path());
+
+            // Refreshing, the header is already up to date, so only the branches need rereading
+            self.reload_branches();
             return true;
         }
 
@@ -328,75 +472,147 @@ impl StorageRepo {
     //     &self.folder
     // }
 
-    pub fn push_commit_onto_branch(&mut self, repo_file: &RepoFile, branch_name: String) -> bool {
-        // Updating the files
+    // Branch names are file names in the repo folder, so they can not collide with the HEADER or commit files
+    pub fn is_valid_branch_name(name: &String) -> bool {
+        if name.is_empty() || name == "HEADER" || name.starts_with('.') {
+            return false;
+        }
+
+        for c in name.chars() {
+            if !(c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.') {
+                return false;
+            }
+        }
+
+        // Commit files are named by 29 bytes in hex
+        if name.len() == U232::NUM_OF_BYTES * 2 && name.chars().all(|c| c.is_ascii_hexdigit()) {
+            return false;
+        }
+
+        true
+    }
+
+    pub fn create_branch(&mut self, branch_name: String, commit: U232) -> BranchUpdate {
         self.update_header_and_branches();
         let folder = PathBuf::from(&self.folder);
 
-        // Finding the branch
-        let mut index = 0;
-        let mut branch = &self.header; // Potential that there are no branches, so we load the header file to not get a out of bounds
-        while index < self.branches.len() {
-            branch = &self.branches[index];
-            if branch.get_name() == &branch_name {
-                break;
+        if !StorageRepo::is_valid_branch_name(&branch_name) {
+            return BranchUpdate::Err;
+        }
+
+        if let Some(_) = self.get_branch(branch_name.clone()) {
+            return BranchUpdate::Conflict;
+        }
+
+        if commit != U232::new() && self.get_commit(commit).is_err() {
+            return BranchUpdate::NotFound;
+        }
+
+        if let RepoFileType::Head(header) = self.header.get_type(0x00) {
+            let mut header = header.clone();
+            header.branches.push(branch_name.clone());
+            
+            // Create branch file
+            let mut branch = RepoFile::new(
+                0,
+                branch_name,
+                vec![RepoFileType::BranchHead;1],
+                commit,
+                U232::new()
+            );
+            match branch.write_file_back(folder.as_path()) {
+                WritingStates::Conflict(_) => return BranchUpdate::Conflict, // There is a file with this name already
+                WritingStates::Err(_) => return BranchUpdate::Err,
+                _ => ()
+            }
+
+            // Updating Header file
+            let mut new_header = self.header.clone_with_content(vec![RepoFileType::Head(header)]);
+            match new_header.write_file_back(folder.as_path()) {
+                WritingStates::Conflict(_) | WritingStates::Err(_) => {
+                    // The branch is not listed, so we remove the file again, else creating it again would conflict
+                    let mut file = folder.clone();
+                    file.push(branch.get_name());
+                    let _res = io::delete_file(file.as_path());
+                    return BranchUpdate::Err;
+                },
+                _ => ()
             }
-            index = index + 1;
+            self.header = new_header;
+
+            // Updating cache
+            self.reload_branches();
+            return BranchUpdate::Ok;
+        } else {
+            // The Header file does not have a header info? This should not happen
+            panic!("Header file does not have header information");
         }
+    }
 
-        if branch.get_name() != &branch_name {
-            // No branch with this name, creating one
-            if let RepoFileType::Head(header) = self.header.get_type(0x00) {
-                let mut header = header.clone();
-                header.branches.push(branch_name.clone());
-                
-                // Create branch file
-                let mut branch = RepoFile::new(
-                    0,
-                    branch_name.clone(),
-                    vec![RepoFileType::BranchHead;1],
-                    U232::from_u8arr(common::hex_string_to_bytes(repo_file.get_name()).as_slice()),
-                    U232::new()
-                );
-                branch.write_file_back(folder.as_path());
-
-                // Updating Header file
-                let new_header = self.header.clone_with_content(vec![RepoFileType::Head(header)]);
-                self.header = new_header;
-                self.header.write_file_back(folder.as_path());
-
-                // Updating cache
-                self.update_header_and_branches();
+    // Checks if ancestor is part of the history of commit (or the commit itself)
+    fn is_ancestor(&mut self, ancestor: U232, commit: U232) -> bool {
+        let mut index = commit;
+        while let Ok(res) = self.get_commit(index) {
+            if index == ancestor {
                 return true;
-            } else {
-                // The Header file does not have a header info? This should not happen
-                panic!("Header file does not have header information");
             }
+
+            index = res.lock().unwrap().get_previous_commit();
         }
 
-        // Checking if the branch has been updated since
-        if repo_file.get_previous_commit() != branch.get_previous_commit() {
+        false
+    }
+
+    pub fn push_commit_onto_branch(&mut self, commit: U232, branch_name: String, force: bool) -> BranchUpdate {
+        // Updating the files
+        self.update_header_and_branches();
+        let folder = PathBuf::from(&self.folder);
+
+        if self.get_commit(commit).is_err() {
+            return BranchUpdate::NotFound;
+        }
+
+        // Finding the branch
+        let branch = if let Some(branch) = self.get_branch(branch_name.clone()) {
+            branch.clone()
+        } else {
+            // No branch with this name, creating one
+            return self.create_branch(branch_name, commit);
+        };
+
+        // Checking if the branch has been updated since, we only allow fast forwards onto the branch
+        let last_commit = branch.get_previous_commit();
+        if last_commit == commit {
+            return BranchUpdate::Ok; // Nothing to do
+        }
+        if !force && last_commit != U232::new() && !self.is_ancestor(last_commit, commit) {
             // There is a conflict
-            return false;
+            return BranchUpdate::Conflict;
         }
 
         // Updating the branch
-        let mut branch = branch.clone_with_prev_commit(U232::from_u8arr(common::hex_string_to_bytes(repo_file.get_name()).as_slice()));
-        branch.write_file_back(folder.as_path());
+        let mut branch = branch.clone_with_prev_commit(commit);
+        let res = branch.write_file_back(folder.as_path());
 
         // Update the information again
         self.update_header_and_branches();
 
-        true
+        match res {
+            WritingStates::Conflict(_) => BranchUpdate::Conflict, // Someone else wrote the branch file in the meantime
+            WritingStates::Err(_) => BranchUpdate::Err,
+            _ => BranchUpdate::Ok
+        }
     }
 
-    pub fn create_commit(&mut self, prev_commit_id: Option<U232>, location: &Path, is_root: bool) -> Option<U232> {
+    pub fn create_commit(&mut self, prev_commit_id: Option<U232>, location: &Path, is_root: bool) -> Result<U232, CommitError> {
+        self.full_copies.clear();
+
         let prev_commit_id = if let Some(prev) = prev_commit_id {
             if prev == U232::new() {
                 None
             } else {
                 if self.get_commit(prev).is_err() {
-                    return None;
+                    return Err(CommitError::PreviousNotFound);
                 }
                 Some(prev)
             }
@@ -408,10 +624,10 @@ impl StorageRepo {
 
         if !location.exists() {
             if let Some(prev) = prev_commit_id {
-                return Some(self.create_delete_commit(prev));
+                return Ok(self.create_delete_commit(prev));
             } else {
                 // Nothing to commit, exiting
-                return None;
+                return Err(CommitError::NothingToCommit);
             }
         }
 
@@ -425,6 +641,11 @@ impl StorageRepo {
         return self.create_folder_commit(prev_commit_id, location, is_root);
     }
 
+    // Files of the last create_commit that had to be stored as a full copy, as generating instructions failed
+    pub fn get_full_copies(&self) -> &Vec<PathBuf> {
+        &self.full_copies
+    }
+
     fn create_delete_commit(&mut self, prev_commit_id: U232) -> U232 {
         // Deleting what existed
         let repo = RepoFile::new(
@@ -438,7 +659,7 @@ impl StorageRepo {
         return self.insert_commit(Mutex::new(repo));
     }
 
-    fn create_folder_commit(&mut self, prev_commit: Option<U232>, location: &Path, is_root: bool) -> Option<U232> {
+    fn create_folder_commit(&mut self, prev_commit: Option<U232>, location: &Path, is_root: bool) -> Result<U232, CommitError> {
         // TODO check if the folder exists
         
 
@@ -456,7 +677,7 @@ impl StorageRepo {
 
             let history = self.get_commit_chain(prev_commit);
             if history.is_empty() {
-                return None; // Previous commit does not exist
+                return Err(CommitError::PreviousNotFound); // Previous commit does not exist
             }
             let p = history[0].lock().unwrap();
             if let RepoFileType::Delete = p.get_type(0x05) {
@@ -484,22 +705,19 @@ impl StorageRepo {
                     repo_file_type.push(RepoFileType::NewFolder(name));
                 }
             } else {
-                return None; // couldn't find an original file name
+                return Err(CommitError::PreviousNotFound); // couldn't find an original file name
             };
 
             // Getting the old file list
             let mut old_sub_commits = if let Some(data) = commit_generation::get_old_sub_info(self, prev_commit) {
                 data
             } else {
-                return None;
+                return Err(CommitError::PreviousNotFound);
             };
             
             let mut content = io::get_folder_content(location);
             let mut left_over_commits = Vec::<commit_generation::OldSub>::new();
 
-            let mut commits = Vec::<U232>::new();
-            let mut appended = Vec::<u8>::new();
-
             let mut changed = false; // Just comparing the commit ids should be sufficient, but we are not taking the chances
 
             // Iterating over the old_subcommits and content, looking for clean matches, processing those, and missfits get listed
@@ -512,17 +730,13 @@ impl StorageRepo {
                     if sub.name == name {
                         // We have got a match 
                         if item.is_dir() && sub.is_folder {
-                            sub_commit = self.create_folder_commit(Some(sub.id), item.as_path(), false);
+                            sub_commit = Some(self.create_folder_commit(Some(sub.id), item.as_path(), false)?);
                         } else if item.is_file() && !sub.is_folder {
-                            sub_commit = self.create_file_commit(Some(sub.id), item.as_path());
+                            sub_commit = Some(self.create_file_commit(Some(sub.id), item.as_path())?);
                         } else {
                             // Folders can't have the same name as files, one was renamed/removed, and another type was created
                             break;
                         }
-
-                        if let None = sub_commit {
-                            return None; // Something went wrong, abort
-                        }
                         break;
                     }
 
@@ -547,54 +761,38 @@ impl StorageRepo {
             if left_over_commits.is_empty() && content.is_empty() {
                 // in case nothing changed, no renaming, we may just return the previous commit
                 if !changed && repo_file_type.is_empty() {
-                    return Some(prev_commit);
+                    return Ok(prev_commit);
                 }
             } else {
                 // We process the remaining files/folders, trying to match them to the remaining old sub commits, if not possible creating new entries
                 for item in content {
                     let commit_to_add = if item.is_file() {
                         
-                        let res = commit_generation::process_leftover_file(self, &mut left_over_commits, &item, location);
-
-                        match res {
-                            Ok(index) => {
-                                let commit = left_over_commits[index].id;
-                                left_over_commits.remove(index);
-
-                                self.create_file_commit(Some(commit), item.as_path())
-                            },
-                            Err(fine) => {
-                                if fine {
-                                    self.create_file_commit(None, item.as_path())
-                                } else {
-                                    return None;
-                                }
-                            }
+                        let res = commit_generation::process_leftover_file(self, &mut left_over_commits, &item, location)?;
+
+                        if let Some(index) = res {
+                            let commit = left_over_commits[index].id;
+                            left_over_commits.remove(index);
+
+                            self.create_file_commit(Some(commit), item.as_path())?
+                        } else {
+                            self.create_file_commit(None, item.as_path())?
                         }
                     } else {
-                        let res = commit_generation::process_leftover_folder(self, &left_over_commits, &item);
+                        let res = commit_generation::process_leftover_folder(self, &left_over_commits, &item)?;
                         
-                        match res {
-                            Ok(index) => {
-                                let commit = left_over_commits[index].id;
-                                left_over_commits.remove(index);
-
-                                self.create_folder_commit(Some(commit), item.as_path(), false)
-                            },
-                            Err(fine) => {
-                                if fine {
-                                    self.create_folder_commit(None, item.as_path(), false)
-                                } else {
-                                    return None;
-                                }
-                            }
+                        if let Some(index) = res {
+                            let commit = left_over_commits[index].id;
+                            left_over_commits.remove(index);
+
+                            self.create_folder_commit(Some(commit), item.as_path(), false)?
+                        } else {
+                            self.create_folder_commit(None, item.as_path(), false)?
                         }
                     };
 
-                    if let Some(commit) = commit_to_add {
-                        appended.append(&mut commit.to_be_bytes().to_vec());
-                        commits.push(commit);
-                    }
+                    appended.append(&mut commit_to_add.to_be_bytes().to_vec());
+                    commits.push(commit_to_add);
                 }
 
 
@@ -620,20 +818,15 @@ impl StorageRepo {
 
             // Generating all sub commits
             for item in content {
-                let res =if item.is_file() {
-                    self.create_file_commit(None, item.as_path())
+                let com = if item.is_file() {
+                    self.create_file_commit(None, item.as_path())?
                 } else {
                     // it is a directory
-                    self.create_folder_commit(None, item.as_path(), false)
+                    self.create_folder_commit(None, item.as_path(), false)?
                 };
 
-                if let Some(com) = res {
-                    appended.append(&mut com.to_be_bytes().to_vec());
-                    commits.push(com);
-                } else {
-                    // As the item excists, it must be able to generate a commit
-                    return None; // Something went wrong, aborting
-                }
+                appended.append(&mut com.to_be_bytes().to_vec());
+                commits.push(com);
             }
             let name = if is_root {
                 "".to_string()
@@ -657,20 +850,22 @@ impl StorageRepo {
                 final_prev_commit,
                 U232::new()
         )));
-        return Some(id);
+        return Ok(id);
     }
 
-    fn create_file_commit(&mut self, prev_commit: Option<U232>, location: &Path) -> Option<U232> {
-        let (new_data,
-            mut old_data,
-            new_hash,
+    fn create_file_commit(&mut self, prev_commit: Option<U232>, location: &Path) -> Result<U232, CommitError> {
+        let new_size = io::u64_to_usize(io::get_file_size(location).map_err(|e| CommitError::Io(e))?);
+        let new_hash = io::hash_file(location).map_err(|e| CommitError::Io(e))?;
+
+        let (old_size,
             rename,
-            prev_com_id) = if let Some(old_id) = prev_commit {
+            prev_com_id,
+            new_file) = if let Some(old_id) = prev_commit {
 
             let p = if let Ok(commit) = self.get_commit(old_id) {
                 commit
             } else {
-                return None;
+                return Err(CommitError::PreviousNotFound);
             }.lock().unwrap();
             if let RepoFileType::Delete = p.get_type(0x05) {
                 // If the previous commit was a delete we start from scratch (which is easier done by calling the function again on the same folder)
@@ -678,55 +873,41 @@ impl StorageRepo {
                 return self.create_file_commit(None, location);
             }
             drop(p);
-            
-            if let Ok(new_data) = io::read_bytes(location) {
-                let new_hash = common::hash_data(new_data.as_slice());
-
-                if new_hash.equal_224(&old_id) {
-                    // no changes in the file, return the Prev commit
-                    return Some(old_id);
-                }
 
-                let (loc, old_data) = self.build_file(old_id, location);
+            if new_hash.equal_224(&old_id) {
+                // no changes in the file, return the Prev commit
+                return Ok(old_id);
+            }
+            let name = location.file_name().and_then(|n| n.to_str()).map(|n| n.to_string());
+            let same_name = self.get_file_name(old_id) == name;
 
-                let rename = if loc.file_name() != location.file_name() {
+            if self.needs_snapshot(old_id) {
+                // chain got too long, we write the whole file again, but keep the history
+                (0, Some(location.file_name()), old_id, true)
+            } else {
+                let rename = if !same_name {
                     Some(location.file_name())
                 } else {
                     None
                 };
 
-                (new_data, old_data, new_hash, rename, old_id)
-             } else {
-                return None;
+                (self.get_file_size(old_id), rename, old_id, false)
             }
         } else {
             // First commit
-            if let Ok(new_data) = io::read_bytes(location) {
-                let new_hash = common::hash_data(new_data.as_slice());
-
-                (new_data, vec![0_u8;0], new_hash, Some(location.file_name()), U232::new())
-            } else {
-                return None;
-            }
+            (0, Some(location.file_name()), U232::new(), true)
         };
         
         let mut content = Vec::<RepoFileType>::new();
 
         // New File
-        if prev_com_id == U232::new() {
+        if new_file {
             content.push(RepoFileType::NewFile);
         }
 
-        // Resize
-        if old_data.len() != new_data.len() {
-            content.push(RepoFileType::Resize(new_data.len().try_into().unwrap()));
-
-            // Resizing old_data so we can compare
-            if old_data.len() > new_data.len() {
-                old_data = old_data[..new_data.len()].to_vec();
-            } else {
-                old_data.append(&mut vec![0_u8; new_data.len() - old_data.len()])
-            }
+        // Resize, a NewFile always needs it
+        if old_size != new_size || new_file {
+            content.push(RepoFileType::Resize(new_size.try_into().unwrap()));
         }
 
         // Rename
@@ -735,18 +916,196 @@ impl StorageRepo {
             content.push(RepoFileType::Rename(name));
         }
 
-        // Edit
-        content.push(commit_generation::generate_file_instructions(old_data, new_data));
+        // Checked before adding, other commits of this repo may already use the blob
+        let blob_referenced = self.references_blob(&new_hash);
+
+        // New files are stored in the blob store, so identical files in other repos are only stored once
+        if new_file && self.add_blob(&new_hash, location, new_size) {
+            content.push(RepoFileType::Blob(new_hash.clone()));
+            return self.insert_file_commit(content, &new_hash, prev_com_id, blob_referenced);
+        }
+
+        // Edit, large files are processed in chunks, so we don't have to load them into memory
+        let old_commit = if new_file { None } else { Some(prev_com_id) };
+        let edit = if new_size > LARGE_FILE_SIZE || old_size > LARGE_FILE_SIZE {
+            commit_generation::generate_streamed_instructions(self, old_commit, location, new_size)
+        } else {
+            let new_data = io::read_bytes(location).map_err(|e| CommitError::Io(e))?;
+            let old_data = if let Some(old_id) = old_commit {
+                self.build_file(old_id, location).map_err(|e| CommitError::Io(e))?.1
+            } else {
+                Vec::<u8>::new()
+            };
+
+            commit_generation::generate_file_instructions(old_data, new_data)
+        };
+
+        match edit {
+            Ok(edit) => content.push(edit),
+            Err(CommitError::InstructionMismatch) => {
+                // The instructions did not reproduce the file, so we store a full copy instead, keeping the history
+                let name = location.file_name().unwrap().to_str().unwrap().to_string();
+                content = vec![
+                    RepoFileType::NewFile,
+                    RepoFileType::Resize(new_size.try_into().unwrap()),
+                    RepoFileType::Rename(name),
+                    if self.add_blob(&new_hash, location, new_size) {
+                        RepoFileType::Blob(new_hash.clone())
+                    } else {
+                        commit_generation::generate_full_copy(location, new_size)?
+                    }
+                ];
+
+                self.full_copies.push(location.to_path_buf());
+            },
+            Err(e) => return Err(e)
+        }
+
+        self.insert_file_commit(content, &new_hash, prev_com_id, blob_referenced)
+    }
+
+    // If the commit can not be written, a blob reference added for it is dropped again, unless the repo had it before
+    fn insert_file_commit(&mut self, content: Vec<RepoFileType>, new_hash: &U232, prev_com_id: U232, blob_referenced: bool) -> Result<U232, CommitError> {
+        let blob = content.iter().find_map(|item| if let RepoFileType::Blob(id) = item { Some(id.clone()) } else { None });
 
         let repo_file = RepoFile::new(
-            0, // Current Version
-            common::bytes_to_hex_string(self.get_free_commit_id(&new_hash).to_be_bytes()),
+            repository_file::get_version(&self.compression), // Current Version
+            common::bytes_to_hex_string(self.get_free_commit_id(new_hash).to_be_bytes()),
             content, 
             prev_com_id,
             U232::new()
         );
-        
-        Some(self.insert_commit(Mutex::new(repo_file)))
+
+        let (hash, state) = self.write_commit(repo_file);
+        let e = match state {
+            WritingStates::Err(e) => e,
+            WritingStates::Conflict(_) => std::io::Error::new(std::io::ErrorKind::AlreadyExists, "A different commit with this name exists"),
+            _ => return Ok(hash)
+        };
+
+        // It only exists in the cache, so we drop it there too
+        self.commits.remove(&hash);
+        if let (Some(id), false) = (blob, blob_referenced) {
+            if let Some(store) = &self.blob_store {
+                store.release(&id, &self.get_repo_name());
+            }
+        }
+
+        Err(CommitError::Io(e))
+    }
+
+    pub fn set_blob_store(&mut self, blob_store: BlobStore) {
+        self.blob_store = Some(blob_store);
+    }
+
+    pub fn set_temp_folder(&mut self, temp_folder: PathBuf) {
+        self.temp_folder = Some(temp_folder);
+    }
+
+    // Large files get build on disk, in a folder inside the temp folder
+    // Repos without one (only in tests) use the systems temp directory
+    fn get_temp_build_folder(&self) -> PathBuf {
+        let mut temp = self.temp_folder.clone().unwrap_or_else(std::env::temp_dir);
+        temp.push(format!("build-{}", uuid::Uuid::new_v4()));
+        temp
+    }
+
+    // Blobs are referenced under the folder name, same as the RepoController uses
+    fn get_repo_name(&self) -> String {
+        Path::new(&self.folder).file_name().and_then(|n| n.to_str()).unwrap_or_default().to_string()
+    }
+
+    // Stores the file in the shared blob store, returns false if there is none or the file is too small to be worth it
+    fn add_blob(&self, id: &U232, location: &Path, size: usize) -> bool {
+        if size < blob_store::BLOB_MIN_SIZE {
+            return false;
+        }
+
+        if let Some(store) = &self.blob_store {
+            return store.add(id, location, &self.get_repo_name()).is_ok();
+        }
+
+        false
+    }
+
+    fn references_blob(&self, id: &U232) -> bool {
+        self.blob_store.as_ref().map(|store| store.is_referenced_by(id, &self.get_repo_name())).unwrap_or(false)
+    }
+
+    // Drops the reference of this repo on every blob not in keep
+    pub fn release_blobs(&self, keep: &HashSet<U232>) -> usize {
+        if let Some(store) = &self.blob_store {
+            return store.release_repo(&self.get_repo_name(), keep);
+        }
+
+        0
+    }
+
+    // Bytes used by the files in the repo folder, plus every blob the repo references
+    pub fn get_storage_size(&self) -> u64 {
+        self.get_folder_size() + self.get_referenced_blobs().values().sum::<u64>()
+    }
+
+    // Bytes used by the files in the repo folder, without the blobs
+    pub fn get_folder_size(&self) -> u64 {
+        io::get_folder_content(Path::new(&self.folder)).iter()
+            .filter_map(|file| io::get_file_size(file.as_path()).ok())
+            .sum()
+    }
+
+    // Ids and sizes of the blobs the repo references
+    pub fn get_referenced_blobs(&self) -> HashMap<U232, u64> {
+        self.blob_store.as_ref().map(|store| store.get_referenced(&self.get_repo_name())).unwrap_or_default()
+    }
+
+    pub fn get_compression(&self) -> Compression {
+        self.compression.clone()
+    }
+
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.compression = compression;
+    }
+
+    pub fn get_retention_policy(&self) -> Option<RetentionPolicy> {
+        self.retention.clone()
+    }
+
+    pub fn set_retention_policy(&mut self, policy: Option<RetentionPolicy>) {
+        self.retention = policy;
+    }
+
+    pub fn get_snapshot_policy(&self) -> SnapshotPolicy {
+        self.snapshot_policy.clone()
+    }
+
+    pub fn set_snapshot_policy(&mut self, policy: SnapshotPolicy) {
+        self.snapshot_policy = policy;
+    }
+
+    fn needs_snapshot(&mut self, commit: U232) -> bool {
+        let policy = self.snapshot_policy.clone();
+        if policy.max_depth == 0 && policy.max_size == 0 {
+            return false;
+        }
+
+        let folder = PathBuf::from(&self.folder);
+        let mut depth:usize = 0;
+        let mut size:u64 = 0;
+        for item in self.get_file_chain(commit) {
+            let temp = item.lock().unwrap();
+            if let RepoFileType::NewFile = temp.get_type(0x03) {
+                // the full copy itself is not counted, else large files would snapshot on every commit
+                break;
+            }
+
+            // The size on disk, so compressed commits count with what they actually take up
+            let mut file = folder.clone();
+            file.push(temp.get_name());
+            depth = depth + 1;
+            size = size + io::get_file_size(file.as_path()).unwrap_or_default();
+        }
+
+        (policy.max_depth != 0 && depth >= policy.max_depth) || (policy.max_size != 0 && size >= policy.max_size)
     }
 
     fn get_commit_chain<'a>(&'a mut self, commit: U232) -> Vec<&'a Mutex<RepoFile>> {
@@ -761,7 +1120,7 @@ impl StorageRepo {
                 //TODO potentially cut down calls, as build folder and file do not need the full history
             };
 
-            ids.push(prev_commit.clone());
+            ids.push(index);
             index = prev_commit;
         }
 
@@ -774,32 +1133,180 @@ impl StorageRepo {
         stack
     }
 
-    pub fn build_commit(&mut self, commit_id: U232, target_folder: &Path) {
+    // Same as get_commit_chain, but stops at the first NewFile, as nothing before it is needed to build the file
+    fn get_file_chain<'a>(&'a mut self, commit: U232) -> Vec<&'a Mutex<RepoFile>> {
+        let mut ids = Vec::<U232>::new();
+        let mut index = commit;
+        while let Ok(res) = self.get_commit(index) {
+            let (prev_commit, new_file) = {
+                let file = res.lock().unwrap();
+                (file.get_previous_commit(), matches!(file.get_type(0x03), RepoFileType::NewFile))
+            };
+
+            ids.push(index);
+            if new_file {
+                break;
+            }
+            index = prev_commit;
+        }
+
+        let mut stack = Vec::<&Mutex<RepoFile>>::new();
+        for i in ids {
+            if self.commits.contains_key(&i) {
+                stack.push(&self.commits[&i]);
+            }
+        }
+
+        stack
+    }
+
+    pub fn build_commit(&mut self, commit_id: U232, target_folder: &Path) -> bool {
         if let Ok(repo_file) = self.get_commit(commit_id){
             
 
-            let res = { 
+            let (res, deleted) = { 
                 let repo_file = repo_file.lock().unwrap();
-                repo_file.get_type(0x0F).clone()
+                let deleted = if let RepoFileType::Delete = repo_file.get_type(0x05) { true } else { false };
+                (repo_file.get_type(0x0F).clone(), deleted)
             };
 
+            if deleted {
+                // Nothing to build, else we would rebuild the file that got deleted
+                return true;
+            }
+
             if let RepoFileType::Folder(_d) = res {
-                self.build_folder(commit_id, target_folder);
+                return self.build_folder(commit_id, target_folder);
             } else {
-                let (file, data) = self.build_file(commit_id, target_folder);
-                let _res = io::write_bytes(file.as_path(), data); //TODO prober handling
+                if self.get_file_size(commit_id) > LARGE_FILE_SIZE {
+                    return self.write_file(commit_id, target_folder).is_ok();
+                }
+
+                if let Ok((file, data)) = self.build_file(commit_id, target_folder) {
+                    return io::write_bytes(file.as_path(), data).is_ok();
+                }
+                return false;
+            }
+        }
+
+        false
+    }
+
+    pub fn diff_commits(&mut self, from: U232, to: U232) -> Option<CommitDiff> {
+        diff::diff_commits(self, from, to)
+    }
+
+    // Removes (or with dry_run only lists) all commit files that can not be reached from any branch
+    // Files written within a day before now are kept, as they might be waiting to be pushed
+    pub fn collect_garbage(&mut self, now: u64, dry_run: bool) -> GarbageReport {
+        maintenance::collect_garbage(self, now, dry_run)
+    }
+
+    // Thins out the history according to the retention policy, see retention::apply_retention
+    // Returns None if the repo has no policy
+    pub fn apply_retention(&mut self, now: u64, dry_run: bool) -> Option<RetentionReport> {
+        let policy = self.retention.clone()?;
+        Some(retention::apply_retention(self, &policy, now, dry_run))
+    }
+
+    // Rebuilds every reachable commit and checks it against it's name, see maintenance::verify
+    pub fn verify(&mut self) -> VerifyReport {
+        maintenance::verify(self)
+    }
+
+    // Lists what build_commit would create, without writing anything
+    // Returns None for deleted commits, and if the history is broken
+    pub fn get_commit_tree(&mut self, commit_id: U232) -> Option<TreeEntry> {
+        let full_history = self.get_commit_chain(commit_id);
+        if full_history.is_empty() {
+            return None;
+        }
+
+        let (items, deleted) = {
+            let newest = full_history[0].lock().unwrap();
+            let deleted = if let RepoFileType::Delete = newest.get_type(0x05) { true } else { false };
+            (newest.get_type(0x0F).clone(), deleted)
+        };
+        if deleted {
+            return None;
+        }
+
+        // Content hashes are the commit names without the inequality byte
+        let mut hash = commit_id;
+        hash.set_inequailty_byte(0);
+
+        if let RepoFileType::Folder(items) = items {
+            let mut name = None;
+            for item in full_history {
+                let temp = item.lock().unwrap();
+                if let RepoFileType::NewFolder(n) = temp.get_type(0x0D) {
+                    name = Some(n.clone());
+                    break;
+                }
+            }
+
+            let mut content = Vec::<TreeEntry>::new();
+            for item in items {
+                if let Ok(sub) = self.get_commit(item) {
+                    if let RepoFileType::Delete = sub.lock().unwrap().get_type(0x05) {
+                        continue; // Deleted items are not part of the tree
+                    }
+                }
+
+                content.push(self.get_commit_tree(item)?);
+            }
+
+            return Some(TreeEntry { name: name?, commit: commit_id, hash, size: None, content: Some(content) });
+        } else {
+            let mut name = None;
+            let mut size = None;
+            for item in full_history {
+                let temp = item.lock().unwrap();
+
+                // We are going into the past, so the first occurrence is the current value
+                if size.is_none() {
+                    if let RepoFileType::Resize(s) = temp.get_type(0x08) {
+                        size = Some(s.clone());
+                    }
+                }
+                if name.is_none() {
+                    if let RepoFileType::Rename(n) = temp.get_type(0x04) {
+                        name = Some(n.clone());
+                    }
+                }
+
+                if let RepoFileType::NewFile = temp.get_type(0x03) {
+                    break;
+                }
+            }
+
+            return Some(TreeEntry { name: name?, commit: commit_id, hash, size: Some(size.unwrap_or(0)), content: None });
+        }
+    }
+
+    fn get_file_name(&mut self, commit: U232) -> Option<String> {
+        for item in self.get_commit_chain(commit) {
+            let temp = item.lock().unwrap();
+            if let RepoFileType::Rename(name) = temp.get_type(0x04) {
+                return Some(name.clone());
+            }
+            if let RepoFileType::NewFile = temp.get_type(0x03) {
+                break;
             }
         }
+
+        None
     }
 
-    fn build_file(&mut self, commit: U232, target_folder: &Path) -> (PathBuf, Vec<u8>) {
+    // Collects the commits needed to build the file (up to the last New File, which ends up last), the file name, the largest and the current size
+    fn get_file_history(&mut self, commit: U232) -> (Vec<MutexGuard<'_, RepoFile>>, String, usize, Option<usize>) {
         let mut stack = Vec::<MutexGuard<RepoFile>>::new();
 
         let mut max_file_size:usize = 0;
-        let mut cur_file_size:usize = 0;
+        let mut cur_file_size:Option<usize> = None;
         let mut file_name = String::new();
 
-        let full_history = self.get_commit_chain(commit);
+        let full_history = self.get_file_chain(commit);
         for temp in full_history {
             let temp = temp.lock().unwrap();
 
@@ -811,8 +1318,8 @@ impl StorageRepo {
                 }
 
                 // Only update cur_file_size on the first resize
-                if cur_file_size == 0 {
-                    cur_file_size = size;
+                if cur_file_size.is_none() { // files can be resized to 0
+                    cur_file_size = Some(size);
                 }
             }
             // As we are iterating into the past we take the first occurence of a new name and save it. We do not need older names
@@ -831,62 +1338,125 @@ impl StorageRepo {
             }
         }
 
+        (stack, file_name, max_file_size, cur_file_size)
+    }
+
+    // Size of the file after this commit
+    fn get_file_size(&mut self, commit: U232) -> usize {
+        for item in self.get_file_chain(commit) {
+            if let RepoFileType::Resize(size) = item.lock().unwrap().get_type(0x08) {
+                return io::u64_to_usize(size.clone());
+            }
+        }
+
+        0
+    }
+
+    // Fails if a blob of the file can not be read, a save full of zeros would be worse then none
+    fn build_file(&mut self, commit: U232, target_folder: &Path) -> std::io::Result<(PathBuf, Vec<u8>)> {
+        let blob_store = self.blob_store.clone(); // the history borrows self
+        let (mut stack, file_name, max_file_size, cur_file_size) = self.get_file_history(commit);
+
         let mut data: Vec<u8> = vec![0_u8; max_file_size];
         let mut pointer_size: usize = 0;
 
         // Executing the code
         while let Some(mut item) = stack.pop() {
-            let res = item.get_type(0x02);
-            
-            if let RepoFileType::Edit(ins, p_size) = res {
-                pointer_size = p_size.clone(); // We update the pointer size for future repo files
-
-                let mut iter = ins.iter();
-                while let Some(instruction) = iter.next() {
-                    instruction.run_instruction(&mut data);
-                }
-            } else if let RepoFileType::EditNotProcessed(_bytes) = res {
-                if let Ok(p_size) = item.get_pointer_size() { // In case there was a resize on this commit
-                    pointer_size = p_size;
-                }
-
-                // Processing Instructions
-                //let mut item = item;
-                item.parse_edit_instructions(pointer_size);
-
-                // Running instructions
-                if let RepoFileType::Edit(ins, _p) = item.get_type(0x02) {
-                    let mut iter = ins.iter();
-                    while let Some(instruction) = iter.next() {
-                        instruction.run_instruction(&mut data);
-                    }
+            // Instructions were generated against the old file cut or padded with zeros to this size
+            if let RepoFileType::Resize(size) = item.get_type(0x08) {
+                let size = io::u64_to_usize(size.clone());
+                if size < data.len() {
+                    data[size..].fill(0);
                 }
+            }
 
-                // Updating cache
-                //self.insert_commit(item);
-            } else if let Ok(p_size) = item.get_pointer_size() {
-                // This is for the special case that there was no edit instruction, but a resize instruction, so we update that for future commits
-                pointer_size = p_size.clone(); 
+            if let RepoFileType::Blob(id) = item.get_type(0x07) {
+                let blob = get_blob_store(&blob_store)?.read(id)?;
+                let len = blob.len().min(data.len());
+                data[..len].copy_from_slice(&blob[..len]);
             }
+
+            // A commit that can not be read leaves the file incomplete, which verify reports as a hash mismatch
+            let _ = run_instructions(&mut item, &mut pointer_size, |instruction| {
+                instruction.run_instruction(&mut data);
+                Ok(())
+            });
         }
 
         let mut file = PathBuf::from(target_folder);
         file.push(file_name);
 
-        data = data[..cur_file_size].to_vec(); // setting the correct file size
+        data = data[..cur_file_size.unwrap_or(max_file_size)].to_vec(); // setting the correct file size
 
         // TODO validate the file hash
 
-        (file, data)
+        Ok((file, data))
+    }
+
+    // Same as build_file, but the instructions are run directly on the file, so only a chunk is in memory at a time
+    fn write_file(&mut self, commit: U232, target_folder: &Path) -> std::io::Result<PathBuf> {
+        let blob_store = self.blob_store.clone(); // the history borrows self
+        let (mut stack, file_name, max_file_size, cur_file_size) = self.get_file_history(commit);
+
+        let mut path = PathBuf::from(target_folder);
+        path.push(file_name);
+
+        let mut file = File::options().read(true).write(true).create(true).truncate(true).open(path.as_path())?;
+        file.set_len(max_file_size.try_into().unwrap_or_default())?;
+        let mut pointer_size: usize = 0;
+
+        while let Some(mut item) = stack.pop() {
+            // Cutting the file down and back up clears everything behind the size
+            if let RepoFileType::Resize(size) = item.get_type(0x08) {
+                let size = size.clone();
+                if io::u64_to_usize(size) < max_file_size {
+                    file.set_len(size)?;
+                    file.set_len(max_file_size.try_into().unwrap_or_default())?;
+                }
+            }
+
+            if let RepoFileType::Blob(id) = item.get_type(0x07) {
+                let mut blob = File::open(get_blob_store(&blob_store)?.get_path(id))?;
+                let mut offset:usize = 0;
+                while offset < max_file_size {
+                    let chunk = io::read_chunk(&mut blob, offset.try_into().unwrap(), io::CHUNK_SIZE.min(max_file_size - offset))?;
+                    if chunk.is_empty() {
+                        break;
+                    }
+                    io::write_chunk(&mut file, offset.try_into().unwrap(), &chunk)?;
+                    offset = offset + chunk.len();
+                }
+            }
+
+            run_instructions(&mut item, &mut pointer_size, |instruction| instruction.run_instruction_on_file(&mut file, max_file_size))?;
+        }
+
+        file.set_len(cur_file_size.unwrap_or(max_file_size).try_into().unwrap_or_default())?;
 
+        Ok(path)
     }
 
-    fn build_folder(&mut self, commit: U232, target_folder: &Path) {
+    // Hashes the file of this commit, large files are build on disk for this
+    fn hash_file_commit(&mut self, commit: U232) -> std::io::Result<U232> {
+        if self.get_file_size(commit) <= LARGE_FILE_SIZE {
+            let (_, data) = self.build_file(commit, Path::new(""))?;
+            return Ok(common::hash_data(data.as_slice()));
+        }
+
+        let temp = self.get_temp_build_folder();
+        io::create_folder(temp.as_path())?;
+        let res = self.write_file(commit, temp.as_path()).and_then(|path| io::hash_file(path.as_path()));
+        let _ = io::delete_folder(temp.as_path());
+
+        res
+    }
+
+    fn build_folder(&mut self, commit: U232, target_folder: &Path) -> bool {
         let mut folder_path = PathBuf::from(target_folder.as_os_str());
 
         let full_history = self.get_commit_chain(commit);
         if full_history.len() == 0 {
-            return; // Commit does not exist
+            return false; // Commit does not exist
         }
 
         // Used later to build the folder
@@ -905,7 +1475,7 @@ impl StorageRepo {
 
                 // Creating the folder
                 if let Err(_e) = io::create_folder(folder_path.as_path()) {
-                    // TODO
+                    return false;
                 }
                 break;
             }
@@ -915,10 +1485,65 @@ impl StorageRepo {
         if let RepoFileType::Folder(items) = res {
             let mut iter = items.iter();
             while let Some(commit) = iter.next() {
-                self.build_commit(commit.clone(), folder_path.as_path());
+                if !self.build_commit(commit.clone(), folder_path.as_path()) {
+                    return false;
+                }
             }
         }
 
+        true
+    }
+}
+
+// Commits that point to a blob can not be build without the store
+fn get_blob_store(blob_store: &Option<BlobStore>) -> std::io::Result<&BlobStore> {
+    blob_store.as_ref().ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "Repo has no blob store"))
+}
+
+// Updates the pointer size and runs the instructions of the commit, edits left on disk are streamed from the file
+fn run_instructions(item: &mut RepoFile, pointer_size: &mut usize, mut run: impl FnMut(&Instruction) -> std::io::Result<()>) -> std::io::Result<()> {
+    if let RepoFileType::EditOnDisk(_, _) = item.get_type(0x02) {
+        if let Ok(p_size) = item.get_pointer_size() {
+            *pointer_size = p_size;
+        }
 
+        for instruction in item.read_edit_instructions(pointer_size.clone())? {
+            run(&instruction?)?;
+        }
+    } else if let Some(ins) = prepare_instructions(item, pointer_size) {
+        let mut iter = ins.iter();
+        while let Some(instruction) = iter.next() {
+            run(instruction)?;
+        }
     }
+
+    Ok(())
 }
+
+// Updates the pointer size, and parses the instructions if necessary
+fn prepare_instructions<'a>(item: &'a mut RepoFile, pointer_size: &mut usize) -> Option<&'a Vec<Instruction>> {
+    if let RepoFileType::Edit(_ins, p_size) = item.get_type(0x02) {
+        *pointer_size = p_size.clone(); // We update the pointer size for future repo files
+    } else if let RepoFileType::EditNotProcessed(_bytes) = item.get_type(0x02) {
+        if let Ok(p_size) = item.get_pointer_size() { // In case there was a resize on this commit
+            *pointer_size = p_size;
+        }
+
+        // Processing Instructions
+        item.parse_edit_instructions(pointer_size.clone());
+    } else {
+        if let Ok(p_size) = item.get_pointer_size() {
+            // This is for the special case that there was no edit instruction, but a resize instruction, so we update that for future commits
+            *pointer_size = p_size;
+        }
+        return None;
+    }
+
+    if let RepoFileType::Edit(ins, _p) = item.get_type(0x02) {
+        return Some(ins);
+    }
+
+    None
+}
+
+
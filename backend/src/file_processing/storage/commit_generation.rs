@@ -1,10 +1,14 @@
-use std::path::{PathBuf, Path};
+use std::{path::{PathBuf, Path}, collections::HashMap, fs::File};
 
 use common::{U232, LargeU};
 
-use crate::file_processing::{storage::StorageRepo, io, repository_file::{RepoFileType, Instruction, Operation}};
+use crate::file_processing::{storage::{StorageRepo, CommitError, LARGE_FILE_SIZE}, io, repository_file::{RepoFileType, Instruction, Operation}};
 
 const MAX_DIFFERENCE_PERCENT:u64 = 25;
+const COPY_BLOCK_SIZE:usize = 32; // Size of the anchors when searching for moved blocks
+const MAX_BLOCK_CANDIDATES:usize = 8; // Repeating blocks would otherwise blow up the search
+const HASH_BASE:u64 = 0x100000001B3;
+const STREAM_CHUNK_SIZE:usize = 8 * 1024 * 1024; // Large files are compared in chunks of this size
 
 pub struct OldSub {
     pub id: U232,
@@ -12,21 +16,29 @@ pub struct OldSub {
     pub is_folder: bool
 }
 
-pub fn process_leftover_file (store: &mut StorageRepo, left_over_commits: &Vec<OldSub>, item: &PathBuf, location: &Path) -> Result<usize, bool> {
-    if let Ok(file_content) = io::read_bytes(item.as_path()) {
-        let new_hash = common::hash_data(file_content.as_slice());
-        
-        let mut index = 0;
-        // Lets see if an identical file exists
-        for sub in left_over_commits.iter() {
-            if !sub.is_folder && sub.id.equal_224(&new_hash) {
-                // Match
-                return Ok(index);
-            }
+// Returns the index of the best matching old commit, or None if a new file should be created
+pub fn process_leftover_file (store: &mut StorageRepo, left_over_commits: &Vec<OldSub>, item: &PathBuf, location: &Path) -> Result<Option<usize>, CommitError> {
+    let new_hash = io::hash_file(item.as_path()).map_err(|e| CommitError::Io(e))?;
 
-            index += 1;
+    let mut index = 0;
+    // Lets see if an identical file exists
+    for sub in left_over_commits.iter() {
+        if !sub.is_folder && sub.id.equal_224(&new_hash) {
+            // Match
+            return Ok(Some(index));
         }
 
+        index += 1;
+    }
+
+    // Large files are not compared byte by byte, they would need to be loaded into memory
+    if io::get_file_size(item.as_path()).map_err(|e| CommitError::Io(e))? > LARGE_FILE_SIZE as u64 {
+        return Ok(None);
+    }
+
+    let res = io::read_bytes(item.as_path());
+    if let Ok(file_content) = res {
+
         // we compare them, seeing if we get a close enough match
         // maybe we should iterate over all content to see if we get precise matches, but oh well, we might do this too
         let mut error_rates = Vec::<(usize, u64)>::new();
@@ -35,8 +47,14 @@ pub fn process_leftover_file (store: &mut StorageRepo, left_over_commits: &Vec<O
 
         let mut index = 0;
         for sub in left_over_commits.iter() {
-            if !sub.is_folder {
-                let (_, mut sub_file) = store.build_file(sub.id, location);
+            if !sub.is_folder && store.get_file_size(sub.id) <= LARGE_FILE_SIZE {
+                // A file that can not be build is no match
+                let (_, mut sub_file) = if let Ok(built) = store.build_file(sub.id, location) {
+                    built
+                } else {
+                    index += 1;
+                    continue;
+                };
                 let sub_file_size = sub_file.len();
 
                 // Resizing old_data so we can compare
@@ -76,18 +94,21 @@ pub fn process_leftover_file (store: &mut StorageRepo, left_over_commits: &Vec<O
         }
 
         if let Some((index,_)) = lowest {
-            return Ok(index);
+            return Ok(Some(index));
         }
         
         // So we couldn't find a match, we add a new file
-        return Err(true);
-    } else {
-        return Err(false); // Something went wrong when reading a file that should exist
+        return Ok(None);
+    } else if let Err(e) = res {
+        return Err(CommitError::Io(e)); // Something went wrong when reading a file that should exist
     }
+
+    Ok(None)
 }
 
 
-pub fn process_leftover_folder (store: &mut StorageRepo, left_over_commits: &Vec<OldSub>, item: &PathBuf) -> Result<usize, bool> {
+// Same as process_leftover_file, but for folders
+pub fn process_leftover_folder (store: &mut StorageRepo, left_over_commits: &Vec<OldSub>, item: &PathBuf) -> Result<Option<usize>, CommitError> {
     // Find out what this folder contains
     let content = io::get_folder_content(item.as_path());
     let mut content_detail = Vec::<OldSub>::new();
@@ -96,10 +117,9 @@ pub fn process_leftover_folder (store: &mut StorageRepo, left_over_commits: &Vec
         
         content_detail.push(
             if element.is_file() {
-                if let Ok(hash) = io::hash_file(element.as_path()) {
-                    OldSub { id: hash, name, is_folder: false }
-                } else {
-                    return Err(false);
+                match io::hash_file(element.as_path()) {
+                    Ok(hash) => OldSub { id: hash, name, is_folder: false },
+                    Err(e) => return Err(CommitError::Io(e))
                 }
             } else {
                 OldSub { id: U232::new(), name, is_folder: true }
@@ -116,7 +136,7 @@ pub fn process_leftover_folder (store: &mut StorageRepo, left_over_commits: &Vec
             if let Some (new_entry) = res {
                 lefty_content_detail.push((index, new_entry));
             } else {
-                return Err(false);
+                return Err(CommitError::PreviousNotFound);
             }
         }
 
@@ -125,7 +145,7 @@ pub fn process_leftover_folder (store: &mut StorageRepo, left_over_commits: &Vec
 
     // If there are no old folders to match with, we will create a new one
     if lefty_content_detail.is_empty() {
-        return Err(true);
+        return Ok(None);
     }
 
     // We compare the different old folders to find the best match
@@ -179,10 +199,10 @@ pub fn process_leftover_folder (store: &mut StorageRepo, left_over_commits: &Vec
     }
 
     if let Some((index, _)) = best_match {
-        return Ok(index);
+        return Ok(Some(index));
     } else {
         // strange, whatever, lets just create a new one
-        return Err(true);
+        return Ok(None);
     }
 }
 
@@ -211,7 +231,10 @@ pub fn get_old_sub_info(store: &mut StorageRepo, folder_commit: U232) -> Option<
             return None; // Something went wrong
         }
         let last = history[0].lock().unwrap();
-        if let RepoFileType::Delete = last.get_type(0x05) {
+        let deleted = if let RepoFileType::Delete = last.get_type(0x05) { true } else { false };
+        drop(last); // else we deadlock when iterating over the history
+
+        if deleted {
             // this item was deleted on the previous iteration, no need to keep around
         } else {
             for i in history {
@@ -241,7 +264,7 @@ pub fn get_old_sub_info(store: &mut StorageRepo, folder_commit: U232) -> Option<
     Some(old_sub_commits)
 }
 
-pub fn generate_file_instructions(mut old_data: Vec<u8>, new_data: Vec<u8>) -> RepoFileType {
+pub fn generate_file_instructions(mut old_data: Vec<u8>, new_data: Vec<u8>) -> Result<RepoFileType, CommitError> {
     // Resizing old_data so we can compare
     if old_data.len() > new_data.len() {
         old_data = old_data[..new_data.len()].to_vec();
@@ -249,12 +272,99 @@ pub fn generate_file_instructions(mut old_data: Vec<u8>, new_data: Vec<u8>) -> R
         old_data.append(&mut vec![0_u8; new_data.len() - old_data.len()])
     }
 
-    let diff = io::generate_vec_diff(&old_data, &new_data).expect("They must be the same size, we insured that, didn't we?");
+    let pointer_size = get_pointer_size(new_data.len());
+    let instructions = generate_chunk_instructions(old_data, &new_data, pointer_size)?;
+
+    Ok(RepoFileType::Edit(instructions, pointer_size))
+}
+
+// Same as generate_file_instructions, but only a chunk of each file is loaded at a time
+// Moved blocks are only detected within the same chunk
+pub fn generate_streamed_instructions(store: &mut StorageRepo, old_commit: Option<U232>, location: &Path, new_size: usize) -> Result<RepoFileType, CommitError> {
+    let pointer_size = get_pointer_size(new_size);
+
+    // The old file gets build into the temp folder, so we can read it in chunks too
+    let temp = store.get_temp_build_folder();
+    io::create_folder(temp.as_path()).map_err(|e| CommitError::Io(e))?;
+
+    let res = stream_instructions(store, old_commit, location, new_size, temp.as_path(), pointer_size);
+    let _ = io::delete_folder(temp.as_path());
+
+    Ok(RepoFileType::Edit(res?, pointer_size))
+}
+
+fn stream_instructions(store: &mut StorageRepo, old_commit: Option<U232>, location: &Path, new_size: usize, temp: &Path, pointer_size: usize) -> Result<Vec<Instruction>, CommitError> {
+    let mut old_file = if let Some(old_id) = old_commit {
+        let path = store.write_file(old_id, temp).map_err(|e| CommitError::Io(e))?;
+        Some(File::open(path).map_err(|e| CommitError::Io(e))?)
+    } else {
+        None
+    };
+    let mut new_file = File::open(location).map_err(|e| CommitError::Io(e))?;
+
+    let mut instructions = Vec::<Instruction>::new();
+    let mut offset:usize = 0;
+    while offset < new_size {
+        let len = STREAM_CHUNK_SIZE.min(new_size - offset);
+
+        let new_chunk = io::read_chunk(&mut new_file, offset.try_into().unwrap(), len).map_err(|e| CommitError::Io(e))?;
+        let mut old_chunk = if let Some(file) = old_file.as_mut() {
+            io::read_chunk(file, offset.try_into().unwrap(), len).map_err(|e| CommitError::Io(e))?
+        } else {
+            Vec::<u8>::new()
+        };
+        old_chunk.resize(len, 0); // the old file can be shorter
+
+        let mut chunk_instructions = generate_chunk_instructions(old_chunk, &new_chunk, pointer_size)?;
+        for ins in chunk_instructions.iter_mut() {
+            ins.move_by(offset);
+        }
+        instructions.append(&mut chunk_instructions);
+
+        offset = offset + len;
+    }
+
+    Ok(instructions)
+}
+
+// Generates the instructions turning old_data into new_data, both have to be the same size
+fn generate_chunk_instructions(old_data: Vec<u8>, new_data: &Vec<u8>, pointer_size: usize) -> Result<Vec<Instruction>, CommitError> {
+    // Byte by byte comparison
+    let mut plain_data = old_data.clone();
+    let plain = generate_plain_instructions(&mut plain_data, new_data, pointer_size);
+
+    // Moved blocks get copied first, the rest gets filled in the same way as above
+    let mut copy_data = old_data;
+    let mut copied = generate_copy_instructions(&mut copy_data, new_data, pointer_size);
+
+    let (instructions, result) = if copied.is_empty() {
+        (plain, plain_data)
+    } else {
+        let mut rest = generate_plain_instructions(&mut copy_data, new_data, pointer_size);
+        copied.append(&mut rest);
+
+        if get_instructions_size(&copied, pointer_size) < get_instructions_size(&plain, pointer_size) {
+            (copied, copy_data)
+        } else {
+            (plain, plain_data)
+        }
+    };
+
+    // Check of the instructions:
+    if common::hash_data(new_data.as_slice()) != common::hash_data(result.as_slice()) {
+        return Err(CommitError::InstructionMismatch);
+    }
+
+    Ok(instructions)
+}
+
+// Generates instructions for every byte that differs at the same position, old_data has to be the same size as new_data
+// The instructions are also run on old_data
+fn generate_plain_instructions(old_data: &mut Vec<u8>, new_data: &Vec<u8>, pointer_size: usize) -> Vec<Instruction> {
+    let diff = io::generate_vec_diff(old_data, new_data).expect("They must be the same size, we insured that, didn't we?");
 
     let mut instructions = Vec::<Instruction>::new();
 
-        
-    let pointer_size: usize = ((new_data.len().ilog2()) / 8 + 1).try_into().unwrap();
     let ins_overhead = 1 + pointer_size + 1;
     // Type Byte + Pointer Bytes + Minimum Bytes to define Length
 
@@ -348,17 +458,496 @@ pub fn generate_file_instructions(mut old_data: Vec<u8>, new_data: Vec<u8>) -> R
         );
 
         // we test the instructions to see if we get the correct result in the end
-        ins.run_instruction(&mut old_data);
+        ins.run_instruction(old_data);
 
         instructions.push(ins);
 
         index = index + add_index; //- 1;
     }
 
-    // Check of the instructions:
-    if common::hash_data(new_data.as_slice()) != common::hash_data(old_data.as_slice()) {
-        panic!("TODO write error handling for when instructions are incorrectly generating, producing a file that does not match\nTarget Hash:{}\nResulting Hash:{}",common::hash_data(new_data.as_slice()) ,common::hash_data(old_data.as_slice()));
+    instructions
+}
+
+// Finds blocks of the new data that exist somewhere else in the old data, and generates copy instructions for them
+// Blocks of the old data are hashed as anchors, then we roll a hash over the new data to find them,
+// so inserted or deleted bytes only cost the instructions around them, not the whole rest of the file
+// The instructions are also run on old_data, which has to be the same size as new_data
+fn generate_copy_instructions(old_data: &mut Vec<u8>, new_data: &Vec<u8>, pointer_size: usize) -> Vec<Instruction> {
+    let mut instructions = Vec::<Instruction>::new();
+    if new_data.len() < COPY_BLOCK_SIZE * 2 {
+        return instructions;
+    }
+
+    let copy_overhead = 1 + pointer_size + 2 + pointer_size;
+    // Type Byte + Pointer Bytes + Length (usually up to 2 bytes) + Source Pointer Bytes
+
+    // Anchors
+    let mut anchors = HashMap::<u64, Vec<usize>>::new();
+    let mut pos = 0;
+    while pos + COPY_BLOCK_SIZE <= old_data.len() {
+        let block = &old_data[pos..pos + COPY_BLOCK_SIZE];
+        if block.iter().any(|b| *b != block[0]) { // Blocks of a single value are better handled by Set To
+            let list = anchors.entry(block_hash(block)).or_default();
+            if list.len() < MAX_BLOCK_CANDIDATES {
+                list.push(pos);
+            }
+        }
+
+        pos = pos + COPY_BLOCK_SIZE;
+    }
+
+    if anchors.is_empty() {
+        return instructions;
     }
 
-    RepoFileType::Edit(instructions, pointer_size)
-}
\ No newline at end of file
+    // Finding matches
+    let mut matches = Vec::<(usize, usize, usize)>::new(); // target, source, length
+    let top = HASH_BASE.wrapping_pow((COPY_BLOCK_SIZE - 1).try_into().unwrap());
+    let mut last_end = 0;
+    let mut index = 0;
+    let mut hash = block_hash(&new_data[..COPY_BLOCK_SIZE]);
+    while index + COPY_BLOCK_SIZE <= new_data.len() {
+        let mut best: Option<(usize, usize, usize)> = None;
+        if let Some(list) = anchors.get(&hash) {
+            for source in list.iter() {
+                let source = source.clone();
+                if source == index || old_data[source..source + COPY_BLOCK_SIZE] != new_data[index..index + COPY_BLOCK_SIZE] {
+                    continue; // Not moved, or just a hash collision
+                }
+
+                // Extending the match in both directions
+                let mut start = 0;
+                while index - start > last_end && source - start > 0 && old_data[source - start - 1] == new_data[index - start - 1] {
+                    start = start + 1;
+                }
+                let mut end = COPY_BLOCK_SIZE;
+                while index + end < new_data.len() && source + end < old_data.len() && old_data[source + end] == new_data[index + end] {
+                    end = end + 1;
+                }
+
+                if best.map_or(true, |(_, _, length)| length < start + end) {
+                    best = Some((index - start, source - start, start + end));
+                }
+            }
+        }
+
+        if let Some((target, source, length)) = best {
+            // Only worth it if enough bytes at the target actually changed, else we leave it to the plain instructions
+            let changed = (target..target + length).filter(|i| old_data[*i] != new_data[*i]).count();
+            if changed > copy_overhead * 2 {
+                matches.push((target, source, length));
+            }
+
+            index = target + length;
+            last_end = index;
+            if index + COPY_BLOCK_SIZE <= new_data.len() {
+                hash = block_hash(&new_data[index..index + COPY_BLOCK_SIZE]);
+            }
+            continue;
+        }
+
+        // Rolling the hash one byte further
+        if index + COPY_BLOCK_SIZE < new_data.len() {
+            hash = hash.wrapping_sub(top.wrapping_mul(new_data[index] as u64)).wrapping_mul(HASH_BASE).wrapping_add(new_data[index + COPY_BLOCK_SIZE] as u64);
+        }
+        index = index + 1;
+    }
+
+    // Move reads the data as constructed up to that point, so we can't read what was already overwritten
+    // Matches that keep their order in both files (the alignment) never read what another one of them writes,
+    // as long as blocks moving to the front are done front to back, and blocks moving back are done back to front
+    let aligned = align_matches(&matches);
+
+    let mut ordered = Vec::<(usize, usize, usize)>::new();
+    for (index, (target, source, length)) in matches.iter().enumerate() {
+        if aligned[index] && source > target {
+            ordered.push((target.clone(), source.clone(), length.clone()));
+        }
+    }
+    for (index, (target, source, length)) in matches.iter().enumerate().rev() {
+        if aligned[index] && source < target {
+            ordered.push((target.clone(), source.clone(), length.clone()));
+        }
+    }
+    // Blocks that moved across the alignment (like swapped blocks) only work if their source is still intact
+    for (index, item) in matches.iter().enumerate() {
+        if !aligned[index] {
+            ordered.push(item.clone());
+        }
+    }
+
+    let mut written = vec![false; old_data.len()];
+    for (target, source, length) in ordered {
+        if written[source..source + length].iter().any(|w| *w) {
+            continue; // Source was overwritten, the plain instructions will have to handle this
+        }
+
+        let ins = Instruction::new(target, length, Operation::Move(source));
+        ins.run_instruction(old_data);
+        written[target..target + length].fill(true);
+
+        instructions.push(ins);
+    }
+
+    instructions
+}
+
+// Picks the matches with the most bytes that are in the same order in old and new data, so insertions and deletions
+// The matches have to be ordered by target and not overlap, so we only need to check the sources
+fn align_matches(matches: &Vec<(usize, usize, usize)>) -> Vec<bool> {
+    // Fenwick tree over the end of the sources, holding the best chain (bytes, last match) ending before that point
+    let mut ends: Vec<usize> = matches.iter().map(|(_, source, length)| source + length).collect();
+    ends.sort();
+    ends.dedup();
+
+    let mut tree = vec![(0_usize, None); ends.len() + 1];
+    let mut previous = Vec::<Option<usize>>::new();
+    let mut best: (usize, Option<usize>) = (0, None);
+
+    for (index, (_, source, length)) in matches.iter().enumerate() {
+        // Best chain that ends before this source starts
+        let mut pos = ends.partition_point(|end| end <= source);
+        let mut prev: (usize, Option<usize>) = (0, None);
+        while pos > 0 {
+            if tree[pos].0 > prev.0 {
+                prev = tree[pos];
+            }
+            pos = pos - (pos & pos.wrapping_neg());
+        }
+        previous.push(prev.1);
+
+        let chain = (prev.0 + length, Some(index));
+        if chain.0 > best.0 {
+            best = chain;
+        }
+
+        let mut pos = ends.partition_point(|end| *end < source + length) + 1;
+        while pos < tree.len() {
+            if tree[pos].0 < chain.0 {
+                tree[pos] = chain;
+            }
+            pos = pos + (pos & pos.wrapping_neg());
+        }
+    }
+
+    let mut aligned = vec![false; matches.len()];
+    let mut current = best.1;
+    while let Some(index) = current {
+        aligned[index] = true;
+        current = previous[index];
+    }
+
+    aligned
+}
+
+fn block_hash(block: &[u8]) -> u64 {
+    let mut hash:u64 = 0;
+    for b in block {
+        hash = hash.wrapping_mul(HASH_BASE).wrapping_add(b.clone() as u64);
+    }
+    hash
+}
+
+fn get_instructions_size(instructions: &Vec<Instruction>, pointer_size: usize) -> usize {
+    instructions.iter().map(|ins| ins.to_bytes(pointer_size).len()).sum()
+}
+
+// Writes the whole file as replaces, used when generating instructions failed
+// The file is read in chunks, each becoming one replace
+pub fn generate_full_copy(location: &Path, new_size: usize) -> Result<RepoFileType, CommitError> {
+    let pointer_size = get_pointer_size(new_size);
+    let mut file = File::open(location).map_err(|e| CommitError::Io(e))?;
+
+    let mut instructions = Vec::<Instruction>::new();
+    let mut offset:usize = 0;
+    while offset < new_size {
+        let len = STREAM_CHUNK_SIZE.min(new_size - offset);
+        let data = io::read_chunk(&mut file, offset.try_into().unwrap(), len).map_err(|e| CommitError::Io(e))?;
+        if data.len() != len {
+            // File changed while we were reading it
+            return Err(CommitError::InstructionMismatch);
+        }
+
+        instructions.push(Instruction::new(offset, len, Operation::Replace(data)));
+        offset = offset + len;
+    }
+
+    Ok(RepoFileType::Edit(instructions, pointer_size))
+}
+
+fn get_pointer_size(file_size: usize) -> usize {
+    // empty files still need a pointer size
+    ((file_size.checked_ilog2().unwrap_or_default()) / 8 + 1).try_into().unwrap()
+}
+#[cfg(test)]
+mod tests {
+    use common::{U232, LargeU};
+
+    use crate::file_processing::{io, storage, repository_file::{RepoFile, RepoFileType, Instruction, Operation}, test_util::{get_root, pseudo_random}};
+    use super::{generate_file_instructions, generate_plain_instructions, get_pointer_size, get_instructions_size, generate_streamed_instructions, STREAM_CHUNK_SIZE};
+
+    fn apply(old_data: &Vec<u8>, size: usize, instructions: &Vec<Instruction>) -> Vec<u8> {
+        let mut data = old_data.clone();
+        data.resize(size, 0);
+        for ins in instructions {
+            ins.run_instruction(&mut data);
+        }
+        data
+    }
+
+    fn check(old_data: Vec<u8>, new_data: Vec<u8>) -> (usize, usize) {
+        let res = generate_file_instructions(old_data.clone(), new_data.clone());
+        let (instructions, pointer_size) = if let Ok(RepoFileType::Edit(ins, size)) = res {
+            (ins, size)
+        } else {
+            panic!("Generating instructions failed");
+        };
+        assert_eq!(apply(&old_data, new_data.len(), &instructions), new_data);
+
+        let mut plain_data = old_data.clone();
+        plain_data.resize(new_data.len(), 0);
+        let plain = generate_plain_instructions(&mut plain_data, &new_data, get_pointer_size(new_data.len()));
+
+        (get_instructions_size(&instructions, pointer_size), get_instructions_size(&plain, pointer_size))
+    }
+
+    #[test]
+    fn insertion_uses_copy() {
+        let old_data = pseudo_random(8192, 1);
+        let mut new_data = old_data[..1000].to_vec();
+        new_data.append(&mut pseudo_random(200, 2));
+        new_data.extend_from_slice(&old_data[1000..]);
+
+        let (copied, plain) = check(old_data, new_data);
+        assert!(copied * 10 < plain, "copy: {} plain: {}", copied, plain);
+    }
+
+    #[test]
+    fn removal_uses_copy() {
+        let old_data = pseudo_random(8192, 3);
+        let mut new_data = old_data[..500].to_vec();
+        new_data.extend_from_slice(&old_data[700..]);
+
+        let (copied, plain) = check(old_data, new_data);
+        assert!(copied * 10 < plain, "copy: {} plain: {}", copied, plain);
+    }
+
+    #[test]
+    fn swapped_blocks() {
+        let old_data = pseudo_random(4096, 4);
+        let mut new_data = old_data[2048..].to_vec();
+        new_data.extend_from_slice(&old_data[..2048]);
+
+        let (copied, plain) = check(old_data, new_data);
+        assert!(copied < plain, "copy: {} plain: {}", copied, plain);
+    }
+
+    #[test]
+    fn single_inserted_byte() {
+        let old_data = pseudo_random(8192, 9);
+        let mut new_data = old_data.clone();
+        new_data.insert(10, 0x42);
+
+        let (copied, plain) = check(old_data, new_data);
+        assert!(copied < 32, "copy: {} plain: {}", copied, plain);
+    }
+
+    #[test]
+    fn scattered_insertions_and_deletions() {
+        let old_data = pseudo_random(16384, 10);
+        let mut new_data = Vec::<u8>::new();
+        new_data.extend_from_slice(&old_data[..100]);
+        new_data.append(&mut pseudo_random(3, 11));
+        new_data.extend_from_slice(&old_data[100..5000]);
+        new_data.extend_from_slice(&old_data[5007..9000]);
+        new_data.append(&mut pseudo_random(40, 12));
+        new_data.extend_from_slice(&old_data[9000..]);
+
+        let (copied, plain) = check(old_data, new_data);
+        assert!(copied < 128, "copy: {} plain: {}", copied, plain);
+    }
+
+    // Save like data: a header, an inventory that grows or shrinks, and a large world section behind it
+    fn save_fixture(items: usize, seed: u64) -> Vec<u8> {
+        let mut data = b"SAVE0001".to_vec();
+        data.append(&mut (items as u32).to_be_bytes().to_vec());
+        for item in 0..items {
+            data.append(&mut (item as u32).to_be_bytes().to_vec());
+            data.append(&mut pseudo_random(12, item as u64));
+        }
+        data.append(&mut pseudo_random(64 * 1024, seed));
+        data
+    }
+
+    // Compares the old fixed offset algorithm against the aligned one on shifted saves
+    // This is a timing benchmark, run it with --ignored --nocapture for the numbers
+    #[test]
+    #[ignore]
+    fn benchmark_shifted_saves() {
+        let fixtures: Vec<(&str, Vec<u8>, Vec<u8>)> = vec![
+            ("item added", save_fixture(40, 13), save_fixture(41, 13)),
+            ("item removed", save_fixture(40, 14), save_fixture(39, 14)),
+            ("items added", save_fixture(10, 15), save_fixture(60, 15)),
+            ("byte inserted at start", pseudo_random(64 * 1024, 16), {
+                let mut data = pseudo_random(64 * 1024, 16);
+                data.insert(0, 1);
+                data
+            }),
+            ("in place edit", pseudo_random(64 * 1024, 17), {
+                let mut data = pseudo_random(64 * 1024, 17);
+                data[1000] = data[1000].wrapping_add(1);
+                data[30000] = data[30000].wrapping_add(1);
+                data
+            })
+        ];
+
+        println!("{:<24}{:>12}{:>12}{:>12}{:>12}", "fixture", "plain bytes", "plain ms", "align bytes", "align ms");
+        for (name, old_data, new_data) in fixtures {
+            let start = std::time::Instant::now();
+            let mut plain_data = old_data.clone();
+            plain_data.resize(new_data.len(), 0);
+            let plain = generate_plain_instructions(&mut plain_data, &new_data, get_pointer_size(new_data.len()));
+            let plain_time = start.elapsed();
+
+            let start = std::time::Instant::now();
+            let res = generate_file_instructions(old_data.clone(), new_data.clone());
+            let aligned_time = start.elapsed();
+
+            let (aligned, pointer_size) = if let Ok(RepoFileType::Edit(ins, size)) = res {
+                (ins, size)
+            } else {
+                panic!("Generating instructions failed for {}", name);
+            };
+            assert_eq!(apply(&old_data, new_data.len(), &aligned), new_data);
+
+            let plain_size = get_instructions_size(&plain, pointer_size);
+            let aligned_size = get_instructions_size(&aligned, pointer_size);
+            println!("{:<24}{:>12}{:>12.2}{:>12}{:>12.2}", name, plain_size, plain_time.as_secs_f64() * 1000.0, aligned_size, aligned_time.as_secs_f64() * 1000.0);
+
+            assert!(aligned_size <= plain_size, "{}", name);
+        }
+    }
+
+    // Copy keeps the byte by byte behaviour older commits were written with, only Move reads the source first
+    #[test]
+    fn overlapping_copy_and_move() {
+        let old_data: Vec<u8> = vec![1, 2, 3, 4, 5, 6, 7, 8];
+        let cases = vec![
+            (Operation::Copy(0), vec![1, 2, 1, 2, 1, 2, 7, 8]),
+            (Operation::Move(0), vec![1, 2, 1, 2, 3, 4, 7, 8])
+        ];
+
+        let root = get_root("overlap");
+        let mut file = root.clone();
+        file.push("save.sav");
+
+        for (operation, expected) in cases {
+            // Written and parsed again, like it would be read from a commit
+            let bytes = Instruction::new(2, 4, operation).to_bytes(1);
+            let mut commit = RepoFile::new(0, "test".to_string(), vec![RepoFileType::Resize(8), RepoFileType::EditNotProcessed(bytes)], U232::new(), U232::new());
+            commit.parse_edit_instructions(1);
+            let instructions = if let RepoFileType::Edit(ins, _) = commit.get_type(0x02) { ins.clone() } else { panic!("Edit was not parsed") };
+
+            assert_eq!(apply(&old_data, old_data.len(), &instructions), expected);
+
+            io::write_bytes(file.as_path(), old_data.clone()).unwrap();
+            let mut handle = std::fs::File::options().read(true).write(true).open(file.as_path()).unwrap();
+            for ins in instructions.iter() {
+                ins.run_instruction_on_file(&mut handle, old_data.len()).unwrap();
+            }
+            drop(handle);
+            assert_eq!(io::read_bytes(file.as_path()).unwrap(), expected);
+        }
+
+        let _ = io::delete_folder(root.as_path());
+    }
+
+    #[test]
+    fn unrelated_data() {
+        let (copied, plain) = check(pseudo_random(4096, 5), pseudo_random(3000, 6));
+        assert!(copied <= plain);
+    }
+
+    #[test]
+    fn smaller_commit_file_on_shifted_data() {
+        let root = get_root("copy");
+        let mut repo_path = root.clone();
+        repo_path.push("repo");
+        let mut file = root.clone();
+        file.push("save.sav");
+
+        let old_data = pseudo_random(16384, 7);
+        io::write_bytes(file.as_path(), old_data.clone()).unwrap();
+
+        let mut repo = storage::new_repo(repo_path.as_path(), "test".to_string()).unwrap();
+        let first = repo.create_commit(None, file.as_path(), false).unwrap();
+
+        // the inventory grew
+        let mut new_data = old_data[..4000].to_vec();
+        new_data.append(&mut pseudo_random(64, 8));
+        new_data.extend_from_slice(&old_data[4000..]);
+        io::write_bytes(file.as_path(), new_data.clone()).unwrap();
+        let second = repo.create_commit(Some(first), file.as_path(), false).unwrap();
+
+        let mut commit_file = repo_path.clone();
+        commit_file.push(common::bytes_to_hex_string(common::LargeU::to_be_bytes(&second)));
+        let size = std::fs::metadata(commit_file.as_path()).unwrap().len();
+        // without copies this would be a replace of everything behind the insertion
+        assert!(size * 8 < new_data.len() as u64, "commit file has {} bytes", size);
+
+        // and it still builds
+        let mut out = root.clone();
+        out.push("out");
+        io::create_folder(out.as_path()).unwrap();
+        let mut repo = storage::read_storage_info(repo_path.as_path()).unwrap();
+        assert!(repo.build_commit(second, out.as_path()));
+        out.push("save.sav");
+        assert_eq!(io::read_bytes(out.as_path()).unwrap(), new_data);
+
+        let _ = io::delete_folder(root.as_path());
+    }
+
+    #[test]
+    fn streamed_instructions_match_in_memory() {
+        let root = get_root("stream");
+        let mut repo_path = root.clone();
+        repo_path.push("repo");
+        let mut file = root.clone();
+        file.push("state.bin");
+
+        // spans more then one chunk, with changes on both sides of the chunk border
+        let mut old_data = vec![0_u8; STREAM_CHUNK_SIZE + 4096];
+        old_data[100..4196].copy_from_slice(&pseudo_random(4096, 20));
+        old_data[STREAM_CHUNK_SIZE - 2048..STREAM_CHUNK_SIZE + 2048].copy_from_slice(&pseudo_random(4096, 21));
+        io::write_bytes(file.as_path(), old_data.clone()).unwrap();
+
+        let mut repo = storage::new_repo(repo_path.as_path(), "test".to_string()).unwrap();
+        let first = repo.create_commit(None, file.as_path(), false).unwrap();
+
+        let mut new_data = old_data.clone();
+        new_data[STREAM_CHUNK_SIZE - 10..STREAM_CHUNK_SIZE + 10].fill(7);
+        new_data.copy_within(100..1124, 200); // a moved block inside the first chunk
+        new_data.append(&mut pseudo_random(100, 22));
+        io::write_bytes(file.as_path(), new_data.clone()).unwrap();
+
+        let edit = generate_streamed_instructions(&mut repo, Some(first), file.as_path(), new_data.len()).unwrap();
+        let (instructions, pointer_size) = if let RepoFileType::Edit(ins, size) = edit {
+            (ins, size)
+        } else {
+            panic!("Streamed generation did not return an edit");
+        };
+        assert_eq!(pointer_size, get_pointer_size(new_data.len()));
+        assert_eq!(apply(&old_data, new_data.len(), &instructions), new_data);
+
+        // building on disk gives the same file as in memory
+        let second = repo.create_commit(Some(first), file.as_path(), false).unwrap();
+        let mut out = root.clone();
+        out.push("out");
+        io::create_folder(out.as_path()).unwrap();
+        let path = repo.write_file(second, out.as_path()).unwrap();
+        assert_eq!(io::read_bytes(path.as_path()).unwrap(), repo.build_file(second, out.as_path()).unwrap().1);
+        assert_eq!(io::hash_file(path.as_path()).unwrap(), common::hash_data(new_data.as_slice()));
+
+        let _ = io::delete_folder(root.as_path());
+    }
+}
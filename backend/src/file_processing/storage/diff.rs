@@ -0,0 +1,188 @@
+use std::path::Path;
+
+use common::{U232, data::{CommitDiff, TreeEntry, RenamedFile, FileDiff, ByteRange}};
+
+use crate::file_processing::storage::{StorageRepo, LARGE_FILE_SIZE};
+
+// Limits how many bytes of a single file get returned, the ranges are still complete up to that point
+const MAX_DIFF_BYTES:usize = 64 * 1024;
+
+pub fn diff_commits(store: &mut StorageRepo, from: U232, to: U232) -> Option<CommitDiff> {
+    let mut diff = CommitDiff {
+        added: Vec::<String>::new(),
+        removed: Vec::<String>::new(),
+        renamed: Vec::<RenamedFile>::new(),
+        changed: Vec::<FileDiff>::new()
+    };
+
+    if from == to {
+        return Some(diff);
+    }
+
+    let old_tree = store.get_commit_tree(from)?;
+    let new_tree = store.get_commit_tree(to)?;
+
+    if old_tree.content.is_some() && new_tree.content.is_some() {
+        // The root folder name is not part of the path
+        diff_folders(store, &old_tree, &new_tree, "", "", &mut diff);
+    } else {
+        diff_entries(store, &old_tree, &new_tree, old_tree.name.clone(), new_tree.name.clone(), &mut diff);
+    }
+
+    Some(diff)
+}
+
+fn join_path(prefix: &str, name: &String) -> String {
+    if prefix.is_empty() {
+        name.clone()
+    } else {
+        format!("{}/{}", prefix, name)
+    }
+}
+
+// Compares two entries that belong together (same name, or renamed)
+fn diff_entries(store: &mut StorageRepo, old: &TreeEntry, new: &TreeEntry, old_path: String, new_path: String, diff: &mut CommitDiff) {
+    if old.content.is_some() != new.content.is_some() {
+        // A file was replaced by a folder or the other way around
+        list_entry(old, old_path, &mut diff.removed);
+        list_entry(new, new_path, &mut diff.added);
+        return;
+    }
+
+    if old_path != new_path {
+        diff.renamed.push(RenamedFile { from: old_path.clone(), to: new_path.clone() });
+    }
+
+    if old.commit == new.commit {
+        return; // Same commit, nothing changed (including the content of folders)
+    }
+
+    if old.content.is_some() {
+        diff_folders(store, old, new, old_path.as_str(), new_path.as_str(), diff);
+    } else if !old.hash.equal_224(&new.hash) {
+        diff.changed.push(diff_files(store, old.commit, new.commit, new_path));
+    }
+}
+
+fn diff_folders(store: &mut StorageRepo, old: &TreeEntry, new: &TreeEntry, old_prefix: &str, new_prefix: &str, diff: &mut CommitDiff) {
+    let mut old_items = old.content.clone().unwrap_or_default();
+    let mut new_items = Vec::<TreeEntry>::new();
+
+    // Matching by name first
+    for item in new.content.clone().unwrap_or_default() {
+        let mut found = None;
+        let mut index = 0;
+        for old_item in old_items.iter() {
+            if old_item.name == item.name && old_item.content.is_some() == item.content.is_some() {
+                found = Some(index);
+                break;
+            }
+            index += 1;
+        }
+
+        if let Some(index) = found {
+            let old_item = old_items.remove(index);
+            diff_entries(store, &old_item, &item, join_path(old_prefix, &old_item.name), join_path(new_prefix, &item.name), diff);
+        } else {
+            new_items.push(item);
+        }
+    }
+
+    // The remaining new items could be renames, which we find by them continuing the history of an old item, or having the same content
+    for item in new_items {
+        let mut found = None;
+        let mut index = 0;
+        for old_item in old_items.iter() {
+            if old_item.content.is_some() == item.content.is_some() {
+                if store.is_ancestor(old_item.commit, item.commit) || (item.content.is_none() && old_item.hash.equal_224(&item.hash)) {
+                    found = Some(index);
+                    break;
+                }
+            }
+            index += 1;
+        }
+
+        if let Some(index) = found {
+            let old_item = old_items.remove(index);
+            diff_entries(store, &old_item, &item, join_path(old_prefix, &old_item.name), join_path(new_prefix, &item.name), diff);
+        } else {
+            list_entry(&item, join_path(new_prefix, &item.name), &mut diff.added);
+        }
+    }
+
+    for old_item in old_items {
+        list_entry(&old_item, join_path(old_prefix, &old_item.name), &mut diff.removed);
+    }
+}
+
+// Adds the entry, and for folders all their content
+fn list_entry(entry: &TreeEntry, path: String, list: &mut Vec<String>) {
+    if let Some(content) = &entry.content {
+        for item in content {
+            list_entry(item, join_path(path.as_str(), &item.name), list);
+        }
+    }
+
+    list.push(path);
+}
+
+fn diff_files(store: &mut StorageRepo, old: U232, new: U232, path: String) -> FileDiff {
+    // Large files would have to be loaded into memory, so we only report the sizes
+    let old_size = store.get_file_size(old);
+    let new_size = store.get_file_size(new);
+    if old_size > LARGE_FILE_SIZE || new_size > LARGE_FILE_SIZE {
+        return FileDiff { path, old_size: old_size as u64, new_size: new_size as u64, ranges: Vec::<ByteRange>::new(), truncated: true };
+    }
+
+    // Files that can not be build are only reported by their sizes too
+    let (old_data, new_data) = if let (Ok((_, old_data)), Ok((_, new_data))) = (store.build_file(old, Path::new("")), store.build_file(new, Path::new(""))) {
+        (old_data, new_data)
+    } else {
+        return FileDiff { path, old_size: old_size as u64, new_size: new_size as u64, ranges: Vec::<ByteRange>::new(), truncated: true };
+    };
+
+    let mut ranges = Vec::<ByteRange>::new();
+    let mut byte_count = 0;
+    let mut truncated = false;
+
+    let min_len = if old_data.len() < new_data.len() { old_data.len() } else { new_data.len() };
+
+    // Grouping differing bytes into ranges
+    let mut index = 0;
+    while index < min_len {
+        if old_data[index] != new_data[index] {
+            let start = index;
+            while index < min_len && old_data[index] != new_data[index] {
+                index += 1;
+            }
+
+            if byte_count + (index - start) > MAX_DIFF_BYTES {
+                truncated = true;
+                break;
+            }
+            byte_count += index - start;
+
+            ranges.push(ByteRange { offset: start as u64, old_bytes: old_data[start..index].to_vec(), new_bytes: new_data[start..index].to_vec() });
+        } else {
+            index += 1;
+        }
+    }
+
+    // The resize is one range, with either the cut off or added bytes
+    if !truncated && old_data.len() != new_data.len() {
+        let tail = old_data.len().abs_diff(new_data.len());
+        if byte_count + tail > MAX_DIFF_BYTES {
+            truncated = true;
+        } else {
+            ranges.push(ByteRange { offset: min_len as u64, old_bytes: old_data[min_len..].to_vec(), new_bytes: new_data[min_len..].to_vec() });
+        }
+    }
+
+    FileDiff {
+        path,
+        old_size: old_data.len() as u64,
+        new_size: new_data.len() as u64,
+        ranges,
+        truncated
+    }
+}
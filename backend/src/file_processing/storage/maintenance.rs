@@ -0,0 +1,310 @@
+use std::{collections::HashSet, path::{Path, PathBuf}, panic::{self, AssertUnwindSafe}, time::UNIX_EPOCH};
+
+use common::{U232, LargeU, data::{GarbageReport, VerifyReport, CommitProblem, VerifyProblem}};
+
+use crate::file_processing::{io, repository_file::RepoFileType, storage::StorageRepo};
+
+// Marks every commit that can be reached from a branch, walking previous commits and folder children
+// Ids that are referenced but fail to load are still marked, so a broken file is never collected
+pub fn get_reachable_commits(store: &mut StorageRepo) -> HashSet<U232> {
+    store.update_header_and_branches();
+
+    let mut reachable = HashSet::<U232>::new();
+    let mut stack = Vec::<U232>::new();
+    for branch in store.get_branches() {
+        stack.push(branch.get_previous_commit());
+    }
+
+    while let Some(id) = stack.pop() {
+        if id == U232::new() || reachable.contains(&id) {
+            continue;
+        }
+        reachable.insert(id);
+
+        if let Ok(commit) = store.get_commit(id) {
+            let commit = commit.lock().unwrap();
+            stack.push(commit.get_previous_commit());
+
+            if let RepoFileType::Folder(children) = commit.get_type(0x0F) {
+                for child in children {
+                    stack.push(child.clone());
+                }
+            }
+        }
+    }
+
+    reachable
+}
+
+// All files in the repo folder that are named like a commit
+pub fn get_commit_files(store: &StorageRepo) -> Vec<(U232, PathBuf)> {
+    let mut list = Vec::<(U232, PathBuf)>::new();
+
+    for file in io::get_folder_content(PathBuf::from(store.get_folder()).as_path()) {
+        if !file.is_file() {
+            continue;
+        }
+
+        if let Some(name) = file.file_name().and_then(|n| n.to_str()).map(|n| n.to_string()) {
+            if name.len() == U232::NUM_OF_BYTES * 2 && name.chars().all(|c| c.is_ascii_hexdigit()) {
+                let id = U232::from_u8arr(common::hex_string_to_bytes(&name).as_slice());
+                list.push((id, file));
+            }
+        }
+    }
+
+    list
+}
+
+// Commits are created before the client pushes them onto a branch, so files younger than this are never collected
+pub const GARBAGE_GRACE_PERIOD:u64 = 24 * 60 * 60;
+
+// If the file was written within the grace period before now (unix time in s)
+pub fn is_recent(file: &Path, now: u64) -> bool {
+    let modified = file.metadata().and_then(|m| m.modified()).ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs());
+
+    if let Some(modified) = modified {
+        modified + GARBAGE_GRACE_PERIOD > now
+    } else {
+        true // better keep a file we can't tell the age of
+    }
+}
+
+pub fn collect_garbage(store: &mut StorageRepo, now: u64, dry_run: bool) -> GarbageReport {
+    let reachable = get_reachable_commits(store);
+
+    let mut report = GarbageReport {
+        unreachable: Vec::<U232>::new(),
+        bytes: 0,
+        deleted: 0,
+        dry_run
+    };
+
+    let mut remaining = Vec::<U232>::new();
+    for (id, file) in get_commit_files(store) {
+        if reachable.contains(&id) || is_recent(file.as_path(), now) {
+            remaining.push(id);
+            continue;
+        }
+
+        let size = file.metadata().map(|m| m.len()).unwrap_or_default();
+
+        if !dry_run {
+            if io::delete_file(file.as_path()).is_err() {
+                // We leave it out of the report, it is still there after all
+                continue;
+            }
+            store.commits.remove(&id);
+            report.deleted = report.deleted + 1;
+        }
+
+        report.unreachable.push(id);
+        report.bytes = report.bytes + size;
+    }
+
+    // Blobs only used by the deleted commits are no longer referenced by this repo
+    if !dry_run {
+        let mut keep = HashSet::<U232>::new();
+        for id in remaining {
+            if let Ok(commit) = store.get_commit(id) {
+                if let RepoFileType::Blob(blob) = commit.lock().unwrap().get_type(0x07) {
+                    keep.insert(blob.clone());
+                }
+            }
+        }
+        store.release_blobs(&keep);
+    }
+
+    report
+}
+
+enum VerifyType {
+    File,
+    Folder(Vec<U232>),
+    Delete
+}
+
+// Walks everything reachable from the branches and rebuilds every commit, comparing it to the hash in it's name
+// Broken files can panic while decoding or building, so we catch these, best to run this on a freshly read repo
+pub fn verify(store: &mut StorageRepo) -> VerifyReport {
+    store.update_header_and_branches();
+
+    let mut report = VerifyReport {
+        checked: 0,
+        problems: Vec::<CommitProblem>::new()
+    };
+
+    let mut visited = HashSet::<U232>::new();
+    let mut stack = Vec::<(U232, Option<U232>)>::new(); // commit and who referenced it
+    for branch in store.get_branches() {
+        stack.push((branch.get_previous_commit(), None));
+    }
+
+    while let Some((id, referenced_by)) = stack.pop() {
+        if id == U232::new() || visited.contains(&id) {
+            continue;
+        }
+        visited.insert(id);
+        report.checked = report.checked + 1;
+
+        let mut file = PathBuf::from(store.get_folder());
+        file.push(common::bytes_to_hex_string(id.to_be_bytes()));
+        if !file.is_file() {
+            report.problems.push(CommitProblem { commit: id, problem: VerifyProblem::Missing, referenced_by });
+            continue;
+        }
+
+        // Reading the file
+        let res = panic::catch_unwind(AssertUnwindSafe(|| {
+            let commit = store.get_commit(id).ok()?;
+            let commit = commit.lock().unwrap();
+
+            let typ = if let RepoFileType::Delete = commit.get_type(0x05) {
+                VerifyType::Delete
+            } else if let RepoFileType::Folder(children) = commit.get_type(0x0F) {
+                VerifyType::Folder(children.clone())
+            } else if let RepoFileType::Edit(_, _) | RepoFileType::EditNotProcessed(_) | RepoFileType::EditOnDisk(_, _) = commit.get_type(0x02) {
+                VerifyType::File
+            } else if let RepoFileType::Blob(_) = commit.get_type(0x07) {
+                VerifyType::File
+            } else if !matches!(commit.get_type(0x08), RepoFileType::None) || !matches!(commit.get_type(0x04), RepoFileType::None) {
+                VerifyType::File // Only resized or renamed
+            } else {
+                return None;
+            };
+
+            Some((commit.get_previous_commit(), typ))
+        }));
+
+        let (prev, typ) = if let Ok(Some(val)) = res {
+            val
+        } else {
+            report.problems.push(CommitProblem { commit: id, problem: VerifyProblem::Unparsable, referenced_by });
+            continue;
+        };
+
+        stack.push((prev, Some(id)));
+        if let VerifyType::Folder(children) = &typ {
+            for child in children {
+                stack.push((child.clone(), Some(id)));
+            }
+        }
+
+        // Rebuilding and comparing
+        let res = panic::catch_unwind(AssertUnwindSafe(|| {
+            match &typ {
+                VerifyType::File => {
+                    store.hash_file_commit(id).map(|hash| hash.equal_224(&id)).unwrap_or(false)
+                },
+                VerifyType::Folder(children) => {
+                    let mut appended = Vec::<u8>::new();
+                    for child in children {
+                        appended.append(&mut child.to_be_bytes().to_vec());
+                    }
+                    common::hash_data(appended.as_slice()).equal_224(&id)
+                },
+                VerifyType::Delete => prev.equal_224(&id) // Deletes reuse the hash of what they delete
+            }
+        }));
+
+        match res {
+            Ok(true) => {},
+            Ok(false) => report.problems.push(CommitProblem { commit: id, problem: VerifyProblem::HashMismatch, referenced_by }),
+            Err(_) => report.problems.push(CommitProblem { commit: id, problem: VerifyProblem::BuildFailed, referenced_by })
+        }
+    }
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use common::U232;
+
+    use common::data::Compression;
+
+    use crate::file_processing::{io, repository_file::{self, RepoFileType}, storage::{self, BranchUpdate}, test_util::{get_root, pseudo_random}};
+    use super::{get_commit_files, GARBAGE_GRACE_PERIOD};
+
+    fn get_now() -> u64 {
+        chrono::Utc::now().timestamp().try_into().unwrap()
+    }
+
+    #[test]
+    fn unpushed_commit_survives_gc() {
+        let root = get_root("gc");
+        let mut repo_path = root.clone();
+        repo_path.push("repo");
+        let mut file = root.clone();
+        file.push("save.sav");
+
+        let mut repo = storage::new_repo(repo_path.as_path(), "test".to_string()).unwrap();
+        io::write_bytes(file.as_path(), b"pushed".repeat(100)).unwrap();
+        let pushed = repo.create_commit(None, file.as_path(), false).unwrap();
+        assert!(matches!(repo.create_branch("master".to_string(), pushed), BranchUpdate::Ok));
+
+        // Created through /repo/commit/create, but the client did not push it yet
+        io::write_bytes(file.as_path(), b"waiting".repeat(100)).unwrap();
+        let waiting = repo.create_commit(Some(pushed), file.as_path(), false).unwrap();
+
+        let report = repo.collect_garbage(get_now(), false);
+        assert_eq!(report.deleted, 0);
+        assert!(report.unreachable.is_empty());
+        assert!(get_commit_files(&repo).iter().any(|(id, _)| *id == waiting));
+        assert!(matches!(repo.push_commit_onto_branch(waiting, "master".to_string(), false), BranchUpdate::Ok));
+
+        // Once the grace period is over, unreachable commits are collected
+        io::write_bytes(file.as_path(), b"abandoned".repeat(100)).unwrap();
+        let abandoned = repo.create_commit(Some(waiting), file.as_path(), false).unwrap();
+
+        let report = repo.collect_garbage(get_now() + GARBAGE_GRACE_PERIOD + 60, false);
+        assert_eq!(report.unreachable, vec![abandoned]);
+        assert_eq!(report.deleted, 1);
+
+        let files: Vec<U232> = get_commit_files(&repo).into_iter().map(|(id, _)| id).collect();
+        assert!(files.contains(&pushed) && files.contains(&waiting) && !files.contains(&abandoned));
+        assert!(repo.verify().problems.is_empty());
+
+        let _ = io::delete_folder(root.as_path());
+    }
+
+    #[test]
+    fn large_commits_stay_on_disk() {
+        for compression in [Compression::None, Compression::Zstd] {
+            let root = get_root("large");
+            let mut repo_path = root.clone();
+            repo_path.push("repo");
+            let mut file = root.clone();
+            file.push("save.sav");
+
+            let mut repo = storage::new_repo(repo_path.as_path(), "test".to_string()).unwrap();
+            repo.set_compression(compression);
+
+            // Does not compress, so the full copy stays larger then what is cached
+            let mut data = pseudo_random(3 * repository_file::MAX_CACHED_SIZE, 7);
+            io::write_bytes(file.as_path(), data.clone()).unwrap();
+            let first = repo.create_commit(None, file.as_path(), false).unwrap();
+
+            data[10..20].fill(0xFF);
+            io::write_bytes(file.as_path(), data.clone()).unwrap();
+            let second = repo.create_commit(Some(first), file.as_path(), false).unwrap();
+            assert!(matches!(repo.create_branch("master".to_string(), second), BranchUpdate::Ok));
+
+            // The full copy is not kept in memory, but read from the file when building
+            let commit = repo.get_commit(first).unwrap().lock().unwrap().clone();
+            assert!(matches!(commit.get_type(0x02), RepoFileType::EditOnDisk(_, _)));
+
+            let mut target = root.clone();
+            target.push("build");
+            io::create_folder(target.as_path()).unwrap();
+            assert!(repo.build_commit(second, target.as_path()));
+            target.push("save.sav");
+            assert!(io::read_bytes(target.as_path()).unwrap() == data);
+            assert!(repo.verify().problems.is_empty());
+
+            let _ = io::delete_folder(root.as_path());
+        }
+    }
+}
@@ -0,0 +1,388 @@
+use std::{collections::{HashMap, HashSet}, sync::Mutex};
+
+use common::{U232, LargeU, data::{RetentionPolicy, RetentionReport, GarbageReport}};
+
+use crate::file_processing::{io, repository_file::{RepoFileType, CommitInfo}, storage::{StorageRepo, maintenance}};
+
+const DAY:u64 = 24 * 60 * 60;
+const WEEK:u64 = 7 * DAY;
+
+enum Rebase {
+    File,
+    Folder(String) // name of the folder, as the NewFolder that set it might get pruned
+}
+
+// Walks the history of every branch and picks the root commits to keep, the newest commit of a branch always stays
+// Returns the kept commits and how many were dropped
+fn select_commits(store: &mut StorageRepo, policy: &RetentionPolicy, now: u64) -> (HashSet<U232>, usize) {
+    let tips: Vec<U232> = store.get_branches().iter().map(|b| b.get_previous_commit()).collect();
+
+    let mut kept = HashSet::<U232>::new();
+    let mut seen = HashSet::<U232>::new();
+    for tip in tips {
+        if tip == U232::new() {
+            continue;
+        }
+
+        let (log, _) = store.get_commit_log(tip, usize::MAX, usize::MAX);
+
+        // The log is newest first, so the first commit we see in a day/week is the one we keep
+        let mut days = HashSet::<u64>::new();
+        let mut weeks = HashSet::<u64>::new();
+        let mut index = 0;
+        for entry in log {
+            seen.insert(entry.id);
+
+            let keep = if index == 0 {
+                true
+            } else if let Some(time) = entry.timestamp {
+                let age = now.saturating_sub(time);
+
+                if age < policy.keep_all_days.saturating_mul(DAY) {
+                    true
+                } else if age < policy.daily_days.saturating_mul(DAY) {
+                    days.insert(time / DAY)
+                } else if policy.weekly_days.map(|w| age < w.saturating_mul(DAY)).unwrap_or(true) {
+                    weeks.insert(time / WEEK)
+                } else {
+                    false
+                }
+            } else {
+                true // without a time we can't tell how old it is
+            };
+
+            if keep {
+                kept.insert(entry.id);
+            }
+            index = index + 1;
+        }
+    }
+
+    let pruned = seen.iter().filter(|id| !kept.contains(id)).count();
+    (kept, pruned)
+}
+
+// Adds everything the kept root commits need to be build: the content of folders, and what a delete refers to
+fn collect_needed(store: &mut StorageRepo, roots: &HashSet<U232>) -> HashSet<U232> {
+    let mut needed = HashSet::<U232>::new();
+    let mut stack: Vec<U232> = roots.iter().cloned().collect();
+
+    while let Some(id) = stack.pop() {
+        if id == U232::new() || needed.contains(&id) {
+            continue;
+        }
+        needed.insert(id);
+
+        if let Ok(commit) = store.get_commit(id) {
+            let commit = commit.lock().unwrap();
+            if let RepoFileType::Folder(children) = commit.get_type(0x0F) {
+                for child in children {
+                    stack.push(child.clone());
+                }
+            } else if let RepoFileType::Delete = commit.get_type(0x05) {
+                stack.push(commit.get_previous_commit());
+            }
+        }
+    }
+
+    needed
+}
+
+fn get_folder_name(store: &mut StorageRepo, commit: U232) -> Option<String> {
+    for item in store.get_commit_chain(commit) {
+        if let RepoFileType::NewFolder(name) = item.lock().unwrap().get_type(0x0D) {
+            return Some(name.clone());
+        }
+    }
+
+    None
+}
+
+// For every needed commit whose previous commit gets pruned, finds the closest ancestor that survives
+fn plan_rebases(store: &mut StorageRepo, needed: &HashSet<U232>) -> Vec<(U232, U232, Rebase)> {
+    let mut plan = Vec::<(U232, U232, Rebase)>::new();
+
+    for id in needed.iter() {
+        let (prev, typ) = if let Ok(commit) = store.get_commit(id.clone()) {
+            let commit = commit.lock().unwrap();
+
+            let typ = if let RepoFileType::Delete = commit.get_type(0x05) {
+                continue; // what they delete is always kept
+            } else if let RepoFileType::Folder(_) = commit.get_type(0x0F) {
+                None
+            } else {
+                Some(Rebase::File)
+            };
+            (commit.get_previous_commit(), typ)
+        } else {
+            continue;
+        };
+
+        let mut new_prev = prev;
+        while new_prev != U232::new() && !needed.contains(&new_prev) {
+            new_prev = if let Ok(commit) = store.get_commit(new_prev) {
+                commit.lock().unwrap().get_previous_commit()
+            } else {
+                break;
+            };
+        }
+
+        if new_prev == prev || (new_prev != U232::new() && !needed.contains(&new_prev)) {
+            continue; // nothing to do, or the history is broken and we leave it alone
+        }
+
+        let typ = if let Some(typ) = typ {
+            typ
+        } else if let Some(name) = get_folder_name(store, id.clone()) {
+            Rebase::Folder(name)
+        } else {
+            continue;
+        };
+
+        plan.push((id.clone(), new_prev, typ));
+    }
+
+    plan
+}
+
+// Writes the file out and stores it whole, so it no longer depends on the commits before it
+fn rebase_file(store: &mut StorageRepo, id: U232, new_prev: U232) -> bool {
+    let is_new = if let Ok(commit) = store.get_commit(id) {
+        commit.lock().unwrap().is_new()
+    } else {
+        return false;
+    };
+
+    let mut content = Vec::<RepoFileType>::new();
+    if !is_new {
+        let temp = store.get_temp_build_folder();
+        if io::create_folder(temp.as_path()).is_err() {
+            return false;
+        }
+
+        let res = (|| {
+            let path = store.write_file(id, temp.as_path()).ok()?;
+            let size = io::u64_to_usize(io::get_file_size(path.as_path()).ok()?);
+            let hash = io::hash_file(path.as_path()).ok()?;
+            if !hash.equal_224(&id) {
+                return None; // better keep the old chain then loosing the file
+            }
+
+            let name = path.file_name()?.to_str()?.to_string();
+            let data = if store.add_blob(&hash, path.as_path(), size) {
+                RepoFileType::Blob(hash)
+            } else {
+                super::commit_generation::generate_full_copy(path.as_path(), size).ok()?
+            };
+
+            Some(vec![RepoFileType::NewFile, RepoFileType::Resize(size.try_into().ok()?), RepoFileType::Rename(name), data])
+        })();
+        let _ = io::delete_folder(temp.as_path());
+
+        if let Some(full) = res {
+            content = full;
+        } else {
+            return false;
+        }
+    }
+
+    let commit = if let Ok(commit) = store.get_commit(id) {
+        commit.lock().unwrap()
+    } else {
+        return false;
+    };
+
+    let new_commit = if is_new {
+        commit.clone_with_prev_commit(new_prev)
+    } else {
+        // the commit info stays, the time stamp gets fixed afterwards
+        if let RepoFileType::CommitInfo(info) = commit.get_type(0x10) {
+            content.push(RepoFileType::CommitInfo(info.clone()));
+        }
+        commit.clone_with_content(content).clone_with_prev_commit(new_prev)
+    };
+    drop(commit);
+
+    store.insert_commit(Mutex::new(new_commit));
+    true
+}
+
+// Folders only need to point further back, unless they lose the commit they got their name from
+fn rebase_folder(store: &mut StorageRepo, id: U232, new_prev: U232, name: String) -> bool {
+    let inherited = if new_prev == U232::new() {
+        None
+    } else {
+        get_folder_name(store, new_prev)
+    };
+
+    let commit = if let Ok(commit) = store.get_commit(id) {
+        commit.lock().unwrap()
+    } else {
+        return false;
+    };
+
+    let mut content = commit.get_content().clone();
+    if !commit.is_new() && inherited != Some(name.clone()) {
+        content.insert(0, RepoFileType::NewFolder(name));
+    }
+
+    let new_commit = commit.clone_with_content(content).clone_with_prev_commit(new_prev);
+    drop(commit);
+
+    store.insert_commit(Mutex::new(new_commit));
+    true
+}
+
+// Prunes the history of all branches according to the policy
+// Surviving commits are re-based onto the closest surviving ancestor, file commits are turned into full copies for this
+// Afterwards everything no longer reachable is collected
+pub fn apply_retention(store: &mut StorageRepo, policy: &RetentionPolicy, now: u64, dry_run: bool) -> RetentionReport {
+    store.update_header_and_branches();
+
+    let (roots, pruned) = select_commits(store, policy, now);
+    let needed = collect_needed(store, &roots);
+    let plan = plan_rebases(store, &needed);
+
+    if dry_run {
+        // Everything not needed would be collected
+        let mut garbage = GarbageReport { unreachable: Vec::<U232>::new(), bytes: 0, deleted: 0, dry_run };
+        for (id, file) in maintenance::get_commit_files(store) {
+            if !needed.contains(&id) && !maintenance::is_recent(file.as_path(), now) {
+                garbage.unreachable.push(id);
+                garbage.bytes = garbage.bytes + file.metadata().map(|m| m.len()).unwrap_or_default();
+            }
+        }
+
+        return RetentionReport { kept: roots.len(), pruned, rebased: plan.len(), garbage, dry_run };
+    }
+
+    // Time stamps are relative to the previous commit info, so we remember the absolute ones before changing the history
+    let mut infos = HashMap::<U232, CommitInfo>::new();
+    for id in needed.iter() {
+        let has_info = if let Ok(commit) = store.get_commit(id.clone()) {
+            matches!(commit.lock().unwrap().get_type(0x10), RepoFileType::CommitInfo(_))
+        } else {
+            false
+        };
+
+        if has_info {
+            if let Some(info) = store.get_commit_info(id.clone()) {
+                infos.insert(id.clone(), info);
+            }
+        }
+    }
+
+    let mut rebased = 0;
+    for (id, new_prev, typ) in plan {
+        let done = match typ {
+            Rebase::File => rebase_file(store, id, new_prev),
+            Rebase::Folder(name) => rebase_folder(store, id, new_prev, name)
+        };
+
+        if done {
+            rebased = rebased + 1;
+        }
+    }
+
+    // Restoring the time stamps, older commits first, as the newer ones are relative to them
+    let mut order: Vec<(usize, U232)> = infos.keys().map(|id| (store.get_commit_chain(id.clone()).len(), id.clone())).collect();
+    order.sort_by_key(|(depth, _)| *depth);
+    for (_, id) in order {
+        if let Some(info) = infos.remove(&id) {
+            store.set_commit_info(id, info);
+        }
+    }
+
+    let garbage = maintenance::collect_garbage(store, now, false);
+
+    RetentionReport { kept: roots.len(), pruned, rebased, garbage, dry_run }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use common::{U232, LargeU, data::RetentionPolicy};
+
+    use crate::file_processing::{io, repository_file::CommitInfo, storage::{self, BranchUpdate, maintenance::get_commit_files}, test_util::get_root};
+    use super::DAY;
+
+    const COMMITS:u64 = 12;
+
+    fn write_save(folder: &PathBuf, index: u64) -> Vec<u8> {
+        io::create_folder(folder.as_path()).unwrap();
+        let mut file = folder.clone();
+        file.push("game.sav");
+        let data = format!("save number {};", index).repeat(50).into_bytes();
+        io::write_bytes(file.as_path(), data.clone()).unwrap();
+        data
+    }
+
+    #[test]
+    fn retention_keeps_buildable_history() {
+        let root = get_root("retention");
+        let mut repo_path = root.clone();
+        repo_path.push("repo");
+        let mut folder = root.clone();
+        folder.push("client");
+
+        // Far enough ahead, so the commit files are older then the garbage collection grace period
+        let now:u64 = chrono::Utc::now().timestamp().try_into().unwrap();
+        let now = now + 30 * DAY;
+
+        // One commit every 12 hours, the newest a minute old
+        let mut repo = storage::new_repo(repo_path.as_path(), "test".to_string()).unwrap();
+        assert!(matches!(repo.create_branch("master".to_string(), U232::new()), BranchUpdate::Ok));
+        let mut commits = Vec::<(U232, Vec<u8>)>::new();
+        let mut prev = None;
+        for index in 0..COMMITS {
+            let data = write_save(&folder, index);
+            let id = repo.create_commit(prev, folder.as_path(), true).unwrap();
+            let time = now - (COMMITS - 1 - index) * DAY / 2 - 60;
+            assert!(repo.set_commit_info(id, CommitInfo::new(1, 0, format!("commit {}", index), time)));
+            assert!(matches!(repo.push_commit_onto_branch(id, "master".to_string(), false), BranchUpdate::Ok));
+
+            commits.push((id, data));
+            prev = Some(id);
+        }
+
+        repo.set_retention_policy(Some(RetentionPolicy { keep_all_days: 1, daily_days: 3, weekly_days: None }));
+        let report = repo.apply_retention(now, false).unwrap();
+        assert!(report.pruned > 0);
+        assert_eq!(report.kept + report.pruned, COMMITS as usize);
+        assert!(report.rebased > 0);
+        assert!(report.garbage.deleted > 0);
+
+        let tip = repo.get_branch("master".to_string()).unwrap().get_previous_commit();
+        assert_eq!(tip, commits.last().unwrap().0);
+        let (log, _) = repo.get_commit_log(tip, usize::MAX, usize::MAX);
+        assert_eq!(log.len(), report.kept);
+
+        // Everything within keep_all_days is still there
+        assert!(log.iter().any(|entry| entry.id == commits[COMMITS as usize - 2].0));
+
+        // Kept commits still build to what was committed, pruned ones are gone
+        let files: Vec<U232> = get_commit_files(&repo).into_iter().map(|(id, _)| id).collect();
+        for (id, data) in commits.iter() {
+            if log.iter().any(|entry| entry.id == *id) {
+                let mut target = root.clone();
+                target.push(common::bytes_to_hex_string(id.to_be_bytes()));
+                io::create_folder(target.as_path()).unwrap();
+                assert!(repo.build_commit(*id, target.as_path()));
+
+                target.push("game.sav");
+                assert!(io::read_bytes(target.as_path()).unwrap() == *data);
+            } else {
+                assert!(!files.contains(id));
+            }
+        }
+
+        // Also from a fresh read, so the cache can not hide anything
+        let mut fresh = storage::read_storage_info(repo_path.as_path()).unwrap();
+        let report = fresh.verify();
+        assert!(report.checked > 0);
+        assert!(report.problems.is_empty());
+
+        let _ = io::delete_folder(root.as_path());
+    }
+}
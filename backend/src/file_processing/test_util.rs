@@ -0,0 +1,57 @@
+// Shared by the tests of the file processing and the api
+use std::{path::{Path, PathBuf}, collections::HashMap, sync::Arc};
+
+use actix_web_lab::__reexports::tokio::sync::RwLock;
+
+use crate::database::{self, DbPool};
+use super::{RepoController, blob_store, io};
+
+// A fresh folder for every test, so tests running in parallel don't share files
+pub fn get_root(name: &str) -> PathBuf {
+    let mut root = std::env::temp_dir();
+    root.push(format!("own_your_saves-{}-{}", name, uuid::Uuid::new_v4()));
+    io::create_folder(root.as_path()).unwrap();
+    root
+}
+
+// Repos in root/repos, large files are build in root
+pub fn new_controller(root: &Path) -> RepoController {
+    let mut path = root.to_path_buf();
+    path.push("repos");
+    io::create_folder(path.as_path()).unwrap();
+
+    RepoController {
+        root_path: path.to_str().unwrap().to_string(),
+        repos: HashMap::new(),
+        blob_store: blob_store::init(path.as_path()).unwrap(),
+        temp_folder: root.to_path_buf()
+    }
+}
+
+// The state the server starts with: a DB in root/dat.db, repos in root/repos and temp folders in root/temp
+pub fn open_server(name: &str) -> (PathBuf, DbPool, Arc<RwLock<RepoController>>) {
+    let root = get_root(name);
+    let mut db_file = root.clone();
+    db_file.push("dat.db");
+    let mut repos = root.clone();
+    repos.push("repos");
+    let mut temp = root.clone();
+    temp.push("temp");
+
+    let pool = database::open_pool(db_file.to_str().unwrap().to_string());
+    let db = pool.get().unwrap();
+    assert!(super::set_temp_folder_root(&db, temp.to_str().unwrap().to_string()));
+    let controller = Arc::new(RwLock::new(super::open(&db, repos.to_str().unwrap().to_string())));
+    drop(db);
+
+    (root, pool, controller)
+}
+
+// Deterministic data that does not repeat or compress, like most save files
+pub fn pseudo_random(len: usize, seed: u64) -> Vec<u8> {
+    let mut state = seed;
+    (0..len).map(|_| {
+        state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        (state >> 33) as u8
+    }).collect()
+}
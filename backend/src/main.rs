@@ -3,7 +3,9 @@ pub mod api;
 pub mod database;
 
 
-use api::{task, repo, transfer, user};
+use std::sync::Arc;
+
+use api::{task, repo, transfer, user, admin};
 
 use actix_web::{HttpServer, App, web::{Data, scope}, middleware::Logger};
 use actix_web_lab::{web::spa, __reexports::tokio::sync::RwLock};
@@ -16,18 +18,26 @@ async fn main() -> std::io::Result<()> {
     
     env_logger::init();
 
+    // Set up once, the workers share the pool and the controller
+    let pool = database::init_pool();
+    let repocontroller = match pool.get() {
+        Ok(database) => file_processing::init(&database),
+        Err(e) => panic!("Unable to get a database connection: {}", e.to_string())
+    };
+    let repocontroller = Arc::new(RwLock::new(repocontroller));
+
+    file_processing::start_retention_schedule(pool.clone(), repocontroller.clone());
+
+    let data = Data::new(pool);
+    let repo = Data::from(repocontroller);
+
     HttpServer::new(move || {
         let logger = Logger::default();
-        let database = database::init_sql();
-        let repocontroller = file_processing::init(&database);
-        
-        let data = Data::new(database);
-        let repo = Data::new(RwLock::new(repocontroller));
 
         App::new()
         .wrap(logger)
-        .app_data(data)
-        .app_data(repo)
+        .app_data(data.clone())
+        .app_data(repo.clone())
         .service(
             scope("/api")
                 .service(task::get_ping)
@@ -39,14 +49,30 @@ async fn main() -> std::io::Result<()> {
                 .service(user::get_device)
                 .service(user::create_device)
                 .service(user::delete_device)
+                .service(user::list_sessions)
+                .service(user::revoke_session)
+                .service(user::revoke_all_sessions)
+                .service(user::create_api_key)
+                .service(user::list_api_keys)
+                .service(user::revoke_api_key)
                 
                 .service(repo::get_repo)
                 .service(repo::list_repo)
                 .service(repo::create_repo)
                 .service(repo::delete_repo)
                 .service(repo::set_repo_access)
+                .service(repo::get_repo_settings)
+                .service(repo::set_repo_settings)
                 .service(repo::list_branches)
+                .service(repo::create_branch)
+                .service(repo::delete_branch)
+                .service(repo::push_branch)
                 .service(repo::create_commit)
+                .service(repo::create_commit_v2)
+                .service(repo::checkout_commit)
+                .service(repo::commit_log)
+                .service(repo::commit_tree)
+                .service(repo::commit_diff)
 
                 .service(transfer::upload_folder)
                 .service(transfer::upload_file)
@@ -56,6 +82,15 @@ async fn main() -> std::io::Result<()> {
                 .service(transfer::download)
                 .service(transfer::clear_temp_folder)
 
+                .service(admin::collect_garbage)
+                .service(admin::verify_repo)
+                .service(admin::apply_retention)
+                .service(admin::set_quota)
+                .service(admin::list_lockouts)
+                .service(admin::clear_lockouts)
+                .service(admin::get_server_settings)
+                .service(admin::set_server_settings)
+
                 .service(task::get_test)
         )
         //Production
@@ -14,7 +14,9 @@ pub trait RequestToFull<T> {
 pub struct User {
     pub user_id: u32,
     pub user_name: String,
-    pub admin: bool
+    pub admin: bool,
+    pub storage_used: Option<u64>, // Bytes of all repos owned by this user
+    pub storage_quota: Option<u64>
 }
 
 impl CastToRequest<RequestUser> for User {
@@ -61,7 +63,9 @@ impl RequestToFull<User> for RequestUser {
                     return Some(User {
                         user_id,
                         user_name: user_name.clone(),
-                        admin
+                        admin,
+                        storage_used: None,
+                        storage_quota: None
                     });
                 }
             }
@@ -133,6 +137,10 @@ pub enum Reply<T> {
     AuthFailed,
     MissingParameter{ token: Option<TokenCarrier>},
     Error{ token: Option<TokenCarrier>},
+    Conflict{ token: Option<TokenCarrier>},
+    QuotaExceeded{ used: u64, quota: u64, token: Option<TokenCarrier>},
+    TooManyAttempts{ retry_after: u64 }, // seconds until the next log in attempt is accepted
+    NoPolicy{ token: Option<TokenCarrier>}, // there is no retention policy to apply
     Failed
 }
 
@@ -140,6 +148,23 @@ impl<T> Reply<T> {
     pub fn new(value: T) -> Self {
         Self::Ok { value, token: None }
     }
+
+    // Converts the value of an Ok, every other reply stays the same
+    pub fn map<U>(self, f: impl FnOnce(T) -> U) -> Reply<U> {
+        match self {
+            Self::Ok { value, token } => Reply::Ok { value: f(value), token },
+            Self::NotFound { token } => Reply::NotFound { token },
+            Self::Denied { token } => Reply::Denied { token },
+            Self::AuthFailed => Reply::AuthFailed,
+            Self::MissingParameter { token } => Reply::MissingParameter { token },
+            Self::Error { token } => Reply::Error { token },
+            Self::Conflict { token } => Reply::Conflict { token },
+            Self::QuotaExceeded { used, quota, token } => Reply::QuotaExceeded { used, quota, token },
+            Self::TooManyAttempts { retry_after } => Reply::TooManyAttempts { retry_after },
+            Self::NoPolicy { token } => Reply::NoPolicy { token },
+            Self::Failed => Reply::Failed
+        }
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
@@ -197,6 +222,48 @@ impl AccessType {
         true
     }
 
+    pub fn is_delete_allowed(& self) -> bool {
+        if !self.is_write_allowed() {
+            return false;
+        }
+
+        if let AccessType::ReadWrite = self {
+            return false;
+        }
+
+        true
+    }
+
+    // Changing settings of the repository itself
+    pub fn is_manage_allowed(& self) -> bool {
+        if let AccessType::All | AccessType::Owner = self {
+            return true;
+        }
+
+        false
+    }
+
+    // Each type allows everything the lower ones do
+    fn rank(& self) -> u8 {
+        match self {
+            AccessType::No => 0,
+            AccessType::Read => 1,
+            AccessType::ReadWrite => 2,
+            AccessType::ReadWriteDelete => 3,
+            AccessType::All => 4,
+            AccessType::Owner => 5
+        }
+    }
+
+    // The lower of the two
+    pub fn limit(& self, max: &AccessType) -> AccessType {
+        if self.rank() > max.rank() {
+            max.clone()
+        } else {
+            self.clone()
+        }
+    }
+
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -204,7 +271,9 @@ pub struct Repository {
     pub repo_name: String,
     pub display_name: Option<String>,
     pub game: Option<String>,
-    pub permission: Option<AccessType>
+    pub permission: Option<AccessType>,
+    pub size: Option<u64>, // Bytes used by the commit files
+    pub quota: Option<u64>
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -212,7 +281,8 @@ pub struct RequestRepository {
     pub token: Option<Uuid>,
     pub repo_name: Option<String>,
     pub display_name: Option<String>,
-    pub game: Option<String>
+    pub game: Option<String>,
+    pub compression: Option<Compression>
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -223,6 +293,130 @@ pub struct RepositoryAccess {
     pub permission: AccessType
 }
 
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RepositorySettings {
+    pub snapshot_depth: usize,
+    pub snapshot_size: u64,
+    pub compression: Compression,
+    pub retention: Option<RetentionPolicy>
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RequestRepositorySettings {
+    pub token: Option<Uuid>,
+    pub repo_name: String,
+    pub snapshot_depth: Option<usize>,
+    pub snapshot_size: Option<u64>,
+    pub compression: Option<Compression>,
+    pub retention: Option<RetentionPolicy>,
+    pub disable_retention: Option<bool> // removes the retention policy, keeping every commit again
+}
+
+// Commits are thinned out by age, always keeping the newest of each time span (and the branches)
+// Within keep_all_days every commit is kept, then one per day up to daily_days, then one per week up to weekly_days
+// No weekly_days means weekly commits are kept forever
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct RetentionPolicy {
+    pub keep_all_days: u64,
+    pub daily_days: u64,
+    pub weekly_days: Option<u64>
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RequestRetention {
+    pub token: Option<Uuid>,
+    pub repo_name: String,
+    pub dry_run: Option<bool> // defaults to true, so nothing gets deleted by accident
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RetentionReport {
+    pub kept: usize, // root commits still in the history of a branch
+    pub pruned: usize, // root commits removed from the history
+    pub rebased: usize, // commits that got a new previous commit, or were turned into a full copy
+    pub garbage: GarbageReport,
+    pub dry_run: bool
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub enum Compression {
+    None,
+    Zstd
+}
+
+impl Compression {
+    pub fn from_str(typ: String) -> Compression {
+        let typ = typ.to_uppercase();
+        match typ.as_str() {
+            "ZSTD" => Compression::Zstd,
+            _ => Compression::None
+        }
+    }
+
+    pub fn cast(& self) -> String {
+        match self {
+            Compression::None => "NONE",
+            Compression::Zstd => "ZSTD"
+        }.to_string()
+    }
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression::None
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RequestQuota {
+    pub token: Option<Uuid>,
+    pub repo_name: Option<String>,
+    pub user_id: Option<u32>,
+    pub quota: Option<u64> // None removes the quota
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RequestGarbageCollection {
+    pub token: Option<Uuid>,
+    pub repo_name: String,
+    pub dry_run: Option<bool> // defaults to true, so nothing gets deleted by accident
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct GarbageReport {
+    pub unreachable: Vec<U232>,
+    pub bytes: u64,
+    pub deleted: usize,
+    pub dry_run: bool
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub enum VerifyProblem {
+    Missing, // referenced, but the file does not exist
+    Unparsable, // file exists, but could not be read
+    HashMismatch, // rebuilding resulted in a different hash then the name
+    BuildFailed // rebuilding failed, usually due to a broken file further back in history
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CommitProblem {
+    pub commit: U232,
+    pub problem: VerifyProblem,
+    pub referenced_by: Option<U232> // None means it was a branch
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct VerifyReport {
+    pub checked: usize,
+    pub problems: Vec<CommitProblem>
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CommitResult {
+    pub commit: U232,
+    pub full_copies: Vec<String> // files that could not be diffed and were stored whole instead
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Branch {
     pub name: String,
@@ -257,4 +451,209 @@ pub struct CreateCommit {
     pub repo_name: String,
     pub previous_commit: Option<U232>,
     pub commit_message: Option<String>
-}
\ No newline at end of file
+}
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RequestCommit {
+    pub token: Option<Uuid>,
+    pub repo_name: String,
+    pub branch_name: Option<String>,
+    pub commit: Option<U232>
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RequestBranch {
+    pub token: Option<Uuid>,
+    pub repo_name: String,
+    pub branch_name: String,
+    pub commit: Option<U232>,
+    pub force: Option<bool>
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub enum CommitType {
+    File,
+    Folder,
+    Delete
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CommitEntry {
+    pub commit: U232,
+    pub previous_commit: U232,
+    pub commit_type: CommitType,
+    pub user_id: Option<u32>,
+    pub user_name: Option<String>,
+    pub device_id: Option<u8>,
+    pub device_name: Option<String>,
+    pub message: Option<String>,
+    pub timestamp: Option<u64>
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CommitLog {
+    pub commits: Vec<CommitEntry>,
+    pub next: Option<U232>
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RequestCommitLog {
+    pub token: Option<Uuid>,
+    pub repo_name: String,
+    pub branch_name: Option<String>,
+    pub commit: Option<U232>,
+    pub limit: Option<usize>,
+    pub depth: Option<usize>
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TreeEntry {
+    pub name: String,
+    pub commit: U232,
+    pub hash: U232,
+    pub size: Option<u64>,
+    pub content: Option<Vec<TreeEntry>>
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RequestDiff {
+    pub token: Option<Uuid>,
+    pub repo_name: String,
+    pub from_branch: Option<String>,
+    pub from_commit: Option<U232>,
+    pub to_branch: Option<String>,
+    pub to_commit: Option<U232>
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CommitDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub renamed: Vec<RenamedFile>,
+    pub changed: Vec<FileDiff>
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RenamedFile {
+    pub from: String,
+    pub to: String
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct FileDiff {
+    pub path: String,
+    pub old_size: u64,
+    pub new_size: u64,
+    pub ranges: Vec<ByteRange>,
+    pub truncated: bool
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ByteRange {
+    pub offset: u64,
+    pub old_bytes: Vec<u8>,
+    pub new_bytes: Vec<u8>
+}
+
+// The key values of the server, times are in seconds
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ServerSettings {
+    pub expire_time: u64, // tokens older than this are rejected
+    pub replacement_time: u64, // tokens older than this are replaced on their next use
+    pub temp_folder: String,
+    pub retention_interval: u64 // time between the scheduled retention runs
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RequestServerSettings {
+    pub token: Option<Uuid>,
+    pub expire_time: Option<u64>,
+    pub replacement_time: Option<u64>,
+    pub temp_folder: Option<String>, // can only be moved while no temp folders exist
+    pub retention_interval: Option<u64>
+}
+
+// Long lived credential of a device, used in place of a token
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ApiKey {
+    pub key_id: u32,
+    pub device_id: u8,
+    pub name: Option<String>,
+    pub repos: Option<Vec<String>>, // None means every repo the user has access to
+    pub max_access: AccessType, // the permission of the user is lowered to this
+    pub creation_time: i64,
+    pub last_use: Option<i64>
+}
+
+// The key itself is only ever returned once, we only keep a hash of it
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct NewApiKey {
+    pub key: Uuid,
+    pub info: ApiKey
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RequestApiKey {
+    pub token: Option<Uuid>,
+    pub user_id: Option<u32>, // admin only
+    pub device_id: Option<u8>,
+    pub key_id: Option<u32>,
+    pub name: Option<String>,
+    pub repos: Option<Vec<String>>,
+    pub max_access: Option<AccessType> // defaults to ReadWrite, enough to sync
+}
+
+// There is only ever one token per device, so the device identifies the session
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Session {
+    pub device_id: u8,
+    pub device_name: String,
+    pub creation_time: i64,
+    pub last_use: Option<i64>, // None if it was never used after log in
+    pub current: bool // the session the request was made with
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RequestSession {
+    pub token: Option<Uuid>,
+    pub user_id: Option<u32>, // admin only
+    pub device_id: Option<u8>
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub enum LockoutSource {
+    User, // the user name that was tried, it doesn't have to exist
+    Address // the ip of the client
+}
+
+impl LockoutSource {
+    pub fn from_str(typ: String) -> LockoutSource {
+        if typ.to_uppercase() == "A" {
+            LockoutSource::Address
+        } else {
+            LockoutSource::User
+        }
+    }
+
+    pub fn cast(& self) -> String {
+        match self {
+            LockoutSource::User => "U",
+            LockoutSource::Address => "A"
+        }.to_string()
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct LoginLockout {
+    pub source: LockoutSource,
+    pub subject: String,
+    pub failures: u32,
+    pub last_failure: i64,
+    pub locked_until: i64 // unix time, in the past if the subject is only being watched
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RequestLockout {
+    pub token: Option<Uuid>,
+    pub source: Option<LockoutSource>, // without source and subject all lockouts are cleared
+    pub subject: Option<String>
+}